@@ -0,0 +1,169 @@
+use crate::config::QQMusicConfig;
+use crate::credential::Credential;
+use crate::error::*;
+
+use reqwest::header::{HeaderMap, COOKIE, REFERER};
+use reqwest::Client;
+use serde_json::{json, Value};
+
+use std::time::SystemTime;
+use log::error;
+
+
+// Consecutive failures after which an account is only used when the others failed too
+pub const ACCOUNT_FAILURE_LIMIT: usize = 3;
+
+
+#[derive(Debug, Clone, Default)]
+pub struct AccountHealth {
+
+    pub last_success: Option<SystemTime>,
+    pub last_failure: Option<SystemTime>,
+    pub failures: usize,
+    pub empty_purls: usize,
+    pub refresh_failed: bool,
+}
+
+impl AccountHealth {
+
+    pub fn is_healthy(&self) -> bool {
+
+        !self.refresh_failed && self.failures < ACCOUNT_FAILURE_LIMIT
+    }
+}
+
+
+// One QQ Music account of the pool, with a client carrying its cookie
+
+#[derive(Clone)]
+pub struct Account {
+
+    pub client: Client,
+    pub credential: Option<Credential>,
+    pub health: AccountHealth,
+}
+
+impl Account {
+
+    pub fn new(config: &QQMusicConfig, credential: Option<Credential>, user_cookie: Option<&str>) -> Result<Self, QQMusicError> {
+
+        let mut headers = HeaderMap::new();
+
+        match config.site_url.parse() {
+
+            Ok(referer) => {
+                headers.insert(REFERER, referer);
+            }
+
+            Err(e) => {
+
+                error!("Account: The site url is not a valid header value");
+                return Err(QQMusicError::QQMusicCookieError(e));
+            }
+        }
+
+        let user_cookie = user_cookie.map(str::to_string).or_else(|| credential.as_ref().map(Credential::cookie));
+
+        if let Some(user_cookie) = user_cookie {
+
+            match user_cookie.parse() {
+
+                Ok(cookie) => {
+                    headers.insert(COOKIE, cookie);
+                }
+
+                Err(e) => {
+
+                    error!("Account: The cookie is not a valid header value");
+                    return Err(QQMusicError::QQMusicCookieError(e));
+                }
+            }
+        }
+
+        let client = config.client(headers)?;
+
+        Ok(Account { client, credential, health: AccountHealth::default() })
+    }
+
+
+    // The QQ number of the account, 0 when anonymous
+
+    pub fn musicid(&self) -> u64 {
+
+        self.credential.as_ref().map(|credential| credential.musicid).unwrap_or(0)
+    }
+
+    pub fn musickey(&self) -> Option<String> {
+
+        self.credential.as_ref().map(|credential| credential.musickey.clone())
+    }
+
+
+    // The comm block tells musicu.fcg who is asking, without it a VIP cookie is treated as anonymous
+
+    pub fn comm(&self) -> Value {
+
+        match &self.credential {
+
+            Some(credential) => json!({
+                "ct": 24,
+                "cv": 0,
+                "format": "json",
+                "uin": credential.musicid,
+                "authst": credential.musickey,
+                "tmeLoginType": credential.tme_login_type(),
+            }),
+
+            None => json!({"ct": 24, "cv": 0, "format": "json", "uin": 0}),
+        }
+    }
+
+
+    pub fn record_success(&mut self) {
+
+        self.health.last_success = Some(SystemTime::now());
+        self.health.failures = 0;
+        self.health.empty_purls = 0;
+    }
+
+    pub fn record_failure(&mut self) {
+
+        self.health.last_failure = Some(SystemTime::now());
+        self.health.failures += 1;
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_account_health() {
+
+        let credential = Credential::from_cookie("uin=o0123456789; qqmusic_key=Q_H_L_5abc").unwrap();
+
+        let mut account = Account::new(&QQMusicConfig::default(), Some(credential), None).unwrap();
+
+        assert_eq!(account.musicid(), 123456789);
+        assert_eq!(account.comm()["uin"], 123456789);
+        assert!(account.health.is_healthy());
+
+        for _ in 0..ACCOUNT_FAILURE_LIMIT {
+            account.record_failure();
+        }
+
+        assert!(!account.health.is_healthy());
+
+        account.record_success();
+
+        assert!(account.health.is_healthy());
+        assert!(account.health.last_success.is_some());
+
+        let anonymous = Account::new(&QQMusicConfig::default(), None, None).unwrap();
+
+        assert_eq!(anonymous.musicid(), 0);
+        assert!(anonymous.musickey().is_none());
+    }
+}
@@ -0,0 +1,563 @@
+use crate::structs::{AudioTrack, Quality};
+
+use bytes::Bytes;
+use serenity::async_trait;
+use songbird::input::{AudioStream, AudioStreamError, AuxMetadata, Compose, File, HttpRequest, Input};
+use symphonia::core::formats::FormatOptions;
+use symphonia::core::io::{MediaSource, MediaSourceStream};
+use symphonia::core::meta::MetadataOptions;
+use symphonia::core::probe::Hint;
+
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::io::{self, Cursor, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use log::{info, warn, error, debug};
+
+
+// Audio files of the songs already played, so a replay starts at once and still works when
+// the vkey server doesn't. Files are named `{songmid}-{quality}` and the least recently
+// played ones are deleted past `max_bytes`. The last few downloads also stay in memory
+
+pub struct AudioCache {
+
+    dir: PathBuf,
+    max_bytes: u64,
+    memory_tracks: usize,
+    index: Mutex<AudioIndex>,
+}
+
+#[derive(Default)]
+struct AudioIndex {
+
+    files: HashMap<String, AudioFile>,
+    total_bytes: u64,
+    clock: u64,
+    memory: VecDeque<(String, Bytes)>,
+}
+
+struct AudioFile {
+
+    size: u64,
+    last_used: u64,
+}
+
+impl AudioCache {
+
+    pub async fn new(dir: PathBuf, max_bytes: u64, memory_tracks: usize) -> Self {
+
+        // Reading the directory blocks, it stays off the async workers
+        let scanned = dir.clone();
+        let index = tokio::task::spawn_blocking(move || scan(&scanned)).await.unwrap_or_default();
+
+        if !index.files.is_empty() {
+            info!("AudioCache: Found {} files, {} MB in {:?}", index.files.len(), index.total_bytes / 1024 / 1024, dir);
+        }
+
+        let cache = AudioCache { dir, max_bytes, memory_tracks, index: Mutex::new(index) };
+
+        cache.remove(cache.evict()).await;
+
+        cache
+    }
+
+
+    // The song in exactly this quality, from memory first and then from disk
+
+    pub async fn get(&self, songmid: &str, quality: Quality) -> Option<AudioTrack> {
+
+        let name = file_name(songmid, quality);
+
+        let memory = {
+
+            let mut index = self.index.lock().unwrap();
+
+            index.clock += 1;
+            let clock = index.clock;
+
+            let memory = index.memory.iter().find(|(cached, _)| *cached == name).map(|(_, bytes)| bytes.clone());
+
+            index.files.get_mut(&name)?.last_used = clock;
+
+            memory
+        };
+
+        let path = self.dir.join(&name);
+        let songmid = songmid.to_string();
+
+        // Touching and probing the file block, they stay off the async workers
+        let track = tokio::task::spawn_blocking(move || {
+
+            if let Some(bytes) = memory {
+
+                debug!("AudioCache: {} from memory", name);
+
+                let duration = probe_duration(Cursor::new(bytes.clone()));
+
+                return AudioTrack::new(&songmid, bytes.into(), duration);
+            }
+
+            // The order must survive a restart, the modified time is what gets read back
+            if let Err(e) = fs::File::options().write(true).open(&path).and_then(|file| file.set_modified(SystemTime::now())) {
+                warn!("AudioCache: Failed to touch {:?}: {:?}", path, e);
+            }
+
+            debug!("AudioCache: {} from {:?}", name, path);
+
+            let duration = fs::File::open(&path).ok().and_then(probe_duration);
+
+            AudioTrack::new(&songmid, File::new(path).into(), duration)
+        });
+
+        track.await.ok()
+    }
+
+
+    // Keep a downloaded song, in the quality its play url was asked for
+
+    pub async fn insert(&self, songmid: &str, quality: Quality, bytes: &Bytes) {
+
+        let size = bytes.len() as u64;
+
+        if size == 0 || size > self.max_bytes {
+            return;
+        }
+
+        let name = file_name(songmid, quality);
+        let path = self.dir.join(&name);
+        let part = self.dir.join(format!("{}.part", name));
+
+        // Written aside and renamed, so a crash never leaves half a song behind
+
+        let written = async {
+            tokio::fs::create_dir_all(&self.dir).await?;
+            tokio::fs::write(&part, bytes).await?;
+            tokio::fs::rename(&part, &path).await
+        };
+
+        if let Err(e) = written.await {
+
+            error!("AudioCache: Failed to save {:?}: {:?}", path, e);
+            let _ = tokio::fs::remove_file(&part).await;
+            return;
+        }
+
+        {
+            let mut index = self.index.lock().unwrap();
+
+            index.clock += 1;
+            let last_used = index.clock;
+
+            if let Some(previous) = index.files.insert(name.clone(), AudioFile { size, last_used }) {
+                index.total_bytes -= previous.size;
+            }
+
+            index.total_bytes += size;
+
+            if self.memory_tracks > 0 {
+
+                index.memory.retain(|(cached, _)| cached != &name);
+                index.memory.push_front((name, bytes.clone()));
+                index.memory.truncate(self.memory_tracks);
+            }
+        }
+
+        self.remove(self.evict()).await;
+    }
+
+
+    // Stream a song and keep it once it has been read to the end, so songs queued from a
+    // playlist or an album are cached as they are played instead of downloaded twice
+
+    pub fn stream(cache: &Arc<AudioCache>, client: &reqwest::Client, url: &str, songmid: &str, quality: Quality) -> Input {
+
+        Input::Lazy(Box::new(CachingRequest {
+            request: HttpRequest::new(client.clone(), url.to_string()),
+            cache: cache.clone(),
+            runtime: tokio::runtime::Handle::current(),
+            songmid: songmid.to_string(),
+            quality,
+        }))
+    }
+
+
+    pub fn total_bytes(&self) -> u64 {
+
+        self.index.lock().unwrap().total_bytes
+    }
+
+
+    // Forget the least recently played files until the cache fits, the names are handed back
+    // so the files are deleted once the index is unlocked
+
+    fn evict(&self) -> Vec<String> {
+
+        let mut index = self.index.lock().unwrap();
+        let mut evicted = vec![];
+
+        while index.total_bytes > self.max_bytes {
+
+            let Some(name) = index.files.iter().min_by_key(|(_, file)| file.last_used).map(|(name, _)| name.clone()) else {
+                break;
+            };
+
+            let file = index.files.remove(&name).unwrap();
+            index.total_bytes -= file.size;
+            index.memory.retain(|(cached, _)| cached != &name);
+
+            evicted.push(name);
+        }
+
+        evicted
+    }
+
+
+    // Deleting blocks, the evicted files go on a blocking thread
+
+    async fn remove(&self, names: Vec<String>) {
+
+        if names.is_empty() {
+            return;
+        }
+
+        let dir = self.dir.clone();
+
+        let removed = tokio::task::spawn_blocking(move || {
+
+            for name in names {
+
+                if let Err(e) = fs::remove_file(dir.join(&name)) {
+                    warn!("AudioCache: Failed to delete {}: {:?}", name, e);
+                }
+
+                debug!("AudioCache: Evicted {}", name);
+            }
+        });
+
+        let _ = removed.await;
+    }
+}
+
+
+// Files left by the previous run, oldest first so they are evicted first
+
+fn scan(dir: &Path) -> AudioIndex {
+
+    let mut index = AudioIndex::default();
+
+    let mut saved: Vec<(String, u64, SystemTime)> = fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter_map(|entry| {
+            let metadata = entry.metadata().ok().filter(|metadata| metadata.is_file())?;
+            let name = entry.file_name().into_string().ok().filter(|name| !name.ends_with(".part"))?;
+            Some((name, metadata.len(), metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH)))
+        })
+        .collect();
+
+    saved.sort_by_key(|(_, _, modified)| *modified);
+
+    for (name, size, _) in saved {
+
+        index.clock += 1;
+        index.total_bytes += size;
+        index.files.insert(name, AudioFile { size, last_used: index.clock });
+    }
+
+    index
+}
+
+
+struct CachingRequest {
+
+    request: HttpRequest,
+    cache: Arc<AudioCache>,
+    // songbird opens the stream on its own threads, the insert is sent back here
+    runtime: tokio::runtime::Handle,
+    songmid: String,
+    quality: Quality,
+}
+
+impl CachingRequest {
+
+    fn tee(&self, stream: AudioStream<Box<dyn MediaSource>>) -> AudioStream<Box<dyn MediaSource>> {
+
+        let cache = self.cache.clone();
+        let runtime = self.runtime.clone();
+        let songmid = self.songmid.clone();
+        let quality = self.quality;
+
+        let complete = Box::new(move |bytes: Bytes| {
+            runtime.spawn(async move { cache.insert(&songmid, quality, &bytes).await });
+        });
+
+        AudioStream {
+            input: Box::new(TeeSource::new(stream.input, self.cache.max_bytes, complete)) as Box<dyn MediaSource>,
+            hint: stream.hint,
+        }
+    }
+}
+
+#[async_trait]
+impl Compose for CachingRequest {
+
+    fn create(&mut self) -> Result<AudioStream<Box<dyn MediaSource>>, AudioStreamError> {
+
+        self.request.create().map(|stream| self.tee(stream))
+    }
+
+    async fn create_async(&mut self) -> Result<AudioStream<Box<dyn MediaSource>>, AudioStreamError> {
+
+        self.request.create_async().await.map(|stream| self.tee(stream))
+    }
+
+    fn should_create_async(&self) -> bool {
+
+        self.request.should_create_async()
+    }
+
+    async fn aux_metadata(&mut self) -> Result<AuxMetadata, AudioStreamError> {
+
+        self.request.aux_metadata().await
+    }
+}
+
+
+// Copies what is read from a source. The copy is only handed over when it is the whole
+// file: a seek past what has been read, or a file bigger than the cache, gives it up
+
+type Complete = Box<dyn FnOnce(Bytes) + Send + Sync>;
+
+struct TeeSource {
+
+    inner: Box<dyn MediaSource>,
+    position: u64,
+    copy: Vec<u8>,
+    max_bytes: u64,
+    complete: Option<Complete>,
+}
+
+impl TeeSource {
+
+    fn new(inner: Box<dyn MediaSource>, max_bytes: u64, complete: Complete) -> Self {
+
+        TeeSource { inner, position: 0, copy: vec![], max_bytes, complete: Some(complete) }
+    }
+
+
+    fn give_up(&mut self) {
+
+        self.complete = None;
+        self.copy = vec![];
+    }
+}
+
+impl Read for TeeSource {
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+
+        let read = self.inner.read(buf)?;
+
+        if self.complete.is_none() {
+            self.position += read as u64;
+            return Ok(read);
+        }
+
+        let copied = self.copy.len() as u64;
+
+        // Bytes read again are already in the copy, only what comes after its end is added
+        if self.position > copied {
+            self.give_up();
+        } else if self.position + read as u64 > copied {
+            self.copy.extend_from_slice(&buf[(copied - self.position) as usize..read]);
+        }
+
+        self.position += read as u64;
+
+        if self.copy.len() as u64 > self.max_bytes {
+            self.give_up();
+        }
+
+        let whole = self.inner.byte_len().is_none_or(|len| len == self.copy.len() as u64);
+
+        if read == 0 && !buf.is_empty() && self.position == self.copy.len() as u64 && whole && !self.copy.is_empty() {
+
+            if let Some(complete) = self.complete.take() {
+                complete(Bytes::from(std::mem::take(&mut self.copy)));
+            }
+        }
+
+        Ok(read)
+    }
+}
+
+impl Seek for TeeSource {
+
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+
+        self.position = self.inner.seek(pos)?;
+
+        Ok(self.position)
+    }
+}
+
+impl MediaSource for TeeSource {
+
+    fn is_seekable(&self) -> bool {
+
+        self.inner.is_seekable()
+    }
+
+    fn byte_len(&self) -> Option<u64> {
+
+        self.inner.byte_len()
+    }
+}
+
+
+// Length of an audio file, read from its container without decoding it
+
+pub fn probe_duration(source: impl MediaSource + 'static) -> Option<Duration> {
+
+    let stream = MediaSourceStream::new(Box::new(source), Default::default());
+
+    let probed = symphonia::default::get_probe()
+        .format(&Hint::new(), stream, &FormatOptions::default(), &MetadataOptions::default())
+        .ok()?;
+
+    let params = &probed.format.default_track()?.codec_params;
+    let time = params.time_base?.calc_time(params.n_frames?);
+
+    Some(Duration::from_secs(time.seconds) + Duration::from_secs_f64(time.frac))
+}
+
+
+// "002GwAma2DGN2x-high". The songmid comes from the command, nothing in it may escape the directory
+
+fn file_name(songmid: &str, quality: Quality) -> String {
+
+    let songmid: String = songmid.chars().filter(char::is_ascii_alphanumeric).collect();
+
+    format!("{}-{}", songmid, quality)
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_file_name() {
+
+        assert_eq!(file_name("002GwAma2DGN2x", Quality::High), "002GwAma2DGN2x-high");
+        assert_eq!(file_name("../002GwAma2DGN2x", Quality::Standard), "002GwAma2DGN2x-standard");
+    }
+
+
+    #[test]
+    fn test_tee_source() {
+
+        let copied: Arc<Mutex<Option<Bytes>>> = Arc::default();
+
+        let tee = |max_bytes: u64| {
+            let copied = copied.clone();
+            TeeSource::new(Box::new(Cursor::new(b"0123456789".to_vec())), max_bytes, Box::new(move |bytes| *copied.lock().unwrap() = Some(bytes)))
+        };
+
+        // Probing reads the start twice, the copy is still the file once
+        let mut source = tee(100);
+        let mut buf = [0u8; 4];
+        source.read_exact(&mut buf).unwrap();
+        source.seek(SeekFrom::Start(2)).unwrap();
+        io::copy(&mut source, &mut io::sink()).unwrap();
+
+        assert_eq!(copied.lock().unwrap().take(), Some(Bytes::from_static(b"0123456789")));
+
+        // Skipping ahead leaves a hole
+        let mut source = tee(100);
+        source.seek(SeekFrom::Start(5)).unwrap();
+        io::copy(&mut source, &mut io::sink()).unwrap();
+
+        assert!(copied.lock().unwrap().is_none());
+
+        // Bigger than the cache
+        let mut source = tee(5);
+        io::copy(&mut source, &mut io::sink()).unwrap();
+
+        assert!(copied.lock().unwrap().is_none());
+    }
+
+
+    #[test]
+    fn test_probe_duration() {
+
+        // One second of 8 kHz mono silence in a WAV container
+        let mut wav = vec![];
+        wav.extend_from_slice(b"RIFF");
+        wav.extend_from_slice(&(36u32 + 16000).to_le_bytes());
+        wav.extend_from_slice(b"WAVEfmt ");
+        wav.extend_from_slice(&[16, 0, 0, 0, 1, 0, 1, 0]);
+        wav.extend_from_slice(&8000u32.to_le_bytes());
+        wav.extend_from_slice(&16000u32.to_le_bytes());
+        wav.extend_from_slice(&[2, 0, 16, 0]);
+        wav.extend_from_slice(b"data");
+        wav.extend_from_slice(&16000u32.to_le_bytes());
+        wav.extend(std::iter::repeat_n(0u8, 16000));
+
+        assert_eq!(probe_duration(Cursor::new(wav)), Some(Duration::from_secs(1)));
+        assert_eq!(probe_duration(Cursor::new(b"not audio".to_vec())), None);
+    }
+
+
+    #[tokio::test]
+    async fn test_audio_cache() {
+
+        let dir = std::env::temp_dir().join(format!("qqmusic-audio-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        let cache = AudioCache::new(dir.clone(), 10, 1).await;
+
+        assert!(cache.get("001", Quality::Standard).await.is_none());
+
+        cache.insert("001", Quality::Standard, &Bytes::from_static(b"1111")).await;
+        cache.insert("002", Quality::Standard, &Bytes::from_static(b"2222")).await;
+
+        assert!(dir.join("001-standard").exists());
+        assert!(cache.get("001", Quality::Standard).await.is_some());
+
+        // A guild that wants another quality doesn't get this one
+        assert!(cache.get("001", Quality::VeryHigh).await.is_none());
+
+        // Over 10 bytes, 002 was played least recently
+        cache.insert("003", Quality::Standard, &Bytes::from_static(b"3333")).await;
+
+        assert!(cache.get("002", Quality::Standard).await.is_none());
+        assert!(!dir.join("002-standard").exists());
+        assert_eq!(cache.total_bytes(), 8);
+
+        // Too big to ever fit
+        cache.insert("004", Quality::Standard, &Bytes::from_static(b"44444444444")).await;
+
+        assert!(cache.get("004", Quality::Standard).await.is_none());
+
+        // Still there after a restart
+        let reloaded = AudioCache::new(dir.clone(), 10, 0).await;
+
+        assert!(reloaded.get("001", Quality::Standard).await.is_some());
+        assert!(reloaded.get("003", Quality::Standard).await.is_some());
+        assert_eq!(reloaded.total_bytes(), 8);
+
+        // A smaller cache at the next start deletes the least recently played
+        drop(reloaded);
+        let smaller = AudioCache::new(dir.clone(), 4, 0).await;
+
+        assert!(!dir.join("001-standard").exists());
+        assert!(smaller.get("003", Quality::Standard).await.is_some());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -1,40 +1,60 @@
 use crate::error::BotError;
-use crate::structs::BotCommand;
-use crate::qqmusic::QQMusic;
+use crate::filter::Filter;
+use crate::permission::Author;
+use crate::player::{apply_settings, queue_track, share_fairly, LoopMode, PlayerSettings, TrackData, SKIP_FADE};
+use crate::settings::{Blocked, GuildSettingsCache, SettingChange};
+use crate::structs::{AudioTrack, BotCommand};
+
+use bytes::Bytes;
 
 use serenity::async_trait;
+use serenity::builder::{CreateAttachment, CreateMessage};
+use serenity::http::CacheHttp;
 use serenity::model::channel::Message;
 use serenity::model::gateway::Ready;
-use serenity::model::id::UserId;
+use serenity::model::id::{ChannelId, GuildId, RoleId, UserId};
 use serenity::prelude::*;
 
 use songbird::SerenityInit;
-use songbird::input::HttpRequest;
-use songbird::input::Input;
+use songbird::input::{HttpRequest, Input};
 use songbird::Call;
-use songbird::input::cached::{Compressed, Memory};
-use symphonia::*;
 
 
-use tokio::sync::mpsc::{self, Sender, Receiver};
+use tokio::sync::mpsc::Sender;
 use log::{info, error,debug};
 use std::env;
-use dotenvy::dotenv;
+use std::sync::Arc;
+use std::time::Duration;
+
+
+// The next song starts buffering this long before the current one ends
+const DEFAULT_PRELOAD_SECONDS: u64 = 10;
 
 
 pub struct Bot {
 
     pub client: Client,
+    pub owner_id: Option<UserId>,
 }
 
 impl Bot {
 
-    pub async fn new(tx:Sender<BotCommand>) -> Result<Self, BotError> {
+    // `ready_tx` gets the context once the guilds are cached, the saved queues can be restored then.
+    // The guild settings give the command prefix of each guild
+
+    pub async fn new(tx:Sender<BotCommand>, ready_tx: Sender<Context>, settings: Arc<GuildSettingsCache>) -> Result<Self, BotError> {
+
+        let token = env::var("DISCORD_TOKEN").map_err(|_| BotError::BotEnvError("DISCORD_TOKEN"))?;
+
+        let bot_id = env::var("DISCORD_BOT_ID").ok()
+            .and_then(|id| id.parse::<u64>().ok())
+            .map(UserId::new)
+            .ok_or(BotError::BotEnvError("DISCORD_BOT_ID"))?;
 
-        let token = env::var("DISCORD_TOKEN").unwrap();
-        let bot_id_str = env::var("DISCORD_BOT_ID").unwrap();
-        let bot_id_u64 = bot_id_str.parse::<u64>().unwrap();
-        let bot_id = UserId::new(bot_id_u64);
+        // Owner-only commands such as /login are disabled when this is unset
+        let owner_id = env::var("DISCORD_OWNER_ID").ok()
+            .and_then(|id| id.parse::<u64>().ok())
+            .map(UserId::new);
 
         let intents = GatewayIntents::GUILDS
             | GatewayIntents::GUILD_MESSAGES
@@ -42,27 +62,28 @@ impl Bot {
             | GatewayIntents::GUILD_VOICE_STATES
             | GatewayIntents::GUILD_MODERATION;
         
-        let handler = Handler { bot_id,tx };
+        let handler = Handler { bot_id, owner_id, tx, ready_tx, settings };
 
         match Client::builder(&token, intents).event_handler(handler).register_songbird().await {
 
             Ok(client) => {
 
                 info!("Bot: Success to initialize the client");
-                Ok(Bot { client })
+                Ok(Bot { client, owner_id })
             }
 
             Err(e) => {
 
                 error!("Bot: Failed to initialize the client: {:?}",e);
-                Err(BotError::BotClientError)
+                Err(BotError::BotClientError(e))
             }
         }
     }
 
 
-    pub async fn play_music(ctx: &Context, msg: &Message, record_url: &str) -> Result<(), BotError> {
-        
+    // Join the voice channel of the message author
+
+    async fn join_author_channel(ctx: &Context, msg: &Message) -> Result<Arc<Mutex<Call>>, BotError> {
 
         let guild_id = match msg.guild_id {
             Some(id) => id,
@@ -90,55 +111,250 @@ impl Bot {
         };
 
 
-        let handle_lock = match manager.join(guild_id, connect_to).await {
-            Ok(handle) => handle,
+        match manager.join(guild_id, connect_to).await {
+            Ok(handle) => Ok(handle),
             Err(e) => {
-                return Err(BotError::BotJoinChannelError);
+                error!("Bot: Failed to join the channel: {:?}",e);
+                Err(BotError::BotJoinChannelError(e))
             }
-        };
+        }
+    }
 
 
-        debug!("Downloading the music: {}", record_url);
+    // `client` is the downloader of QQMusic, it carries the proxy and timeouts
 
-        let client = reqwest::Client::builder()
-            .timeout(std::time::Duration::from_secs(30))
-            .user_agent("Mozilla/5.0")
-            .build()
-            .map_err(|e| format!("Failed to build the downloader client: {}", e)).unwrap();
+    pub async fn download_music(client: &reqwest::Client, record_url: &str) -> Result<Bytes, BotError> {
+
+        debug!("Downloading the music: {}", record_url);
 
         let response = client.get(record_url)
             .send()
             .await
-            .map_err(|e| format!("Failed to download with error: {}", e)).unwrap();
+            .map_err(|e| {
+                error!("Bot: Failed to download with error: {:?}", e);
+                BotError::BotDownloadMusicError(e)
+            })?;
 
         if !response.status().is_success() {
-            return Err(BotError::BotDownloadMusicError);
+            error!("Bot: Failed to download, status {}", response.status());
+            return Err(BotError::BotDownloadStatusError { status: response.status() });
         }
 
         let bytes = response.bytes()
             .await
-            .map_err(|e| format!("Failed to load data from memory with error: {}", e)).unwrap();
+            .map_err(|e| {
+                error!("Bot: Failed to load data from memory with error: {:?}", e);
+                BotError::BotDownloadMusicError(e)
+            })?;
 
         debug!("Suucess to download the music: {} KB", bytes.len() / 1024);
 
-        let source: Input = bytes.into();
+        Ok(bytes)
+    }
+
+
+    // Played when its turn comes, without downloading it up front
+
+    pub fn stream_music(client: &reqwest::Client, record_url: &str) -> Input {
+
+        HttpRequest::new(client.clone(), record_url.to_string()).into()
+    }
+
+
+    pub async fn play_music(ctx: &Context, msg: &Message, track: AudioTrack, settings: &PlayerSettings) -> Result<(), BotError> {
+
+        Self::play_music_list(ctx, msg, vec![track], settings).await.map(|_| ())
+    }
+
+
+    // Queue many songs at once. songbird opens the next song in the queue once the current
+    // one reaches its preload time, so the gap between songs is gone when the length is known
+
+    pub async fn play_music_list(ctx: &Context, msg: &Message, mut tracks: Vec<AudioTrack>, settings: &PlayerSettings) -> Result<usize, BotError> {
+
+        let handle_lock = Self::join_author_channel(ctx, msg).await?;
+
+        for track in tracks.iter_mut() {
+            track.requester.get_or_insert(msg.author.id);
+        }
 
         let mut handle = handle_lock.lock().await;
 
-        debug!("Playing with: {}", record_url);
+        Ok(Self::enqueue(&mut handle, tracks, settings))
+    }
 
-        // Add  new music into the queue
-        handle.enqueue_input(source).await;
 
-        // let track = handle.play_input(source.into());
+    // Join the channel of a saved queue and queue its songs again
 
+    pub async fn restore_queue(ctx: &Context, guild_id: GuildId, channel_id: ChannelId, tracks: Vec<AudioTrack>, settings: &PlayerSettings) -> Result<usize, BotError> {
 
-        // let info = track.get_info().await
-        //     .map_err(|e| format!("Failed to get record info: {:?}", e)).unwrap();
+        let manager = songbird::get(ctx).await.ok_or(BotError::BotPlayerError)?;
 
-        // debug!("Status: {:?}", info.playing);
+        let handle_lock = manager.join(guild_id, channel_id).await.map_err(|e| {
+            error!("Bot: Failed to rejoin the channel: {:?}",e);
+            BotError::BotJoinChannelError(e)
+        })?;
 
-        Ok(())
+        let mut handle = handle_lock.lock().await;
+
+        Ok(Self::enqueue(&mut handle, tracks, settings))
+    }
+
+
+    // People other than bots in a voice channel
+
+    pub fn listeners(ctx: &Context, guild_id: GuildId, channel_id: ChannelId) -> Vec<UserId> {
+
+        let bot_id = ctx.cache.current_user().id;
+
+        ctx.cache.guild(guild_id)
+            .map(|guild| guild.voice_states.values()
+                .filter(|state| state.channel_id == Some(channel_id) && state.user_id != bot_id)
+                .filter(|state| !state.member.as_ref().is_some_and(|member| member.user.bot))
+                .map(|state| state.user_id)
+                .collect())
+            .unwrap_or_default()
+    }
+
+
+    // Songs queued in the guild, the playing one included
+
+    pub async fn queue_length(ctx: &Context, guild_id: GuildId) -> usize {
+
+        let Some(manager) = songbird::get(ctx).await else {
+            return 0;
+        };
+
+        match manager.get(guild_id) {
+            Some(handler_lock) => handler_lock.lock().await.queue().len(),
+            None => 0,
+        }
+    }
+
+
+    // Songs of a member in the guild's queue, the playing one included
+
+    pub async fn queued_by(ctx: &Context, guild_id: GuildId, user_id: UserId) -> usize {
+
+        let Some(manager) = songbird::get(ctx).await else {
+            return 0;
+        };
+
+        let Some(handler_lock) = manager.get(guild_id) else {
+            return 0;
+        };
+
+        let queue = handler_lock.lock().await.queue().clone();
+
+        queue.current_queue().iter()
+            .filter(|handle| handle.data::<TrackData>().requester == Some(user_id))
+            .count()
+    }
+
+
+    // Let the requesters of the waiting songs take turns, for guilds with fair_queue on
+
+    pub async fn share_queue(ctx: &Context, guild_id: GuildId) {
+
+        let Some(manager) = songbird::get(ctx).await else {
+            return;
+        };
+
+        if let Some(handler_lock) = manager.get(guild_id) {
+
+            share_fairly(handler_lock.lock().await.queue());
+
+            debug!("Shared the queue of guild {} fairly", guild_id);
+        }
+    }
+
+
+    // Manage Server, or Administrator which includes it
+
+    pub fn can_manage_guild(ctx: &Context, msg: &Message) -> bool {
+
+        msg.author_permissions(&ctx.cache).is_some_and(|permissions| permissions.manage_guild())
+    }
+
+
+    // What the author of a command counts as in its guild. Without a dj_role everyone is a DJ
+
+    pub async fn author(ctx: &Context, msg: &Message, dj_role: Option<RoleId>) -> Author {
+
+        let Some(guild_id) = msg.guild_id else {
+            return Author::default();
+        };
+
+        let dj = match dj_role {
+            Some(role_id) => Self::has_role(ctx, msg, guild_id, role_id),
+            None => true,
+        };
+
+        let requester = Self::current_requester(ctx, guild_id).await == Some(msg.author.id);
+
+        Author { admin: Self::can_manage_guild(ctx, msg), dj, requester }
+    }
+
+
+    // The roles come with the message, the cache is only asked when they don't
+
+    fn has_role(ctx: &Context, msg: &Message, guild_id: GuildId, role_id: RoleId) -> bool {
+
+        if let Some(member) = &msg.member {
+            return member.roles.contains(&role_id);
+        }
+
+        ctx.cache.guild(guild_id)
+            .and_then(|guild| guild.members.get(&msg.author.id).map(|member| member.roles.contains(&role_id)))
+            .unwrap_or(false)
+    }
+
+
+    // Who asked for the song that is playing in the guild
+
+    pub async fn current_requester(ctx: &Context, guild_id: GuildId) -> Option<UserId> {
+
+        let manager = songbird::get(ctx).await?;
+
+        let current = manager.get(guild_id)?.lock().await.queue().current()?;
+
+        current.data::<TrackData>().requester
+    }
+
+
+    fn enqueue(handle: &mut Call, tracks: Vec<AudioTrack>, settings: &PlayerSettings) -> usize {
+
+        let queue = handle.queue().clone();
+
+        let count = tracks.len();
+
+        // The next song has to be ready by the time the crossfade starts it
+        let preload = match Self::preload_seconds() {
+            preload if preload.is_zero() => preload,
+            preload => preload.max(settings.crossfade),
+        };
+
+        for track in tracks {
+
+            // Add  new music into the queue, the track preloads the next one itself
+            handle.enqueue_with_preload(queue_track(track, settings, &queue, preload), None);
+        }
+
+        debug!("Queued {} tracks", count);
+
+        count
+    }
+
+
+    // BOT_PRELOAD_SECONDS, 0 turns buffering ahead off
+
+    fn preload_seconds() -> Duration {
+
+        let seconds = env::var("BOT_PRELOAD_SECONDS").ok()
+            .and_then(|seconds| seconds.parse::<u64>().ok())
+            .unwrap_or(DEFAULT_PRELOAD_SECONDS);
+
+        Duration::from_secs(seconds)
     }
 
 
@@ -175,12 +391,155 @@ impl Bot {
     }
 
 
+    // Move on to the next song. A song queued with a crossfade fades out quickly first
+
+    pub async fn skip_music(ctx: &Context, msg: &Message) -> Result<(), BotError> {
+
+        let guild_id = msg.guild_id.ok_or(BotError::BotAudioChannelError)?;
+
+        let manager = songbird::get(ctx).await.ok_or(BotError::BotPlayerError)?;
+
+        let handler_lock = manager.get(guild_id).ok_or(BotError::BotPlayerError)?;
+
+        let queue = handler_lock.lock().await.queue().clone();
+
+        let current = queue.current().ok_or(BotError::BotPlayerError)?;
+
+        let data = current.data::<TrackData>();
+
+        if data.crossfade.is_zero() {
+
+            queue.skip().map_err(|e| {
+                error!("Bot: Failed to skip the track: {:?}", e);
+                BotError::BotPlayerError
+            })?;
+        }
+
+        else {
+
+            let position = current.get_info().await.map(|state| state.position).unwrap_or_default();
+
+            data.start_skip_fade(position, SKIP_FADE.min(data.crossfade));
+        }
+
+        debug!("Skipped track {}", current.uuid());
+
+        Ok(())
+    }
+
+
+    // Count the author's vote to skip the song that is playing. Only the people in the bot's
+    // voice channel may vote, `fraction` of them have to. Returns the votes and how many are
+    // needed, the song is skipped once they are reached
+
+    pub async fn vote_skip(ctx: &Context, msg: &Message, fraction: f32) -> Result<(usize, usize), BotError> {
+
+        let guild_id = msg.guild_id.ok_or(BotError::BotAudioChannelError)?;
+
+        let manager = songbird::get(ctx).await.ok_or(BotError::BotPlayerError)?;
+
+        let handler_lock = manager.get(guild_id).ok_or(BotError::BotPlayerError)?;
+
+        let (channel_id, queue) = {
+            let call = handler_lock.lock().await;
+            (call.current_channel(), call.queue().clone())
+        };
+
+        let channel_id = ChannelId::new(channel_id.ok_or(BotError::BotPlayerError)?.0.get());
+
+        let current = queue.current().ok_or(BotError::BotPlayerError)?;
+
+        let listeners = Self::listeners(ctx, guild_id, channel_id);
+
+        if !listeners.contains(&msg.author.id) {
+            return Err(BotError::BotVoterNotListeningError);
+        }
+
+        let votes = current.data::<TrackData>().vote_skip(msg.author.id, &listeners);
+        let needed = ((listeners.len() as f32 * fraction).ceil() as usize).max(1);
+
+        debug!("Skip votes for track {}: {}/{}", current.uuid(), votes, needed);
+
+        if votes >= needed {
+            Self::skip_music(ctx, msg).await?;
+        }
+
+        Ok((votes, needed))
+    }
+
+
+    // Hand changed loop or volume settings to the songs already queued, if any
+
+    pub async fn update_player(ctx: &Context, msg: &Message, settings: &PlayerSettings) -> Result<(), BotError> {
+
+        let guild_id = msg.guild_id.ok_or(BotError::BotAudioChannelError)?;
+
+        let manager = songbird::get(ctx).await.ok_or(BotError::BotPlayerError)?;
+
+        if let Some(handler_lock) = manager.get(guild_id) {
+
+            apply_settings(handler_lock.lock().await.queue(), settings);
+        }
+
+        Ok(())
+    }
+
+
+    // Send the QQ Music login QR code to the owner in private
+
+    pub async fn send_login_qrcode(ctx: &Context, msg: &Message, image: &[u8]) -> Result<(), BotError> {
+
+        let builder = CreateMessage::new()
+            .content("Sir, scan this QR code with the QQ app to login QQ Music. It expires in about two minutes.")
+            .add_file(CreateAttachment::bytes(image.to_vec(), "qrcode.png"));
+
+        match msg.author.direct_message(ctx, builder).await {
+
+            Ok(_) => Ok(()),
+
+            Err(e) => {
+
+                error!("Bot: Failed to send the QR code: {:?}",e);
+                Err(BotError::BotDirectMessageError(e))
+            }
+        }
+    }
+
+
+    // Posted in the announce channel of a guild, for what the bot does without being asked
+
+    pub async fn announce(cache_http: impl CacheHttp, channel_id: ChannelId, content: &str) {
+
+        if let Err(e) = channel_id.say(cache_http.http(), content).await {
+            error!("Bot: Failed to announce in {}: {:?}", channel_id, e);
+        }
+    }
+
+
+    pub async fn send_direct_message(cache_http: impl CacheHttp, user_id: UserId, content: &str) -> Result<(), BotError> {
+
+        match user_id.direct_message(cache_http, CreateMessage::new().content(content)).await {
+
+            Ok(_) => Ok(()),
+
+            Err(e) => {
+
+                error!("Bot: Failed to send the direct message: {:?}",e);
+                Err(BotError::BotDirectMessageError(e))
+            }
+        }
+    }
+
+
 }
 
 
 struct Handler {
     bot_id: UserId,
+    owner_id: Option<UserId>,
     tx: Sender<BotCommand>,
+    ready_tx: Sender<Context>,
+    settings: Arc<GuildSettingsCache>,
 }
 
 #[async_trait]
@@ -195,18 +554,27 @@ impl EventHandler for Handler {
         if msg.author.bot {
             return;
         }
-        if !mentioned_me {
-            return; 
-        }
 
+        // A guild with a prefix also takes "!play ..." without the mention and the slash
+        let prefix = match msg.guild_id {
+            Some(guild_id) => self.settings.get(guild_id).await.prefix,
+            None => None,
+        };
+
+        let content = match prefix.as_deref().and_then(|prefix| msg.content.strip_prefix(prefix)) {
 
-        let mention_string_normal = format!("<@{}>", bot_id.get());
-        let mention_string_nick = format!("<@!{}>", bot_id.get());
+            Some(command) if !mentioned_me => format!("/{}", command.trim_start().trim_start_matches('/')),
 
+            _ if mentioned_me => {
+
+                let mention_string_normal = format!("<@{}>", bot_id.get());
+                let mention_string_nick = format!("<@!{}>", bot_id.get());
+
+                msg.content.replace(&mention_string_normal, "").replace(&mention_string_nick, "")
+            }
 
-        let mut content = msg.content.clone();
-        content = content.replace(&mention_string_normal, "");
-        content = content.replace(&mention_string_nick, "");
+            _ => return,
+        };
 
 
         let command_text = content.trim();
@@ -224,6 +592,155 @@ impl EventHandler for Handler {
             }
 
 
+            "/skip" => {
+
+                Some(BotCommand::Skip {
+                    ctx: ctx.clone(),
+                    msg: msg.clone()
+                })
+            }
+
+
+            "/voteskip" => {
+
+                Some(BotCommand::VoteSkip {
+                    ctx: ctx.clone(),
+                    msg: msg.clone()
+                })
+            }
+
+
+            "/crossfade" => {
+
+                let seconds = match args.trim() {
+                    "off" => Some(0),
+                    seconds => seconds.parse::<u64>().ok(),
+                };
+
+                match seconds {
+
+                    Some(seconds) => Some(BotCommand::Crossfade {
+                        ctx: ctx.clone(),
+                        msg: msg.clone(),
+                        seconds
+                    }),
+
+                    None => {
+
+                        let _ = msg.reply(&ctx, "Error! eg. @me /crossfade 5 or @me /crossfade off").await;
+                        None
+                    }
+                }
+            }
+
+
+            "/filter" => {
+
+                match Filter::parse(args) {
+
+                    Some(filter) => Some(BotCommand::Filter {
+                        ctx: ctx.clone(),
+                        msg: msg.clone(),
+                        filter
+                    }),
+
+                    None => {
+
+                        let _ = msg.reply(&ctx, "Error! eg. @me /filter bassboost|nightcore|vaporwave|speed 1.25|pitch 1.25|off").await;
+                        None
+                    }
+                }
+            }
+
+
+            "/loop" => {
+
+                match LoopMode::parse(args) {
+
+                    Some(loop_mode) => Some(BotCommand::Loop {
+                        ctx: ctx.clone(),
+                        msg: msg.clone(),
+                        loop_mode
+                    }),
+
+                    None => {
+
+                        let _ = msg.reply(&ctx, "Error! eg. @me /loop song or @me /loop off").await;
+                        None
+                    }
+                }
+            }
+
+
+            "/volume" => {
+
+                match args.trim().trim_end_matches('%').parse::<u32>() {
+
+                    Ok(percent) => Some(BotCommand::Volume {
+                        ctx: ctx.clone(),
+                        msg: msg.clone(),
+                        percent
+                    }),
+
+                    Err(_) => {
+
+                        let _ = msg.reply(&ctx, "Error! eg. @me /volume 80").await;
+                        None
+                    }
+                }
+            }
+
+
+            "/settings" => {
+
+                let args = args.trim();
+
+                match SettingChange::parse(args) {
+
+                    change if args.is_empty() || change.is_some() => Some(BotCommand::Settings {
+                        ctx: ctx.clone(),
+                        msg: msg.clone(),
+                        change
+                    }),
+
+                    _ => {
+
+                        let _ = msg.reply(&ctx, "Error! eg. @me /settings, @me /settings volume 80 or @me /settings dj_role off").await;
+                        None
+                    }
+                }
+            }
+
+
+            "/blocklist" => {
+
+                Some(BotCommand::Blocklist {
+                    ctx: ctx.clone(),
+                    msg: msg.clone(),
+                    change: None
+                })
+            }
+
+
+            "/block" | "/unblock" => {
+
+                match Blocked::parse(args) {
+
+                    Some(blocked) => Some(BotCommand::Blocklist {
+                        ctx: ctx.clone(),
+                        msg: msg.clone(),
+                        change: Some(if command == "/block" { SettingChange::Block(blocked) } else { SettingChange::Unblock(blocked) })
+                    }),
+
+                    None => {
+
+                        let _ = msg.reply(&ctx, format!("Error! eg. @me {} song 002GwAma2DGN2x or @me {} singer 周杰伦", command, command)).await;
+                        None
+                    }
+                }
+            }
+
+
             "/search" => {
 
                 let query = args.trim();
@@ -239,7 +756,7 @@ impl EventHandler for Handler {
                     Some(BotCommand::Search { 
                         ctx: ctx.clone(), 
                         msg: msg.clone(), 
-                        Name: query.to_string() 
+                        name: query.to_string() 
                     })
                 }
             }
@@ -260,7 +777,73 @@ impl EventHandler for Handler {
                     Some(BotCommand::Play { 
                         ctx: ctx.clone(), 
                         msg: msg.clone(), 
-                        ID: query.to_string() 
+                        id: query.to_string() 
+                    })
+                }
+            }
+
+
+            "/playlist" | "/album" => {
+
+                let query = args.trim();
+
+                if query.is_empty() {
+
+                    let _ = msg.reply(&ctx, format!("Error! eg. @me {} 8041826393", command)).await;
+                    None
+                }
+
+                else if command == "/playlist" {
+
+                    Some(BotCommand::Playlist {
+                        ctx: ctx.clone(),
+                        msg: msg.clone(),
+                        id: query.to_string()
+                    })
+                }
+
+                else {
+
+                    Some(BotCommand::Album {
+                        ctx: ctx.clone(),
+                        msg: msg.clone(),
+                        id: query.to_string()
+                    })
+                }
+            }
+
+
+            "/login" => {
+
+                if self.owner_id != Some(msg.author.id) {
+
+                    let _ = msg.reply(&ctx, "Error! Only the bot owner can use /login").await;
+                    None
+                }
+
+                else {
+
+                    Some(BotCommand::Login {
+                        ctx: ctx.clone(),
+                        msg: msg.clone(),
+                    })
+                }
+            }
+
+
+            "/accounts" => {
+
+                if self.owner_id != Some(msg.author.id) {
+
+                    let _ = msg.reply(&ctx, "Error! Only the bot owner can use /accounts").await;
+                    None
+                }
+
+                else {
+
+                    Some(BotCommand::Accounts {
+                        ctx: ctx.clone(),
+                        msg: msg.clone(),
                     })
                 }
             }
@@ -287,6 +870,14 @@ impl EventHandler for Handler {
 
         info!("{} Connected", ready.user.name);
     }
+
+    async fn cache_ready(&self, ctx: Context, guilds: Vec<GuildId>) {
+
+        debug!("Bot: Cached {} guilds", guilds.len());
+
+        // Only the first one is waited for, later ones come from reconnects
+        let _ = self.ready_tx.try_send(ctx);
+    }
 }
 
 
@@ -295,20 +886,29 @@ impl EventHandler for Handler {
 mod tests {
 
     use super::*;
-    use tokio::sync::mpsc::{self, Sender, Receiver};
+    use crate::config::QQMusicConfig;
+    use crate::qqmusic::QQMusic;
+    use crate::storage::Storage;
+    use dotenvy::dotenv;
+    use tokio::sync::mpsc;
 
     #[tokio::test]
+    #[ignore = "requires a Discord bot token and runs until interrupted"]
     async fn test_bot() {
 
         dotenv().ok();
-        env_logger::init();
+        let _ = env_logger::try_init();
 
         let (tx, mut rx) = mpsc::channel(100);
+        let (ready_tx, _ready_rx) = mpsc::channel(1);
+
+        let path = std::env::temp_dir().join(format!("qqmusic-bot-{}", std::process::id())).join("storage.db");
+        let settings = Arc::new(GuildSettingsCache::new(Arc::new(Storage::open(path).unwrap())));
 
 
         tokio::spawn(async move {
 
-            let mut app = Bot::new(tx).await.unwrap();
+            let mut app = Bot::new(tx, ready_tx, settings).await.unwrap();
             app.client.start().await.unwrap();
         });
 
@@ -331,28 +931,138 @@ mod tests {
                     }
 
                     // Command Search match
-                    BotCommand::Search { ctx, msg, Name } => {
-
-                        let result = "Got Command Search";
+                    BotCommand::Search { ctx, msg, name } => {
 
-                        let playlist_table = QQMusic::new().await.unwrap().get_search_list(&Name).await.unwrap();
+                        let playlist_table = QQMusic::new(QQMusicConfig::from_env(), Arc::new(Storage::open_default().unwrap())).await.unwrap().get_search_list(&name).await.unwrap();
 
                         (ctx, msg, playlist_table)
                     }
 
                     // Command Play match
-                    BotCommand::Play { ctx, msg, ID } => {
+                    BotCommand::Play { ctx, msg, id } => {
 
                         let result = "Got it! I'm playing this music".to_string();
 
-                        let id = String::from("002GwAma2DGN2x");
+                        let qqmusic = QQMusic::new(QQMusicConfig::from_env(), Arc::new(Storage::open_default().unwrap())).await.unwrap();
+
+                        let url = qqmusic.get_qqmusic_play_url(&id).await.unwrap();
 
-                        let url = QQMusic::new().await.unwrap().get_qqmusic_play_url(&ID).await.unwrap();
+                        let bytes = Bot::download_music(qqmusic.downloader(),&url).await.unwrap();
 
-                        Bot::play_music(&ctx,&msg,&url).await.unwrap();
+                        Bot::play_music(&ctx,&msg,AudioTrack::new(&id, bytes.into(), None),&PlayerSettings::default()).await.unwrap();
 
                         (ctx, msg, result)
                     }
+
+                    // Command Skip match
+                    BotCommand::Skip { ctx, msg } => {
+
+                        Bot::skip_music(&ctx,&msg).await.unwrap();
+
+                        (ctx, msg, "Skipped".to_string())
+                    }
+
+                    // Command VoteSkip match
+                    BotCommand::VoteSkip { ctx, msg } => {
+
+                        let (votes, needed) = Bot::vote_skip(&ctx,&msg,0.5).await.unwrap();
+
+                        (ctx, msg, format!("{}/{} votes", votes, needed))
+                    }
+
+                    // Command Crossfade match
+                    BotCommand::Crossfade { ctx, msg, seconds } => {
+
+                        let result = format!("Sir, I can't crossfade {} seconds in this test", seconds);
+                        (ctx, msg, result)
+                    }
+
+                    // Command Filter match
+                    BotCommand::Filter { ctx, msg, filter } => {
+
+                        let result = format!("Sir, I can't apply {} in this test", filter);
+                        (ctx, msg, result)
+                    }
+
+                    // Command Loop match
+                    BotCommand::Loop { ctx, msg, loop_mode } => {
+
+                        let settings = PlayerSettings { loop_mode, ..PlayerSettings::default() };
+
+                        Bot::update_player(&ctx,&msg,&settings).await.unwrap();
+
+                        (ctx, msg, format!("Loop {:?}", loop_mode))
+                    }
+
+                    // Command Volume match
+                    BotCommand::Volume { ctx, msg, percent } => {
+
+                        let settings = PlayerSettings { volume: percent as f32 / 100.0, ..PlayerSettings::default() };
+
+                        Bot::update_player(&ctx,&msg,&settings).await.unwrap();
+
+                        (ctx, msg, format!("Volume {}%", percent))
+                    }
+
+                    // Command Settings match
+                    BotCommand::Settings { ctx, msg, change } => {
+
+                        let result = format!("Manage Server: {}, change: {:?}", Bot::can_manage_guild(&ctx, &msg), change);
+                        (ctx, msg, result)
+                    }
+
+                    // Command Blocklist match
+                    BotCommand::Blocklist { ctx, msg, change } => {
+
+                        let result = format!("Sir, I can't change the blocklist in this test: {:?}", change);
+                        (ctx, msg, result)
+                    }
+
+                    // Command Login match
+                    BotCommand::Login { ctx, msg } => {
+
+                        let result = "Sir, I can't login in this test".to_string();
+                        (ctx, msg, result)
+                    }
+
+                    // Command Accounts match
+                    BotCommand::Accounts { ctx, msg } => {
+
+                        let result = QQMusic::new(QQMusicConfig::from_env(), Arc::new(Storage::open_default().unwrap())).await.unwrap().get_account_list();
+                        (ctx, msg, result)
+                    }
+
+                    // Command Playlist match
+                    BotCommand::Playlist { ctx, msg, id } => {
+
+                        let qqmusic = QQMusic::new(QQMusicConfig::from_env(), Arc::new(Storage::open_default().unwrap())).await.unwrap();
+
+                        let songs = qqmusic.get_playlist_songs(&id).await.unwrap();
+                        let songmids: Vec<String> = songs.into_iter().map(|song| song.id).collect();
+
+                        let tracks: Vec<AudioTrack> = qqmusic.get_qqmusic_play_urls(&songmids).await.into_iter()
+                            .filter_map(|(songmid, url)| Some(AudioTrack::new(&songmid, Bot::stream_music(qqmusic.downloader(), &url.ok()?), None))).collect();
+
+                        let count = Bot::play_music_list(&ctx,&msg,tracks,&PlayerSettings::default()).await.unwrap();
+
+                        (ctx, msg, format!("Queued {} songs", count))
+                    }
+
+                    // Command Album match
+                    BotCommand::Album { ctx, msg, id } => {
+
+                        let qqmusic = QQMusic::new(QQMusicConfig::from_env(), Arc::new(Storage::open_default().unwrap())).await.unwrap();
+
+                        let songs = qqmusic.get_album_songs(&id).await.unwrap();
+                        let songmids: Vec<String> = songs.into_iter().map(|song| song.id).collect();
+
+                        let tracks: Vec<AudioTrack> = qqmusic.get_qqmusic_play_urls(&songmids).await.into_iter()
+                            .filter_map(|(songmid, url)| Some(AudioTrack::new(&songmid, Bot::stream_music(qqmusic.downloader(), &url.ok()?), None))).collect();
+
+                        let count = Bot::play_music_list(&ctx,&msg,tracks,&PlayerSettings::default()).await.unwrap();
+
+                        (ctx, msg, format!("Queued {} songs", count))
+                    }
                 };
 
                 msg.reply(&ctx, response_content).await.unwrap();
@@ -0,0 +1,299 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use log::{info, warn, error, debug};
+
+
+// How often the entries kept on disk are written, when they changed
+pub const CACHE_FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CacheEntry {
+
+    value: Value,
+
+    // Unix seconds, so the entries still make sense after a restart
+    expires_at: u64,
+
+    #[serde(skip)]
+    last_used: u64,
+}
+
+
+// In-memory TTL cache of QQ Music answers, keyed by request type and parameters. When a path
+// is given the entries are also kept on disk. The least recently used entry goes first when full
+
+pub struct ResponseCache {
+
+    entries: Mutex<HashMap<String, CacheEntry>>,
+    max_entries: usize,
+    path: Option<PathBuf>,
+    // Changed since the last flush
+    dirty: AtomicBool,
+    clock: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheStats {
+
+    pub entries: usize,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl ResponseCache {
+
+    pub fn new(max_entries: usize, path: Option<PathBuf>) -> Self {
+
+        let mut entries: HashMap<String, CacheEntry> = HashMap::new();
+
+        if let Some(content) = path.as_ref().and_then(|path| fs::read_to_string(path).ok()) {
+
+            match serde_json::from_str::<HashMap<String, CacheEntry>>(&content) {
+
+                Ok(saved) => {
+
+                    let now = unix_now();
+                    entries = saved.into_iter().filter(|(_, entry)| entry.expires_at > now).collect();
+
+                    info!("Cache: Loaded {} entries from {:?}", entries.len(), path);
+                }
+
+                Err(e) => warn!("Cache: Ignoring {:?}: {:?}", path, e),
+            }
+        }
+
+        ResponseCache {
+            entries: Mutex::new(entries),
+            max_entries,
+            path,
+            dirty: AtomicBool::new(false),
+            clock: AtomicU64::new(0),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+
+    pub fn get(&self, key: &str) -> Option<Value> {
+
+        let mut entries = self.entries.lock().unwrap();
+
+        let found = match entries.get_mut(key) {
+
+            Some(entry) if entry.expires_at > unix_now() => {
+
+                entry.last_used = self.clock.fetch_add(1, Ordering::Relaxed);
+                Some(entry.value.clone())
+            }
+
+            Some(_) => {
+
+                entries.remove(key);
+                None
+            }
+
+            None => None,
+        };
+
+        match found {
+            Some(_) => self.hits.fetch_add(1, Ordering::Relaxed),
+            None => self.misses.fetch_add(1, Ordering::Relaxed),
+        };
+
+        debug!("Cache: {} {}", if found.is_some() { "hit" } else { "miss" }, key);
+
+        found
+    }
+
+
+    pub fn insert(&self, key: &str, value: Value, ttl: Duration) {
+
+        if ttl.is_zero() || self.max_entries == 0 {
+            return;
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+
+        let now = unix_now();
+        entries.retain(|_, entry| entry.expires_at > now);
+
+        while entries.len() >= self.max_entries && !entries.contains_key(key) {
+
+            let oldest = entries.iter().min_by_key(|(_, entry)| entry.last_used).map(|(key, _)| key.clone());
+
+            match oldest {
+                Some(oldest) => entries.remove(&oldest),
+                None => break,
+            };
+        }
+
+        let entry = CacheEntry {
+            value,
+            expires_at: now + ttl.as_secs(),
+            last_used: self.clock.fetch_add(1, Ordering::Relaxed),
+        };
+
+        entries.insert(key.to_string(), entry);
+
+        // Written by the next flush, not on the way of every answer
+        self.dirty.store(true, Ordering::Relaxed);
+    }
+
+
+    pub fn stats(&self) -> CacheStats {
+
+        CacheStats {
+            entries: self.entries.lock().unwrap().len(),
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+
+
+    // Write the entries to disk if they changed since the last flush. It blocks, async code
+    // goes through flush_in_background
+
+    pub fn flush(&self) {
+
+        let Some(path) = &self.path else {
+            return;
+        };
+
+        if !self.dirty.swap(false, Ordering::Relaxed) {
+            return;
+        }
+
+        let content = serde_json::to_string(&*self.entries.lock().unwrap());
+
+        // Written aside and renamed, a crash while saving keeps the previous entries
+        let saved = content
+            .map_err(std::io::Error::from)
+            .and_then(|content| {
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                let part = path.with_extension("json.part");
+                fs::write(&part, content)?;
+                fs::rename(&part, path)
+            });
+
+        match saved {
+
+            Ok(_) => debug!("Cache: Saved to {:?}", path),
+
+            Err(e) => {
+
+                error!("Cache: Failed to save {:?}: {:?}", path, e);
+                self.dirty.store(true, Ordering::Relaxed);
+            }
+        }
+    }
+
+
+    // Flush every CACHE_FLUSH_INTERVAL on a blocking thread, until the cache is dropped
+
+    pub fn flush_in_background(cache: &Arc<ResponseCache>) {
+
+        if cache.path.is_none() {
+            return;
+        }
+
+        let cache = Arc::downgrade(cache);
+
+        tokio::spawn(async move {
+
+            loop {
+
+                tokio::time::sleep(CACHE_FLUSH_INTERVAL).await;
+
+                let Some(cache) = cache.upgrade() else {
+                    return;
+                };
+
+                let _ = tokio::task::spawn_blocking(move || cache.flush()).await;
+            }
+        });
+    }
+}
+
+// What changed after the last flush is not lost when the client goes away
+
+impl Drop for ResponseCache {
+
+    fn drop(&mut self) {
+
+        self.flush();
+    }
+}
+
+
+fn unix_now() -> u64 {
+
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_response_cache() {
+
+        let cache = ResponseCache::new(2, None);
+
+        assert!(cache.get("search:晴天").is_none());
+
+        cache.insert("search:晴天", json!(["002GwAma2DGN2x"]), Duration::from_secs(60));
+        cache.insert("search:七里香", json!([]), Duration::from_secs(60));
+
+        assert_eq!(cache.get("search:晴天"), Some(json!(["002GwAma2DGN2x"])));
+
+        // Full, 七里香 was used least recently
+        cache.insert("search:稻香", json!([]), Duration::from_secs(60));
+
+        assert!(cache.get("search:七里香").is_none());
+        assert!(cache.get("search:稻香").is_some());
+
+        // Nothing to keep
+        cache.insert("vkey:001", json!("url"), Duration::ZERO);
+
+        assert!(cache.get("vkey:001").is_none());
+
+        assert_eq!(cache.stats(), CacheStats { entries: 2, hits: 2, misses: 3 });
+    }
+
+
+    #[test]
+    fn test_response_cache_on_disk() {
+
+        let path = std::env::temp_dir().join(format!("qqmusic-cache-{}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let cache = ResponseCache::new(10, Some(path.clone()));
+        cache.insert("search:晴天", json!(["002GwAma2DGN2x"]), Duration::from_secs(60));
+
+        // Nothing is written until the flush
+        assert!(!path.exists());
+
+        cache.flush();
+
+        let reloaded = ResponseCache::new(10, Some(path.clone()));
+
+        assert_eq!(reloaded.get("search:晴天"), Some(json!(["002GwAma2DGN2x"])));
+
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,381 @@
+use crate::error::*;
+use crate::musicu::QQMUSIC_API_URL;
+use crate::structs::Quality;
+
+use reqwest::header::HeaderMap;
+use rand::Rng;
+use reqwest::{Client, ClientBuilder, Proxy};
+
+use std::env;
+use std::time::Duration;
+use log::error;
+
+
+pub const QQMUSIC_SITE_URL: &str = "https://y.qq.com/";
+
+// Where the QR code login gets its QR code and status, and where QQ Connect authorizes QQ Music
+pub const QQ_PTLOGIN_URL: &str = "https://ssl.ptlogin2.qq.com";
+pub const QQ_GRAPH_URL: &str = "https://graph.qq.com";
+
+const DEFAULT_USER_AGENT: &str = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/120.0.0.0 Safari/537.36";
+
+
+// How often a failed request is tried again and how long to wait in between
+
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+
+    pub max_retries: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl RetryPolicy {
+
+    // Exponential backoff with jitter, so tasks failing together don't retry together
+
+    pub fn delay(&self, attempt: u32) -> Duration {
+
+        let delay = self.base_delay.saturating_mul(2u32.saturating_pow(attempt)).min(self.max_delay);
+
+        delay.mul_f64(rand::rng().random_range(0.5..=1.0))
+    }
+}
+
+impl Default for RetryPolicy {
+
+    fn default() -> Self {
+
+        RetryPolicy {
+            max_retries: 2,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(5),
+        }
+    }
+}
+
+
+// How many answers are kept and for how long. Play urls are kept until their vkey expires
+
+#[derive(Debug, Clone)]
+pub struct CachePolicy {
+
+    pub max_entries: usize,
+    pub search_ttl: Duration,
+    pub song_list_ttl: Duration,
+
+    // Keep the entries in the data directory across restarts
+    pub persist: bool,
+}
+
+impl Default for CachePolicy {
+
+    fn default() -> Self {
+
+        CachePolicy {
+            max_entries: 1000,
+            search_ttl: Duration::from_secs(10 * 60),
+            song_list_ttl: Duration::from_secs(60 * 60),
+            persist: false,
+        }
+    }
+}
+
+
+// Everything about how the bot talks to QQ Music, for the API client and the audio downloader
+
+#[derive(Debug, Clone)]
+pub struct QQMusicConfig {
+
+    pub api_url: String,
+    pub site_url: String,
+    pub ptlogin_url: String,
+    pub graph_url: String,
+    pub connect_timeout: Duration,
+    pub read_timeout: Duration,
+    pub proxy: Option<String>,
+    pub user_agent: String,
+    pub retry: RetryPolicy,
+
+    // Token bucket in front of every API call, shared by all the tasks
+    pub requests_per_second: f64,
+    pub burst: u32,
+
+    pub cache: CachePolicy,
+
+    // Played songs kept on disk, and how many of the latest downloads also stay in memory
+    pub audio_cache_bytes: u64,
+    pub audio_memory_tracks: usize,
+
+    // Asked for when a guild doesn't choose its own
+    pub quality: Quality,
+}
+
+impl Default for QQMusicConfig {
+
+    fn default() -> Self {
+
+        QQMusicConfig {
+            api_url: QQMUSIC_API_URL.to_string(),
+            site_url: QQMUSIC_SITE_URL.to_string(),
+            ptlogin_url: QQ_PTLOGIN_URL.to_string(),
+            graph_url: QQ_GRAPH_URL.to_string(),
+            connect_timeout: Duration::from_secs(10),
+            read_timeout: Duration::from_secs(30),
+            proxy: None,
+            user_agent: DEFAULT_USER_AGENT.to_string(),
+            retry: RetryPolicy::default(),
+            requests_per_second: 5.0,
+            burst: 10,
+            cache: CachePolicy::default(),
+            audio_cache_bytes: 1024 * 1024 * 1024,
+            audio_memory_tracks: 4,
+            quality: Quality::default(),
+        }
+    }
+}
+
+impl QQMusicConfig {
+
+    pub fn builder() -> QQMusicConfigBuilder {
+
+        QQMusicConfigBuilder { config: QQMusicConfig::default() }
+    }
+
+
+    // QQMUSIC_PROXY, QQMUSIC_USER_AGENT, QQMUSIC_CONNECT_TIMEOUT and QQMUSIC_READ_TIMEOUT (seconds),
+    // QQMUSIC_MAX_RETRIES, QQMUSIC_RATE_LIMIT (requests per second), QQMUSIC_RATE_BURST,
+    // QQMUSIC_CACHE_SIZE (0 turns the cache off), QQMUSIC_CACHE_TTL (seconds), QQMUSIC_CACHE_PERSIST,
+    // QQMUSIC_AUDIO_CACHE_MB, QQMUSIC_AUDIO_MEMORY_TRACKS and QQMUSIC_QUALITY (standard, high,
+    // veryhigh or lossless)
+
+    pub fn from_env() -> Self {
+
+        let number = |name: &str| env::var(name).ok().and_then(|value| value.parse::<u64>().ok());
+        let seconds = |name: &str| number(name).map(Duration::from_secs);
+
+        let mut builder = QQMusicConfig::builder();
+
+        if let Ok(proxy) = env::var("QQMUSIC_PROXY") {
+            builder = builder.proxy(&proxy);
+        }
+
+        if let Some(user_agent) = env::var("QQMUSIC_USER_AGENT").ok().filter(|user_agent| !user_agent.is_empty()) {
+            builder = builder.user_agent(&user_agent);
+        }
+
+        if let Some(timeout) = seconds("QQMUSIC_CONNECT_TIMEOUT") {
+            builder = builder.connect_timeout(timeout);
+        }
+
+        if let Some(timeout) = seconds("QQMUSIC_READ_TIMEOUT") {
+            builder = builder.read_timeout(timeout);
+        }
+
+        if let Some(max_retries) = number("QQMUSIC_MAX_RETRIES") {
+            builder = builder.retry(RetryPolicy { max_retries: max_retries as u32, ..RetryPolicy::default() });
+        }
+
+        let defaults = QQMusicConfig::default();
+
+        let per_second = env::var("QQMUSIC_RATE_LIMIT").ok().and_then(|value| value.parse::<f64>().ok()).unwrap_or(defaults.requests_per_second);
+        let burst = number("QQMUSIC_RATE_BURST").map(|burst| burst as u32).unwrap_or(defaults.burst);
+
+        builder = builder.rate_limit(per_second, burst);
+
+        let mut cache = defaults.cache;
+
+        if let Some(max_entries) = number("QQMUSIC_CACHE_SIZE") {
+            cache.max_entries = max_entries as usize;
+        }
+
+        if let Some(ttl) = seconds("QQMUSIC_CACHE_TTL") {
+            cache.search_ttl = ttl;
+            cache.song_list_ttl = ttl;
+        }
+
+        cache.persist = matches!(env::var("QQMUSIC_CACHE_PERSIST").unwrap_or_default().to_lowercase().as_str(), "1" | "true" | "yes");
+
+        builder = builder.cache(cache);
+
+        let audio_cache_bytes = number("QQMUSIC_AUDIO_CACHE_MB").map(|mb| mb * 1024 * 1024).unwrap_or(defaults.audio_cache_bytes);
+        let audio_memory_tracks = number("QQMUSIC_AUDIO_MEMORY_TRACKS").map(|tracks| tracks as usize).unwrap_or(defaults.audio_memory_tracks);
+
+        builder = builder.audio_cache(audio_cache_bytes, audio_memory_tracks);
+
+        if let Some(quality) = env::var("QQMUSIC_QUALITY").ok().and_then(|quality| Quality::parse(&quality)) {
+            builder = builder.quality(quality);
+        }
+
+        builder.build()
+    }
+
+
+    // A client with the timeouts, proxy and user agent applied. http, https and socks5 proxies work
+
+    pub fn client(&self, headers: HeaderMap) -> Result<Client, QQMusicError> {
+
+        self.client_builder()?.default_headers(headers).build().map_err(|e| {
+            error!("Config: Failed to initialize the client: {:?}", e);
+            QQMusicError::QQMusicClientError(e)
+        })
+    }
+
+    pub fn client_builder(&self) -> Result<ClientBuilder, QQMusicError> {
+
+        let mut builder = Client::builder()
+            .user_agent(&self.user_agent)
+            .connect_timeout(self.connect_timeout)
+            .read_timeout(self.read_timeout);
+
+        if let Some(proxy) = &self.proxy {
+
+            let proxy = Proxy::all(proxy).map_err(|e| {
+                error!("Config: Invalid proxy {}: {:?}", proxy, e);
+                QQMusicError::QQMusicClientError(e)
+            })?;
+
+            builder = builder.proxy(proxy);
+        }
+
+        Ok(builder)
+    }
+}
+
+
+pub struct QQMusicConfigBuilder {
+
+    config: QQMusicConfig,
+}
+
+impl QQMusicConfigBuilder {
+
+    // Where musicu.fcg lives, tests point this to a local mock server
+    pub fn api_url(mut self, api_url: &str) -> Self {
+
+        self.config.api_url = api_url.trim_end_matches('/').to_string();
+        self
+    }
+
+    // Sent as the referer, QQ Music refuses some calls without it
+    pub fn site_url(mut self, site_url: &str) -> Self {
+
+        self.config.site_url = site_url.to_string();
+        self
+    }
+
+    // The QR code login endpoints, tests point these to the mock server too
+    pub fn login_urls(mut self, ptlogin_url: &str, graph_url: &str) -> Self {
+
+        self.config.ptlogin_url = ptlogin_url.trim_end_matches('/').to_string();
+        self.config.graph_url = graph_url.trim_end_matches('/').to_string();
+        self
+    }
+
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+
+        self.config.connect_timeout = timeout;
+        self
+    }
+
+    pub fn read_timeout(mut self, timeout: Duration) -> Self {
+
+        self.config.read_timeout = timeout;
+        self
+    }
+
+    // e.g. socks5://127.0.0.1:1080 for a server outside mainland China
+    pub fn proxy(mut self, proxy: &str) -> Self {
+
+        self.config.proxy = Some(proxy.to_string()).filter(|proxy| !proxy.is_empty());
+        self
+    }
+
+    pub fn user_agent(mut self, user_agent: &str) -> Self {
+
+        self.config.user_agent = user_agent.to_string();
+        self
+    }
+
+    pub fn retry(mut self, retry: RetryPolicy) -> Self {
+
+        self.config.retry = retry;
+        self
+    }
+
+    pub fn rate_limit(mut self, requests_per_second: f64, burst: u32) -> Self {
+
+        self.config.requests_per_second = requests_per_second;
+        self.config.burst = burst;
+        self
+    }
+
+    pub fn cache(mut self, cache: CachePolicy) -> Self {
+
+        self.config.cache = cache;
+        self
+    }
+
+    // 0 bytes turns the audio cache off
+    pub fn audio_cache(mut self, max_bytes: u64, memory_tracks: usize) -> Self {
+
+        self.config.audio_cache_bytes = max_bytes;
+        self.config.audio_memory_tracks = memory_tracks;
+        self
+    }
+
+    pub fn quality(mut self, quality: Quality) -> Self {
+
+        self.config.quality = quality;
+        self
+    }
+
+    pub fn build(self) -> QQMusicConfig {
+
+        self.config
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_config_builder() {
+
+        let config = QQMusicConfig::builder()
+            .api_url("http://127.0.0.1:8080/")
+            .proxy("socks5://127.0.0.1:1080")
+            .read_timeout(Duration::from_secs(5))
+            .build();
+
+        assert_eq!(config.api_url, "http://127.0.0.1:8080");
+        assert_eq!(config.site_url, QQMUSIC_SITE_URL);
+        assert_eq!(config.ptlogin_url, QQ_PTLOGIN_URL);
+        assert_eq!(config.read_timeout, Duration::from_secs(5));
+        assert!(config.client(HeaderMap::new()).is_ok());
+
+        let config = QQMusicConfig::builder().proxy("not a proxy").build();
+
+        assert!(matches!(config.client(HeaderMap::new()), Err(QQMusicError::QQMusicClientError(_))));
+        assert!(QQMusicConfig::builder().proxy("").build().proxy.is_none());
+    }
+
+
+    #[test]
+    fn test_retry_delay() {
+
+        let retry = RetryPolicy { max_retries: 3, base_delay: Duration::from_millis(100), max_delay: Duration::from_millis(300) };
+
+        for _ in 0..20 {
+
+            let first = retry.delay(0);
+            let third = retry.delay(2);
+
+            assert!(first >= Duration::from_millis(50) && first <= Duration::from_millis(100));
+            assert!(third >= Duration::from_millis(150) && third <= Duration::from_millis(300));
+        }
+    }
+}
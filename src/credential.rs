@@ -0,0 +1,176 @@
+use serde::{Deserialize, Serialize};
+
+use std::env;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+
+// Login credential of a QQ Music account, as returned by the login server
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Credential {
+
+    pub musicid: u64,
+    pub musickey: String,
+
+    #[serde(default)]
+    pub refresh_key: String,
+
+    #[serde(default)]
+    pub refresh_token: String,
+
+    #[serde(default, alias = "encryptUin")]
+    pub encrypt_uin: String,
+
+    #[serde(default, alias = "loginType")]
+    pub login_type: u8,
+
+    #[serde(default, alias = "keyExpiresIn")]
+    pub key_expires_in: u64,
+
+    #[serde(default, alias = "musickeyCreateTime")]
+    pub musickey_create_time: u64,
+}
+
+impl Credential {
+
+    // Build the cookie header which y.qq.com expects from a logged in user
+
+    pub fn cookie(&self) -> String {
+
+        format!(
+            "uin=o{:010}; qqmusic_key={}; qm_keyst={}; tmeLoginType={}",
+            self.musicid,
+            self.musickey,
+            self.musickey,
+            self.tme_login_type()
+        )
+    }
+
+    // Read what we can from a cookie pasted from the browser, there is no refresh key in it.
+    // None without a uin, the account couldn't be told apart from the others
+
+    pub fn from_cookie(cookie: &str) -> Option<Self> {
+
+        let value = |names: &[&str]| {
+            cookie.split(';')
+                .filter_map(|pair| pair.split_once('='))
+                .map(|(name, value)| (name.trim(), value.trim()))
+                .find(|(name, value)| names.contains(name) && !value.is_empty())
+                .map(|(_, value)| value.to_string())
+        };
+
+        // uin looks like `o0123456789`, the QQ number with a prefix and zero padding
+        let musicid = value(&["uin", "p_uin"])
+            .map(|uin| uin.trim_start_matches(['o', '0']).to_string())
+            .and_then(|uin| uin.parse::<u64>().ok())
+            .filter(|musicid| *musicid != 0)?;
+
+        let musickey = value(&["qqmusic_key", "qm_keyst"]).unwrap_or_default();
+
+        Some(Credential { musicid, musickey, ..Default::default() })
+    }
+
+
+    // QQ Music tells QQ and WeChat logins apart with this value
+
+    pub fn tme_login_type(&self) -> u8 {
+
+        if self.login_type != 0 {
+            return self.login_type;
+        }
+
+        if self.musickey.starts_with("W_X") { 1 } else { 2 }
+    }
+
+
+    // The login server tells how long the musickey lives, refresh a little before that
+
+    pub fn is_expired(&self) -> bool {
+
+        if self.musickey_create_time == 0 || self.key_expires_in == 0 {
+            return false;
+        }
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+
+        now + 600 >= self.musickey_create_time + self.key_expires_in
+    }
+}
+
+
+// Directory where the bot keeps everything it persists
+
+pub fn data_dir() -> PathBuf {
+
+    PathBuf::from(env::var("DATA_DIR").unwrap_or_else(|_| "data".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_parse_login_response() {
+
+        let data = r#"{
+            "musicid": 123456789,
+            "musickey": "Q_H_L_5abc",
+            "refresh_key": "rk",
+            "refresh_token": "rt",
+            "encryptUin": "oKoAoK",
+            "loginType": 2,
+            "keyExpiresIn": 259200,
+            "musickeyCreateTime": 1700000000
+        }"#;
+
+        let credential: Credential = serde_json::from_str(data).unwrap();
+
+        assert_eq!(credential.musicid, 123456789);
+        assert_eq!(credential.encrypt_uin, "oKoAoK");
+        assert_eq!(credential.key_expires_in, 259200);
+        assert_eq!(
+            credential.cookie(),
+            "uin=o0123456789; qqmusic_key=Q_H_L_5abc; qm_keyst=Q_H_L_5abc; tmeLoginType=2"
+        );
+    }
+
+
+    #[test]
+    fn test_from_cookie() {
+
+        let credential = Credential::from_cookie("pgv_pvid=123; uin=o0123456789; qqmusic_key=Q_H_L_5abc; ts_uid=1").unwrap();
+
+        assert_eq!(credential.musicid, 123456789);
+        assert_eq!(credential.musickey, "Q_H_L_5abc");
+
+        let credential = Credential::from_cookie("p_uin=o0987654321; qm_keyst=W_X_abc").unwrap();
+
+        assert_eq!(credential.musicid, 987654321);
+        assert_eq!(credential.tme_login_type(), 1);
+
+        assert!(Credential::from_cookie("uin=; foo=bar").is_none());
+        assert!(Credential::from_cookie("uin=o0; qqmusic_key=Q_H_L_5abc").is_none());
+    }
+
+
+    #[test]
+    fn test_is_expired() {
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+
+        let mut credential = Credential { musicid: 1, musickey: "Q_H_L_5abc".to_string(), ..Default::default() };
+
+        // Without timing information the server decides
+        assert!(!credential.is_expired());
+
+        credential.key_expires_in = 259200;
+
+        credential.musickey_create_time = now;
+        assert!(!credential.is_expired());
+
+        credential.musickey_create_time = now - 259200;
+        assert!(credential.is_expired());
+    }
+}
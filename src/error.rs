@@ -1,10 +1,15 @@
+use crate::structs::Language;
+
+use reqwest::header::InvalidHeaderValue;
+use reqwest::StatusCode;
+use songbird::error::JoinError;
 use thiserror::Error;
 
 #[derive(Debug,Error)]
 pub enum BotError {
 
     #[error("DiscordBot: Failed to initialize client")]
-    BotClientError,
+    BotClientError(#[source] serenity::Error),
 
     #[error("DiscordBot: Failed to load audio channel")]
     BotAudioChannelError,
@@ -16,21 +21,417 @@ pub enum BotError {
     BotUserNotJoinChannelError,
 
     #[error("DiscordBot: Bot Failed to join the audio channel")]
-    BotJoinChannelError,
+    BotJoinChannelError(#[source] JoinError),
 
     #[error("DiscordBot: Bot Failed to download the target music")]
-    BotDownloadMusicError,
+    BotDownloadMusicError(#[source] reqwest::Error),
+
+    #[error("DiscordBot: The music server answered with HTTP {status}")]
+    BotDownloadStatusError { status: StatusCode },
+
+    #[error("DiscordBot: Failed to send the direct message")]
+    BotDirectMessageError(#[source] serenity::Error),
+
+    #[error("DiscordBot: Environment variable {0} is missing or invalid")]
+    BotEnvError(&'static str),
+
+    #[error("DiscordBot: The author lacks the Manage Server permission")]
+    BotManageGuildRequiredError,
+
+    #[error("DiscordBot: The queue is full")]
+    BotQueueFullError,
+
+    #[error("DiscordBot: The author lacks the DJ role")]
+    BotDjRequiredError,
+
+    #[error("DiscordBot: The author lacks the DJ role and did not request the song")]
+    BotDjOrRequesterRequiredError,
+
+    #[error("DiscordBot: The voter is not in the bot's voice channel")]
+    BotVoterNotListeningError,
+
+    #[error("DiscordBot: The author has as many songs queued as the guild allows")]
+    BotUserQueueFullError,
+
+    #[error("DiscordBot: The song is longer than the guild allows")]
+    BotTrackTooLongError,
+
+    #[error("DiscordBot: The song is blocked in the guild")]
+    BotSongBlockedError,
+
+    #[error("DiscordBot: The singer is blocked in the guild")]
+    BotSingerBlockedError,
+
+    #[error("DiscordBot: The song is flagged and the guild blocks flagged songs")]
+    BotSongFlaggedError,
+
+    #[error("DiscordBot: The song's length is unknown and the guild limits it")]
+    BotTrackLengthUnknownError,
 }
 
 #[derive(Debug,Error)]
 pub enum QQMusicError{
 
     #[error("QQMusic: Failed to build client")]
-    QQMusicClientError,
+    QQMusicClientError(#[source] reqwest::Error),
+
+    #[error("QQMusic: The cookie is not a valid header value")]
+    QQMusicCookieError(#[source] InvalidHeaderValue),
+
+    #[error("QQMusic: Request failed")]
+    QQMusicRequestError(#[from] reqwest::Error),
+
+    #[error("QQMusic: The server answered with HTTP {status}")]
+    QQMusicHttpStatusError { status: StatusCode },
+
+    #[error("QQMusic: The API answered with code {code}")]
+    QQMusicApiError { code: i64 },
 
     #[error("QQMusic: Failed to get the music play url")]
     QQMusicPlayError,
 
+    #[error("QQMusic: This song is only for VIP members")]
+    QQMusicVipRequiredError,
+
+    #[error("QQMusic: This song is in a paid album")]
+    QQMusicPaidAlbumError,
+
+    #[error("QQMusic: This song is not available in this region")]
+    QQMusicRegionBlockedError,
+
+    #[error("QQMusic: This song has been removed")]
+    QQMusicSongRemovedError,
+
+    #[error("QQMusic: Too many requests, try again later")]
+    QQMusicRateLimitedError,
+
     #[error("QQMusic: Failed to get playlist")]
     QQMusicPlaylistError,
+
+    #[error("QQMusic: Failed to search")]
+    QQMusicSearchError,
+
+    #[error("QQMusic: Failed to get the login QR code")]
+    QQMusicQRCodeError,
+
+    #[error("QQMusic: The login QR code has expired")]
+    QQMusicQRCodeExpiredError,
+
+    #[error("QQMusic: The login was refused on the phone")]
+    QQMusicLoginRefusedError,
+
+    #[error("QQMusic: Failed to login")]
+    QQMusicLoginError,
+
+    #[error("QQMusic: Failed to save the credential")]
+    QQMusicCredentialError(#[source] StorageError),
+
+    #[error("QQMusic: The credential has expired and could not be refreshed")]
+    QQMusicRefreshError,
+
+    #[error("QQMusic: Failed to parse the answer")]
+    QQMusicParseError(#[source] serde_json::Error),
+
+    #[error("QQMusic: Failed to get the song")]
+    QQMusicSongError,
+
+    #[error("QQMusic: COOKIE entry {entry} has no uin or p_uin")]
+    QQMusicCookieUinError { entry: usize },
+}
+
+#[derive(Debug,Error)]
+pub enum StorageError {
+
+    #[error("Storage: Failed to read or write the database")]
+    StorageIoError(#[source] std::io::Error),
+
+    #[error("Storage: The database is not valid")]
+    StorageParseError(#[source] serde_json::Error),
+
+    #[error("Storage: The database is version {found}, this build only knows up to {supported}")]
+    StorageVersionError { found: u32, supported: u32 },
+
+    #[error("Storage: The database query failed")]
+    StorageDatabaseError(#[from] rusqlite::Error),
+}
+
+impl QQMusicError {
+
+    // Whether another account of the pool might still be able to play the song
+
+    pub fn is_account_specific(&self) -> bool {
+
+        matches!(
+            self,
+            QQMusicError::QQMusicPlayError
+                | QQMusicError::QQMusicVipRequiredError
+                | QQMusicError::QQMusicPaidAlbumError
+                | QQMusicError::QQMusicRateLimitedError
+        )
+    }
+
+    // Worth sending the same request again: timeouts, connection resets and 5xx
+
+    pub fn is_transient(&self) -> bool {
+
+        match self {
+            QQMusicError::QQMusicRequestError(e) => e.is_timeout() || e.is_connect() || e.is_request() || e.is_body(),
+            QQMusicError::QQMusicHttpStatusError { status } => status.is_server_error(),
+            _ => false,
+        }
+    }
+}
+
+
+// Everything a command can fail with, replied to the user in a friendly way
+
+#[derive(Debug,Error)]
+pub enum CommandError {
+
+    #[error(transparent)]
+    Bot(#[from] BotError),
+
+    #[error(transparent)]
+    QQMusic(#[from] QQMusicError),
+
+    #[error(transparent)]
+    Storage(#[from] StorageError),
+}
+
+impl CommandError {
+
+    pub fn user_message(&self, language: Language) -> &'static str {
+
+        match self {
+            CommandError::Bot(e) => e.user_message(language),
+            CommandError::QQMusic(e) => e.user_message(language),
+            CommandError::Storage(e) => e.user_message(language),
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+
+        match self {
+            CommandError::Bot(e) => e.code(),
+            CommandError::QQMusic(e) => e.code(),
+            CommandError::Storage(e) => e.code(),
+        }
+    }
+
+    // The friendly message with the error code, so a report can be matched to the logs
+
+    pub fn reply(&self, language: Language) -> String {
+
+        format!("{} [{}]", self.user_message(language), self.code())
+    }
+}
+
+
+impl BotError {
+
+    // Stable short code, never reuse a retired one
+
+    pub fn code(&self) -> &'static str {
+
+        match self {
+            BotError::BotClientError(_) => "B01",
+            BotError::BotAudioChannelError => "B02",
+            BotError::BotPlayerError => "B03",
+            BotError::BotUserNotJoinChannelError => "B04",
+            BotError::BotJoinChannelError(_) => "B05",
+            BotError::BotDownloadMusicError(_) => "B06",
+            BotError::BotDownloadStatusError { .. } => "B07",
+            BotError::BotDirectMessageError(_) => "B08",
+            BotError::BotEnvError(_) => "B09",
+            BotError::BotManageGuildRequiredError => "B10",
+            BotError::BotQueueFullError => "B11",
+            BotError::BotDjRequiredError => "B12",
+            BotError::BotDjOrRequesterRequiredError => "B13",
+            BotError::BotVoterNotListeningError => "B14",
+            BotError::BotUserQueueFullError => "B15",
+            BotError::BotTrackTooLongError => "B16",
+            BotError::BotSongBlockedError => "B17",
+            BotError::BotSingerBlockedError => "B18",
+            BotError::BotSongFlaggedError => "B19",
+            BotError::BotTrackLengthUnknownError => "B20",
+        }
+    }
+
+    pub fn user_message(&self, language: Language) -> &'static str {
+
+        let (en, zh) = match self {
+            BotError::BotClientError(_) => ("Sir, I'm not connected to Discord properly.", "抱歉，机器人未能正确连接 Discord。"),
+            BotError::BotAudioChannelError => ("Sir, this only works in a server.", "抱歉，该命令只能在服务器中使用。"),
+            BotError::BotPlayerError => ("Sir, nothing is playing here.", "抱歉，这里没有正在播放的音乐。"),
+            BotError::BotUserNotJoinChannelError => ("Sir, please join a voice channel first.", "请先加入一个语音频道。"),
+            BotError::BotJoinChannelError(_) => ("Sir, I can't join your voice channel.", "抱歉，我无法加入你的语音频道。"),
+            BotError::BotDownloadMusicError(_) | BotError::BotDownloadStatusError { .. } => ("Sir, I failed to download this music.", "抱歉，音乐下载失败。"),
+            BotError::BotDirectMessageError(_) => ("Sir, I can't DM you, please allow direct messages.", "抱歉，我无法私信你，请开启私信权限。"),
+            BotError::BotEnvError(_) => ("Sir, I'm not configured properly.", "抱歉，机器人配置有误。"),
+            BotError::BotManageGuildRequiredError => ("Sir, this needs the Manage Server permission.", "抱歉，该命令需要“管理服务器”权限。"),
+            BotError::BotQueueFullError => ("Sir, the queue is full.", "抱歉，播放队列已满。"),
+            BotError::BotDjRequiredError => ("Sir, this needs the DJ role or the Manage Server permission.", "抱歉，该命令需要 DJ 身份组或“管理服务器”权限。"),
+            BotError::BotDjOrRequesterRequiredError => ("Sir, only a DJ or whoever asked for this song can skip it.", "抱歉，只有 DJ 或点这首歌的人可以跳过它。"),
+            BotError::BotUserQueueFullError => ("Sir, you already have as many songs queued as this server allows.", "抱歉，你点的歌已达到本服务器的上限。"),
+            BotError::BotTrackTooLongError => ("Sir, this song is longer than this server allows.", "抱歉，这首歌超过了本服务器允许的时长。"),
+            BotError::BotSongBlockedError => ("Sir, this song is blocked on this server.", "抱歉，这首歌在本服务器被屏蔽。"),
+            BotError::BotSingerBlockedError => ("Sir, songs by this singer are blocked on this server.", "抱歉，这位歌手的歌在本服务器被屏蔽。"),
+            BotError::BotSongFlaggedError => ("Sir, QQ Music flags this song as VIP, paid or unavailable, and this server blocks those.", "抱歉，这首歌被 QQ 音乐标记为 VIP、付费或不可用，本服务器屏蔽了此类歌曲。"),
+            BotError::BotTrackLengthUnknownError => ("Sir, I can't tell how long this song is, and this server limits the length.", "抱歉，无法得知这首歌的时长，而本服务器限制了歌曲时长。"),
+            BotError::BotVoterNotListeningError => ("Sir, only the people listening in my voice channel can vote.", "抱歉，只有和我在同一语音频道的人可以投票。"),
+        };
+
+        match language {
+            Language::English => en,
+            Language::Chinese => zh,
+        }
+    }
+}
+
+
+impl QQMusicError {
+
+    // Stable short code, never reuse a retired one
+
+    pub fn code(&self) -> &'static str {
+
+        match self {
+            QQMusicError::QQMusicClientError(_) => "Q01",
+            QQMusicError::QQMusicCookieError(_) => "Q02",
+            QQMusicError::QQMusicRequestError(_) => "Q03",
+            QQMusicError::QQMusicHttpStatusError { .. } => "Q04",
+            QQMusicError::QQMusicApiError { .. } => "Q05",
+            QQMusicError::QQMusicPlayError => "Q06",
+            QQMusicError::QQMusicVipRequiredError => "Q07",
+            QQMusicError::QQMusicPaidAlbumError => "Q08",
+            QQMusicError::QQMusicRegionBlockedError => "Q09",
+            QQMusicError::QQMusicSongRemovedError => "Q10",
+            QQMusicError::QQMusicRateLimitedError => "Q11",
+            QQMusicError::QQMusicPlaylistError => "Q12",
+            QQMusicError::QQMusicSearchError => "Q13",
+            QQMusicError::QQMusicQRCodeError => "Q14",
+            QQMusicError::QQMusicQRCodeExpiredError => "Q15",
+            QQMusicError::QQMusicLoginRefusedError => "Q16",
+            QQMusicError::QQMusicLoginError => "Q17",
+            QQMusicError::QQMusicCredentialError(_) => "Q18",
+            QQMusicError::QQMusicRefreshError => "Q19",
+            QQMusicError::QQMusicParseError(_) => "Q20",
+            QQMusicError::QQMusicSongError => "Q21",
+            QQMusicError::QQMusicCookieUinError { .. } => "Q22",
+        }
+    }
+
+    pub fn user_message(&self, language: Language) -> &'static str {
+
+        let (en, zh) = match self {
+            QQMusicError::QQMusicClientError(_) | QQMusicError::QQMusicCookieError(_) | QQMusicError::QQMusicCookieUinError { .. } => ("Sir, my QQ Music client is not set up properly.", "抱歉，QQ 音乐客户端配置有误。"),
+            QQMusicError::QQMusicRequestError(_) | QQMusicError::QQMusicHttpStatusError { .. } => ("Sir, I can't reach QQ Music right now.", "抱歉，暂时无法连接 QQ 音乐。"),
+            QQMusicError::QQMusicApiError { .. } | QQMusicError::QQMusicParseError(_) => ("Sir, QQ Music refused the request.", "抱歉，QQ 音乐拒绝了请求。"),
+            QQMusicError::QQMusicPlayError => ("Sir, I can't get this music from QQ Music.", "抱歉，无法从 QQ 音乐获取这首歌。"),
+            QQMusicError::QQMusicVipRequiredError => ("Sir, this song is only for QQ Music VIP members.", "抱歉，这首歌需要 QQ 音乐会员。"),
+            QQMusicError::QQMusicPaidAlbumError => ("Sir, this song is in a paid album.", "抱歉，这首歌属于付费专辑。"),
+            QQMusicError::QQMusicRegionBlockedError => ("Sir, this song is not available in this region.", "抱歉，这首歌在当前地区不可用。"),
+            QQMusicError::QQMusicSongRemovedError => ("Sir, this song has been removed from QQ Music.", "抱歉，这首歌已下架。"),
+            QQMusicError::QQMusicRateLimitedError => ("Sir, QQ Music says we are asking too often, try again later.", "请求过于频繁，请稍后再试。"),
+            QQMusicError::QQMusicPlaylistError => ("Sir, I can't find this list.", "抱歉，找不到这个歌单。"),
+            QQMusicError::QQMusicSongError => ("Sir, I can't find this song.", "抱歉，找不到这首歌。"),
+            QQMusicError::QQMusicSearchError => ("Sir, the search failed, try again later.", "抱歉，搜索失败，请稍后再试。"),
+            QQMusicError::QQMusicQRCodeError => ("Sir, I failed to get the login QR code.", "抱歉，获取登录二维码失败。"),
+            QQMusicError::QQMusicQRCodeExpiredError => ("Sir, the login QR code has expired.", "登录二维码已过期。"),
+            QQMusicError::QQMusicLoginRefusedError => ("Sir, the login was refused on the phone.", "登录已在手机上被拒绝。"),
+            QQMusicError::QQMusicLoginError => ("Sir, the login failed.", "抱歉，登录失败。"),
+            QQMusicError::QQMusicCredentialError(_) => ("Sir, I failed to save the login.", "抱歉，保存登录信息失败。"),
+            QQMusicError::QQMusicRefreshError => ("Sir, the QQ Music login has expired, the owner needs to /login again.", "QQ 音乐登录已过期，请管理员重新 /login。"),
+        };
+
+        match language {
+            Language::English => en,
+            Language::Chinese => zh,
+        }
+    }
+}
+
+
+impl StorageError {
+
+    // Stable short code, never reuse a retired one
+
+    pub fn code(&self) -> &'static str {
+
+        match self {
+            StorageError::StorageIoError(_) => "S01",
+            StorageError::StorageParseError(_) => "S02",
+            StorageError::StorageVersionError { .. } => "S03",
+            StorageError::StorageDatabaseError(_) => "S04",
+        }
+    }
+
+    pub fn user_message(&self, language: Language) -> &'static str {
+
+        let (en, zh) = match self {
+            StorageError::StorageIoError(_) => ("Sir, I can't read or write my saved data.", "抱歉，无法读写已保存的数据。"),
+            StorageError::StorageParseError(_) => ("Sir, my saved data is damaged.", "抱歉，已保存的数据已损坏。"),
+            StorageError::StorageVersionError { .. } => ("Sir, my saved data is from a newer version of me.", "抱歉，已保存的数据来自更新版本的机器人。"),
+            StorageError::StorageDatabaseError(_) => ("Sir, I failed to load or save that.", "抱歉，读取或保存失败。"),
+        };
+
+        match language {
+            Language::English => en,
+            Language::Chinese => zh,
+        }
+    }
+}
+
+
+// "outer: cause: root cause" for the logs
+
+pub fn error_chain(e: &dyn std::error::Error) -> String {
+
+    let mut chain = e.to_string();
+    let mut source = e.source();
+
+    while let Some(cause) = source {
+        chain.push_str(&format!(": {}", cause));
+        source = cause.source();
+    }
+
+    chain
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_user_message() {
+
+        let e: CommandError = QQMusicError::QQMusicVipRequiredError.into();
+
+        assert_eq!(e.user_message(Language::English), "Sir, this song is only for QQ Music VIP members.");
+        assert_eq!(e.user_message(Language::Chinese), "抱歉，这首歌需要 QQ 音乐会员。");
+        assert_eq!(e.reply(Language::English), "Sir, this song is only for QQ Music VIP members. [Q07]");
+
+        let e: CommandError = BotError::BotUserNotJoinChannelError.into();
+
+        assert_eq!(e.user_message(Language::English), "Sir, please join a voice channel first.");
+        assert_eq!(e.code(), "B04");
+
+        let e: CommandError = StorageError::StorageVersionError { found: 3, supported: 2 }.into();
+
+        assert_eq!(e.user_message(Language::English), "Sir, my saved data is from a newer version of me.");
+        assert_eq!(e.code(), "S03");
+    }
+
+
+    #[test]
+    fn test_error_chain() {
+
+        let io = std::io::Error::new(std::io::ErrorKind::PermissionDenied, "permission denied");
+
+        let e: CommandError = QQMusicError::QQMusicCredentialError(StorageError::StorageIoError(io)).into();
+
+        assert_eq!(error_chain(&e), "QQMusic: Failed to save the credential: Storage: Failed to read or write the database: permission denied");
+    }
 }
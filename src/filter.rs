@@ -0,0 +1,852 @@
+use serenity::async_trait;
+use songbird::input::{AudioStream, AudioStreamError, AuxMetadata, Compose, Input, LiveInput};
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::{Decoder, DecoderOptions};
+use symphonia::core::errors::Error as SymphoniaError;
+use symphonia::core::formats::{FormatOptions, FormatReader, SeekMode, SeekTo};
+use symphonia::core::io::{MediaSource, MediaSourceStream};
+use symphonia::core::meta::MetadataOptions;
+use symphonia::core::probe::Hint;
+use symphonia::core::units::{Time, TimeBase};
+
+use std::f32::consts::PI;
+use std::fmt;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use log::{error, debug};
+
+
+// songbird reads interleaved f32 PCM behind this header, followed by the rate and channels
+const RAW_HEADER: &[u8; 8] = b"SbirdRaw";
+const HEADER_LEN: u64 = 16;
+
+// Bass boost is a low shelf of this gain below this frequency
+const BASS_GAIN_DB: f32 = 9.0;
+const BASS_FREQUENCY: f32 = 110.0;
+
+pub const MIN_SPEED: f32 = 0.5;
+pub const MAX_SPEED: f32 = 2.0;
+
+// Pitch is shifted by reading a delay line at another rate, two taps this long apart take turns
+const PITCH_WINDOW: f64 = 0.06;
+
+
+// Effect applied to every song of a guild. Speed works like a turntable, pitch follows it.
+// Pitch changes the key alone, the song keeps its tempo
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Filter {
+    #[default]
+    Off,
+    BassBoost,
+    Nightcore,
+    Vaporwave,
+    Speed(f32),
+    Pitch(f32),
+}
+
+impl Filter {
+
+    // "bassboost", "nightcore", "vaporwave", "speed 1.25", "pitch 1.25" or "off"
+
+    pub fn parse(args: &str) -> Option<Filter> {
+
+        let mut words = args.split_whitespace();
+
+        let filter = match words.next()?.to_lowercase().as_str() {
+            "off" => Filter::Off,
+            "bassboost" => Filter::BassBoost,
+            "nightcore" => Filter::Nightcore,
+            "vaporwave" => Filter::Vaporwave,
+            "speed" => Filter::Speed(words.next()?.parse::<f32>().ok().filter(|speed| (MIN_SPEED..=MAX_SPEED).contains(speed))?),
+            "pitch" => Filter::Pitch(words.next()?.parse::<f32>().ok().filter(|pitch| (MIN_SPEED..=MAX_SPEED).contains(pitch))?),
+            _ => return None,
+        };
+
+        words.next().is_none().then_some(filter)
+    }
+
+    pub fn speed(&self) -> f32 {
+
+        match self {
+            Filter::Nightcore => 1.25,
+            Filter::Vaporwave => 0.8,
+            Filter::Speed(speed) => *speed,
+            _ => 1.0,
+        }
+    }
+
+    fn bass_boost(&self) -> bool {
+
+        matches!(self, Filter::BassBoost)
+    }
+
+    // Ratio of the shifted frequencies to the song's, without touching the speed
+
+    fn pitch(&self) -> Option<f32> {
+
+        match self {
+            Filter::Pitch(pitch) if *pitch != 1.0 => Some(*pitch),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Filter {
+
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+
+        match self {
+            Filter::Off => write!(f, "off"),
+            Filter::BassBoost => write!(f, "bass boost"),
+            Filter::Nightcore => write!(f, "nightcore"),
+            Filter::Vaporwave => write!(f, "vaporwave"),
+            Filter::Speed(speed) => write!(f, "speed {}", speed),
+            Filter::Pitch(pitch) => write!(f, "pitch {}", pitch),
+        }
+    }
+}
+
+
+// One per guild and shared by its queued songs, so a change is heard in the playing song
+pub type SharedFilter = Arc<RwLock<Filter>>;
+
+
+// The song decoded here and handed to songbird as raw PCM, through the guild's filter.
+// It starts `start` into the song, songbird counts its position from there
+
+pub fn filtered(input: Input, filter: &SharedFilter, start: Duration) -> Input {
+
+    match input {
+
+        Input::Lazy(compose) => Input::Lazy(Box::new(FilterCompose { inner: compose, filter: Arc::clone(filter), start })),
+
+        Input::Live(LiveInput::Raw(stream), compose) => {
+
+            let compose = compose.map(|inner| Box::new(FilterCompose { inner, filter: Arc::clone(filter), start }) as Box<dyn Compose>);
+
+            Input::Live(LiveInput::Raw(wrap(stream, filter, start)), compose)
+        }
+
+        // Already parsed by songbird, nothing to put in between
+        input => input,
+    }
+}
+
+
+fn wrap(stream: AudioStream<Box<dyn MediaSource>>, filter: &SharedFilter, start: Duration) -> AudioStream<Box<dyn MediaSource>> {
+
+    let source = FilterSource {
+        seekable: stream.input.is_seekable(),
+        pending: Some((stream.input, stream.hint)),
+        start,
+        decoding: None,
+        filter: Arc::clone(filter),
+        out: vec![],
+        out_pos: 0,
+        position: 0,
+        finished: false,
+    };
+
+    AudioStream { input: Box::new(source), hint: None }
+}
+
+
+struct FilterCompose {
+
+    inner: Box<dyn Compose>,
+    filter: SharedFilter,
+    start: Duration,
+}
+
+#[async_trait]
+impl Compose for FilterCompose {
+
+    fn create(&mut self) -> Result<AudioStream<Box<dyn MediaSource>>, AudioStreamError> {
+
+        self.inner.create().map(|stream| wrap(stream, &self.filter, self.start))
+    }
+
+    async fn create_async(&mut self) -> Result<AudioStream<Box<dyn MediaSource>>, AudioStreamError> {
+
+        self.inner.create_async().await.map(|stream| wrap(stream, &self.filter, self.start))
+    }
+
+    fn should_create_async(&self) -> bool {
+
+        self.inner.should_create_async()
+    }
+
+    async fn aux_metadata(&mut self) -> Result<AuxMetadata, AudioStreamError> {
+
+        self.inner.aux_metadata().await
+    }
+}
+
+
+// Decodes lazily: songbird reads sources on its own threads, so the probe happens there too.
+// Seekable when the song is, which is how songbird loops a song
+
+struct FilterSource {
+
+    seekable: bool,
+    pending: Option<(Box<dyn MediaSource>, Option<Hint>)>,
+    start: Duration,
+    decoding: Option<Decoding>,
+    filter: SharedFilter,
+    out: Vec<u8>,
+    out_pos: usize,
+
+    // Bytes of output read so far, header included
+    position: u64,
+    finished: bool,
+}
+
+struct Decoding {
+
+    format: Box<dyn FormatReader>,
+    decoder: Box<dyn Decoder>,
+    track_id: u32,
+    time_base: Option<TimeBase>,
+    samples: Option<SampleBuffer<f32>>,
+    dsp: Dsp,
+
+    // Frames still to be dropped to reach the start, when the song couldn't seek there
+    skip: usize,
+}
+
+impl Decoding {
+
+    fn frames(&self, seconds: f64) -> usize {
+
+        (seconds * f64::from(self.dsp.sample_rate)) as usize
+    }
+
+
+    // Seeks land on a packet, returns the frames before `time` to drop
+
+    fn seek(&mut self, time: Duration) -> Result<usize, SymphoniaError> {
+
+        let seek_to = SeekTo::Time { time: Time::from(time.as_secs_f64()), track_id: Some(self.track_id) };
+        let seeked = self.format.seek(SeekMode::Accurate, seek_to)?;
+
+        self.decoder.reset();
+
+        let Some(time_base) = self.time_base else {
+            return Ok(0);
+        };
+
+        let time = time_base.calc_time(seeked.required_ts.saturating_sub(seeked.actual_ts));
+
+        Ok(self.frames(time.seconds as f64 + time.frac))
+    }
+
+
+    fn header(&self) -> Vec<u8> {
+
+        let mut header = RAW_HEADER.to_vec();
+        header.extend_from_slice(&self.dsp.sample_rate.to_le_bytes());
+        header.extend_from_slice(&(self.dsp.channels as u32).to_le_bytes());
+
+        header
+    }
+}
+
+impl FilterSource {
+
+    fn open(source: Box<dyn MediaSource>, hint: Option<Hint>, start: Duration) -> io::Result<Decoding> {
+
+        let stream = MediaSourceStream::new(source, Default::default());
+
+        let probed = symphonia::default::get_probe()
+            .format(&hint.unwrap_or_default(), stream, &FormatOptions::default(), &MetadataOptions::default())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        let track = probed.format.default_track().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no audio track"))?;
+
+        let decoder = symphonia::default::get_codecs()
+            .make(&track.codec_params, &DecoderOptions::default())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        let track_id = track.id;
+        let time_base = track.codec_params.time_base;
+        let sample_rate = track.codec_params.sample_rate.unwrap_or(44100);
+        let channels = track.codec_params.channels.map(|channels| channels.count()).unwrap_or(2);
+
+        let mut decoding = Decoding {
+            track_id,
+            format: probed.format,
+            decoder,
+            time_base,
+            samples: None,
+            dsp: Dsp::new(sample_rate, channels),
+            skip: 0,
+        };
+
+        if !start.is_zero() {
+
+            decoding.skip = decoding.seek(start).unwrap_or_else(|e| {
+
+                // A stream that can't seek is decoded up to the start instead
+                debug!("Filter: Failed to seek to {:?}, decoding up to it: {:?}", start, e);
+                decoding.frames(start.as_secs_f64())
+            });
+        }
+
+        Ok(decoding)
+    }
+
+
+    // Next chunk of output: the header first, then one decoded packet at a time
+
+    fn fill(&mut self) -> io::Result<()> {
+
+        if let Some((source, hint)) = self.pending.take() {
+
+            let decoding = Self::open(source, hint, self.start).inspect_err(|e| error!("Filter: Failed to open the song: {:?}", e))?;
+
+            self.out.extend_from_slice(&decoding.header());
+
+            self.decoding = Some(decoding);
+            return Ok(());
+        }
+
+        let Some(decoding) = self.decoding.as_mut() else {
+            self.finished = true;
+            return Ok(());
+        };
+
+        let filter = *self.filter.read().unwrap();
+
+        loop {
+
+            let packet = match decoding.format.next_packet() {
+
+                Ok(packet) => packet,
+
+                Err(SymphoniaError::IoError(e)) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                    self.finished = true;
+                    return Ok(());
+                }
+
+                Err(e) => {
+                    debug!("Filter: Stopped reading the song: {:?}", e);
+                    self.finished = true;
+                    return Ok(());
+                }
+            };
+
+            if packet.track_id() != decoding.track_id {
+                continue;
+            }
+
+            let decoded = match decoding.decoder.decode(&packet) {
+
+                Ok(decoded) => decoded,
+
+                // A damaged packet is skipped, the rest of the song still plays
+                Err(SymphoniaError::DecodeError(e)) => {
+                    debug!("Filter: Skipped a packet: {}", e);
+                    continue;
+                }
+
+                Err(e) => {
+                    debug!("Filter: Stopped decoding the song: {:?}", e);
+                    self.finished = true;
+                    return Ok(());
+                }
+            };
+
+            let samples = decoding.samples.get_or_insert_with(|| SampleBuffer::new(decoded.capacity() as u64, *decoded.spec()));
+
+            if samples.capacity() < decoded.capacity() * decoded.spec().channels.count() {
+                *samples = SampleBuffer::new(decoded.capacity() as u64, *decoded.spec());
+            }
+
+            samples.copy_interleaved_ref(decoded);
+
+            let channels = decoding.dsp.channels;
+            let skipped = decoding.skip.min(samples.samples().len() / channels);
+            decoding.skip -= skipped;
+
+            let mut output = vec![];
+            decoding.dsp.process(&samples.samples()[skipped * channels..], filter, &mut output);
+
+            self.out.reserve(output.len() * 4);
+
+            for sample in output {
+                self.out.extend_from_slice(&sample.to_le_bytes());
+            }
+
+            return Ok(());
+        }
+    }
+}
+
+impl Read for FilterSource {
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+
+        while self.out_pos >= self.out.len() {
+
+            if self.finished {
+                return Ok(0);
+            }
+
+            self.out.clear();
+            self.out_pos = 0;
+            self.fill()?;
+        }
+
+        let count = buf.len().min(self.out.len() - self.out_pos);
+
+        buf[..count].copy_from_slice(&self.out[self.out_pos..self.out_pos + count]);
+        self.out_pos += count;
+        self.position += count as u64;
+
+        Ok(count)
+    }
+}
+
+// songbird seeks to a frame of the output. The song is seeked to where that frame was
+// decoded from, at the speed the filter has now
+
+impl Seek for FilterSource {
+
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+
+        let offset = match pos {
+            SeekFrom::Start(offset) => offset,
+            SeekFrom::Current(delta) => self.position.checked_add_signed(delta).ok_or(io::ErrorKind::InvalidInput)?,
+            SeekFrom::End(_) => return Err(io::ErrorKind::Unsupported.into()),
+        };
+
+        if offset == self.position {
+            return Ok(offset);
+        }
+
+        if !self.seekable {
+            return Err(io::ErrorKind::Unsupported.into());
+        }
+
+        if self.pending.is_some() {
+            self.fill()?;
+        }
+
+        let Some(decoding) = self.decoding.as_mut() else {
+            return Err(io::ErrorKind::Unsupported.into());
+        };
+
+        let frame_len = 4 * decoding.dsp.channels as u64;
+        let frame = offset.saturating_sub(HEADER_LEN) / frame_len;
+        let speed = f64::from(self.filter.read().unwrap().speed());
+        let time = self.start + Duration::from_secs_f64(frame as f64 * speed / f64::from(decoding.dsp.sample_rate));
+
+        decoding.skip = decoding.seek(time).map_err(io::Error::other)?;
+        decoding.dsp = Dsp::new(decoding.dsp.sample_rate, decoding.dsp.channels);
+
+        let header = decoding.header();
+
+        self.out.clear();
+        self.out_pos = 0;
+        self.finished = false;
+
+        // Back into the header, it is read again
+        let offset = if offset < HEADER_LEN {
+            self.out.extend_from_slice(&header[offset as usize..]);
+            offset
+        } else {
+            HEADER_LEN + frame * frame_len
+        };
+
+        self.position = offset;
+
+        Ok(offset)
+    }
+}
+
+impl MediaSource for FilterSource {
+
+    fn is_seekable(&self) -> bool {
+
+        self.seekable
+    }
+
+    fn byte_len(&self) -> Option<u64> {
+
+        None
+    }
+}
+
+
+// Bass shelf per channel, then linear resampling for the speed
+
+struct Dsp {
+
+    sample_rate: u32,
+    channels: usize,
+    bass: Vec<Biquad>,
+    pitch: PitchShifter,
+
+    // Frames not yet consumed by the resampler and where it is between them
+    pending: Vec<f32>,
+    position: f64,
+}
+
+impl Dsp {
+
+    fn new(sample_rate: u32, channels: usize) -> Self {
+
+        let shelf = Biquad::low_shelf(sample_rate as f32, BASS_FREQUENCY, BASS_GAIN_DB);
+
+        Dsp {
+            sample_rate,
+            channels: channels.max(1),
+            bass: vec![shelf; channels.max(1)],
+            pitch: PitchShifter::new(sample_rate, channels.max(1)),
+            pending: vec![],
+            position: 0.0,
+        }
+    }
+
+
+    fn process(&mut self, samples: &[f32], filter: Filter, output: &mut Vec<f32>) {
+
+        let channels = self.channels;
+
+        let start = self.pending.len();
+        self.pending.extend_from_slice(samples);
+
+        if filter.bass_boost() {
+
+            for (index, sample) in self.pending[start..].iter_mut().enumerate() {
+                *sample = self.bass[index % channels].process(*sample).clamp(-1.0, 1.0);
+            }
+        }
+
+        if let Some(pitch) = filter.pitch() {
+
+            for frame in self.pending[start..].chunks_exact_mut(channels) {
+                self.pitch.process(frame, pitch);
+            }
+        }
+
+        let speed = f64::from(filter.speed());
+        let frames = self.pending.len() / channels;
+
+        // Each output frame sits between two input frames
+        while (self.position as usize) + 1 < frames {
+
+            let index = self.position as usize;
+            let fraction = (self.position - index as f64) as f32;
+
+            for channel in 0..channels {
+
+                let current = self.pending[index * channels + channel];
+                let next = self.pending[(index + 1) * channels + channel];
+
+                output.push(current + (next - current) * fraction);
+            }
+
+            self.position += speed;
+        }
+
+        let consumed = (self.position as usize).min(frames);
+
+        self.pending.drain(..consumed * channels);
+        self.position -= consumed as f64;
+    }
+}
+
+
+// Delay line read by two taps whose delays sweep at the rate the pitch asks for. Each tap
+// fades out where its delay wraps around while the other one, half a window on, is at full
+// volume, so a frame in makes a frame out and the tempo stays
+
+struct PitchShifter {
+
+    channels: usize,
+    window: f64,
+    ring: Vec<f32>,
+    write: usize,
+    // Where the first tap is in its sweep, 0 to 1
+    phase: f64,
+}
+
+impl PitchShifter {
+
+    fn new(sample_rate: u32, channels: usize) -> Self {
+
+        let window = (f64::from(sample_rate) * PITCH_WINDOW).max(2.0);
+
+        PitchShifter {
+            channels,
+            window,
+            ring: vec![0.0; (window as usize + 2) * channels],
+            write: 0,
+            phase: 0.0,
+        }
+    }
+
+
+    fn process(&mut self, frame: &mut [f32], pitch: f32) {
+
+        let channels = self.channels;
+        let len = self.ring.len() / channels;
+
+        self.ring[self.write * channels..(self.write + 1) * channels].copy_from_slice(frame);
+
+        let taps = [self.phase, (self.phase + 0.5).fract()];
+
+        for (channel, sample) in frame.iter_mut().enumerate() {
+
+            *sample = taps.iter()
+                .map(|tap| {
+
+                    let gain = 1.0 - (2.0 * tap - 1.0).abs();
+
+                    // Between two frames, part of a window before the one just written
+                    let position = (self.write as f64 - tap * self.window).rem_euclid(len as f64);
+                    let index = position as usize;
+                    let fraction = (position - index as f64) as f32;
+
+                    let current = self.ring[index * channels + channel];
+                    let next = self.ring[(index + 1) % len * channels + channel];
+
+                    gain as f32 * (current + (next - current) * fraction)
+                })
+                .sum();
+        }
+
+        // A shrinking delay reads faster than it is written, which raises the pitch
+        self.phase = (self.phase + (1.0 - f64::from(pitch)) / self.window).rem_euclid(1.0);
+        self.write = (self.write + 1) % len;
+    }
+}
+
+
+// Direct form I biquad, coefficients from the RBJ audio EQ cookbook
+
+#[derive(Debug, Clone)]
+struct Biquad {
+
+    b0: f32,
+    b1: f32,
+    b2: f32,
+    a1: f32,
+    a2: f32,
+    x1: f32,
+    x2: f32,
+    y1: f32,
+    y2: f32,
+}
+
+impl Biquad {
+
+    fn low_shelf(sample_rate: f32, frequency: f32, gain_db: f32) -> Self {
+
+        let a = 10f32.powf(gain_db / 40.0);
+        let w0 = 2.0 * PI * frequency / sample_rate;
+        let (sin, cos) = w0.sin_cos();
+
+        // Shelf slope of 1
+        let alpha = sin / 2.0 * 2f32.sqrt();
+        let sqrt_a = 2.0 * a.sqrt() * alpha;
+
+        let a0 = (a + 1.0) + (a - 1.0) * cos + sqrt_a;
+
+        Biquad {
+            b0: a * ((a + 1.0) - (a - 1.0) * cos + sqrt_a) / a0,
+            b1: 2.0 * a * ((a - 1.0) - (a + 1.0) * cos) / a0,
+            b2: a * ((a + 1.0) - (a - 1.0) * cos - sqrt_a) / a0,
+            a1: -2.0 * ((a - 1.0) + (a + 1.0) * cos) / a0,
+            a2: ((a + 1.0) + (a - 1.0) * cos - sqrt_a) / a0,
+            x1: 0.0,
+            x2: 0.0,
+            y1: 0.0,
+            y2: 0.0,
+        }
+    }
+
+    fn process(&mut self, x: f32) -> f32 {
+
+        let y = self.b0 * x + self.b1 * self.x1 + self.b2 * self.x2 - self.a1 * self.y1 - self.a2 * self.y2;
+
+        self.x2 = self.x1;
+        self.x1 = x;
+        self.y2 = self.y1;
+        self.y1 = y;
+
+        y
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_parse_filter() {
+
+        assert_eq!(Filter::parse("bassboost"), Some(Filter::BassBoost));
+        assert_eq!(Filter::parse("Nightcore"), Some(Filter::Nightcore));
+        assert_eq!(Filter::parse("speed 1.25"), Some(Filter::Speed(1.25)));
+        assert_eq!(Filter::parse("pitch 0.8"), Some(Filter::Pitch(0.8)));
+        assert_eq!(Filter::Pitch(0.8).speed(), 1.0);
+        assert_eq!(Filter::parse("off"), Some(Filter::Off));
+
+        assert_eq!(Filter::parse("speed"), None);
+        assert_eq!(Filter::parse("speed 10"), None);
+        assert_eq!(Filter::parse("echo"), None);
+        assert_eq!(Filter::parse("off now"), None);
+    }
+
+
+    #[test]
+    fn test_dsp_speed() {
+
+        let mut dsp = Dsp::new(48000, 1);
+        let input: Vec<f32> = (0..1000).map(|i| i as f32 / 1000.0).collect();
+
+        let mut output = vec![];
+        dsp.process(&input, Filter::Off, &mut output);
+
+        // Unchanged, the last frame waits for the next packet
+        assert_eq!(output.len(), 999);
+        assert_eq!(output[10], input[10]);
+
+        let mut dsp = Dsp::new(48000, 2);
+        let stereo: Vec<f32> = (0..2000).map(|i| (i / 2) as f32).collect();
+
+        let mut output = vec![];
+        dsp.process(&stereo, Filter::Speed(2.0), &mut output);
+
+        // Half the frames, every other one, channels kept together
+        assert_eq!(output.len(), 1000);
+        assert_eq!(&output[..4], &[0.0, 0.0, 2.0, 2.0]);
+
+        let mut dsp = Dsp::new(48000, 2);
+
+        let mut output = vec![];
+        dsp.process(&stereo, Filter::Vaporwave, &mut output);
+
+        assert!(output.len() > 2400 && output.len() <= 2502);
+    }
+
+
+    #[test]
+    fn test_dsp_pitch() {
+
+        // One second of a 440 Hz tone
+        let rate = 48000;
+        let input: Vec<f32> = (0..rate).map(|i| (2.0 * PI * 440.0 * i as f32 / rate as f32).sin()).collect();
+
+        let crossings = |samples: &[f32]| samples.windows(2).filter(|pair| pair[0] < 0.0 && pair[1] >= 0.0).count();
+
+        let mut dsp = Dsp::new(rate as u32, 1);
+
+        let mut output = vec![];
+        dsp.process(&input, Filter::Pitch(1.5), &mut output);
+
+        // As long as before and a fifth up, once the delay line has filled
+        assert_eq!(output.len(), input.len() - 1);
+
+        let settled = &output[rate / 10..];
+        let shifted = crossings(settled) as f32 / crossings(&input[rate / 10..]) as f32;
+
+        assert!((shifted - 1.5).abs() < 0.05, "{}", shifted);
+    }
+
+
+    #[tokio::test]
+    async fn test_filtered_input() {
+
+        use songbird::input::codecs::{get_codec_registry, get_probe};
+
+        // One second of 8 kHz mono in a WAV container
+        let mut wav = vec![];
+        wav.extend_from_slice(b"RIFF");
+        wav.extend_from_slice(&(36u32 + 16000).to_le_bytes());
+        wav.extend_from_slice(b"WAVEfmt ");
+        wav.extend_from_slice(&[16, 0, 0, 0, 1, 0, 1, 0]);
+        wav.extend_from_slice(&8000u32.to_le_bytes());
+        wav.extend_from_slice(&16000u32.to_le_bytes());
+        wav.extend_from_slice(&[2, 0, 16, 0]);
+        wav.extend_from_slice(b"data");
+        wav.extend_from_slice(&16000u32.to_le_bytes());
+        wav.extend((0..8000).flat_map(|_| 8192i16.to_le_bytes()));
+
+        let wav = bytes::Bytes::from(wav);
+
+        let frames = |filter: Filter, start: Duration| {
+
+            let filter: SharedFilter = Arc::new(RwLock::new(filter));
+            let input = filtered(wav.clone().into(), &filter, start);
+
+            async move {
+
+                let mut input = input.make_playable_async(get_codec_registry(), get_probe()).await.unwrap();
+
+                let parsed = input.parsed_mut().unwrap();
+                let mut frames = 0;
+
+                while let Ok(packet) = parsed.format.next_packet() {
+                    frames += parsed.decoder.decode(&packet).unwrap().frames();
+                }
+
+                frames
+            }
+        };
+
+        // songbird reads the raw PCM, twice as fast means half the frames
+        let fast = frames(Filter::Speed(2.0), Duration::ZERO).await;
+        assert!((3990..=4000).contains(&fast), "{} frames", fast);
+
+        // Starting halfway through leaves the second half
+        let restored = frames(Filter::Off, Duration::from_millis(500)).await;
+        assert!((3990..=4000).contains(&restored), "{} frames", restored);
+
+        // Looping seeks back to the start, which a downloaded song allows
+        let filter: SharedFilter = Arc::new(RwLock::new(Filter::Off));
+        let mut input = filtered(wav.clone().into(), &filter, Duration::ZERO).make_playable_async(get_codec_registry(), get_probe()).await.unwrap();
+
+        let parsed = input.parsed_mut().unwrap();
+        assert!(parsed.supports_backseek);
+
+        let mut frames = 0;
+
+        for _ in 0..2 {
+
+            while let Ok(packet) = parsed.format.next_packet() {
+                frames += parsed.decoder.decode(&packet).unwrap().frames();
+            }
+
+            parsed.format.seek(SeekMode::Accurate, SeekTo::Time { time: Time::default(), track_id: None }).unwrap();
+            parsed.decoder.reset();
+        }
+
+        assert!((15980..=16000).contains(&frames), "{} frames", frames);
+    }
+
+
+    #[test]
+    fn test_bass_boost() {
+
+        // A low tone comes out louder, a high one stays about the same
+        let peak = |frequency: f32| {
+
+            let mut shelf = Biquad::low_shelf(48000.0, BASS_FREQUENCY, BASS_GAIN_DB);
+
+            (0..48000)
+                .map(|i| shelf.process((2.0 * PI * frequency * i as f32 / 48000.0).sin() * 0.1))
+                .skip(24000)
+                .fold(0f32, |peak, sample| peak.max(sample.abs()))
+        };
+
+        assert!(peak(40.0) > 0.2);
+        assert!((peak(5000.0) - 0.1).abs() < 0.01);
+    }
+}
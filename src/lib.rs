@@ -8,4 +8,49 @@ pub mod bot;
 pub use bot::*;
 
 pub mod qqmusic;
-pub use qqmusic::*;
\ No newline at end of file
+pub use qqmusic::*;
+
+pub mod credential;
+pub use credential::*;
+
+pub mod login;
+pub use login::*;
+
+pub mod account;
+pub use account::*;
+
+pub mod musicu;
+pub use musicu::*;
+
+pub mod sign;
+pub use sign::*;
+
+pub mod config;
+pub use config::*;
+
+pub mod ratelimit;
+pub use ratelimit::*;
+
+pub mod cache;
+pub use cache::*;
+
+pub mod audio_cache;
+pub use audio_cache::*;
+
+pub mod player;
+pub use player::*;
+
+pub mod filter;
+pub use filter::*;
+
+pub mod saved_queue;
+pub use saved_queue::*;
+
+pub mod storage;
+pub use storage::*;
+
+pub mod settings;
+pub use settings::*;
+
+pub mod permission;
+pub use permission::*;
@@ -0,0 +1,357 @@
+use crate::config::QQMusicConfig;
+use crate::credential::Credential;
+use crate::error::*;
+use crate::musicu::*;
+use crate::structs::*;
+
+use reqwest::header::{HeaderMap, COOKIE, LOCATION, REFERER, SET_COOKIE};
+use reqwest::redirect::Policy;
+use reqwest::Client;
+use serde_json::json;
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use log::{info, warn, error, debug};
+
+
+const QQ_APPID: &str = "716027609";
+const QQ_DAID: &str = "383";
+const QQMUSIC_APPID: &str = "100497308";
+const REDIRECT_URI: &str = "https://y.qq.com/portal/wx_redirect.html?login_type=1&surl=https://y.qq.com/";
+
+
+// One QR code login session. The QR code stays valid for about two minutes
+
+pub struct QRLogin {
+
+    client: Client,
+    config: QQMusicConfig,
+    qrsig: String,
+    pub image: Vec<u8>,
+}
+
+impl QRLogin {
+
+    // Fetch a new login QR code
+
+    pub async fn new(config: &QQMusicConfig) -> Result<Self, QQMusicError> {
+
+        let client = match config.client_builder()?
+            .redirect(Policy::none())
+            .timeout(Duration::from_secs(15))
+            .build() {
+
+            Ok(client) => client,
+
+            Err(e) => {
+
+                error!("QRLogin: Failed to initialize the client");
+                return Err(QQMusicError::QQMusicClientError(e));
+            }
+        };
+
+        let res = client.get(format!("{}/ptqrshow", config.ptlogin_url))
+            .query(&[
+                ("appid", QQ_APPID),
+                ("e", "2"),
+                ("l", "M"),
+                ("s", "3"),
+                ("d", "72"),
+                ("v", "4"),
+                ("t", &format!("0.{}", timestamp_millis())),
+                ("daid", QQ_DAID),
+                ("pt_3rd_aid", QQMUSIC_APPID),
+            ])
+            .send()
+            .await
+            .map_err(|e| {
+                error!("QRLogin: Failed to request the QR code: {:?}", e);
+                QQMusicError::QQMusicRequestError(e)
+            })?;
+
+        let qrsig = match get_cookies(res.headers()).into_iter().find(|(name, _)| name == "qrsig") {
+
+            Some((_, qrsig)) => qrsig,
+
+            None => {
+
+                error!("QRLogin: No qrsig in the QR code response");
+                return Err(QQMusicError::QQMusicQRCodeError);
+            }
+        };
+
+        let image = res.bytes().await.map_err(|e| {
+            error!("QRLogin: Failed to read the QR code image: {:?}", e);
+            QQMusicError::QQMusicRequestError(e)
+        })?;
+
+        info!("QRLogin: Success to get the login QR code");
+
+        Ok(QRLogin { client, config: config.clone(), qrsig, image: image.to_vec() })
+    }
+
+
+    // Ask the login server whether the QR code has been scanned
+
+    pub async fn poll(&self) -> Result<QRLoginStatus, QQMusicError> {
+
+        let login_jump = format!("{}/oauth2.0/login_jump", self.config.graph_url);
+
+        let res = self.client.get(format!("{}/ptqrlogin", self.config.ptlogin_url))
+            .header(COOKIE, format!("qrsig={}", self.qrsig))
+            .query(&[
+                ("u1", login_jump.as_str()),
+                ("ptqrtoken", &hash33(&self.qrsig, 0).to_string()),
+                ("ptredirect", "0"),
+                ("h", "1"),
+                ("t", "1"),
+                ("g", "1"),
+                ("from_ui", "1"),
+                ("ptlang", "2052"),
+                ("action", &format!("0-0-{}", timestamp_millis())),
+                ("js_ver", "20102616"),
+                ("js_type", "1"),
+                ("pt_uistyle", "40"),
+                ("aid", QQ_APPID),
+                ("daid", QQ_DAID),
+                ("pt_3rd_aid", QQMUSIC_APPID),
+                ("has_onekey", "1"),
+            ])
+            .send()
+            .await
+            .map_err(|e| {
+                error!("QRLogin: Failed to poll the QR code status: {:?}", e);
+                QQMusicError::QQMusicRequestError(e)
+            })?;
+
+        let text = res.text().await?;
+
+        debug!("QRLogin: {}", text);
+
+        parse_ptuicb(&text).ok_or(QQMusicError::QQMusicLoginError)
+    }
+
+
+    // Poll until the user confirms the login on the phone, then exchange it for a credential
+
+    pub async fn wait(&self, timeout: Duration) -> Result<Credential, QQMusicError> {
+
+        let deadline = tokio::time::Instant::now() + timeout;
+
+        while tokio::time::Instant::now() < deadline {
+
+            match self.poll().await? {
+
+                QRLoginStatus::Waiting | QRLoginStatus::Scanned => {}
+
+                QRLoginStatus::Expired => return Err(QQMusicError::QQMusicQRCodeExpiredError),
+
+                QRLoginStatus::Refused => return Err(QQMusicError::QQMusicLoginRefusedError),
+
+                QRLoginStatus::Success(url) => return self.authorize(&url).await,
+            }
+
+            tokio::time::sleep(Duration::from_secs(2)).await;
+        }
+
+        warn!("QRLogin: Timed out waiting for the QR code to be scanned");
+
+        Err(QQMusicError::QQMusicQRCodeExpiredError)
+    }
+
+
+    // Turn the QQ login into a QQ Music credential through the QQ Connect OAuth flow
+
+    async fn authorize(&self, check_sig_url: &str) -> Result<Credential, QQMusicError> {
+
+        // check_sig hands out the graph.qq.com cookies, p_skey among them
+
+        let res = self.client.get(check_sig_url).send().await.map_err(|e| {
+            error!("QRLogin: Failed to check the login signature: {:?}", e);
+            QQMusicError::QQMusicRequestError(e)
+        })?;
+
+        let cookies = get_cookies(res.headers());
+
+        let p_skey = match cookies.iter().find(|(name, value)| name == "p_skey" && !value.is_empty()) {
+
+            Some((_, p_skey)) => p_skey.clone(),
+
+            None => {
+
+                error!("QRLogin: No p_skey in the check_sig response");
+                return Err(QQMusicError::QQMusicLoginError);
+            }
+        };
+
+        let g_tk = hash33(&p_skey, 5381);
+
+        let cookie = cookies.iter()
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect::<Vec<String>>()
+            .join("; ");
+
+        let res = self.client.post(format!("{}/oauth2.0/authorize", self.config.graph_url))
+            .header(COOKIE, cookie)
+            .form(&[
+                ("response_type", "code"),
+                ("client_id", QQMUSIC_APPID),
+                ("redirect_uri", REDIRECT_URI),
+                ("scope", "get_user_info,get_app_friends"),
+                ("state", "state"),
+                ("switch", ""),
+                ("from_ptlogin", "1"),
+                ("src", "1"),
+                ("update_auth", "1"),
+                ("openapi", "1010_1030"),
+                ("g_tk", &g_tk.to_string()),
+                ("auth_time", &timestamp_millis().to_string()),
+                ("ui", ""),
+            ])
+            .send()
+            .await
+            .map_err(|e| {
+                error!("QRLogin: Failed to authorize QQ Music: {:?}", e);
+                QQMusicError::QQMusicRequestError(e)
+            })?;
+
+        let code = match res.headers().get(LOCATION).and_then(|location| location.to_str().ok()).and_then(|location| query_value(location, "code")) {
+
+            Some(code) => code,
+
+            None => {
+
+                error!("QRLogin: No authorization code in the authorize response");
+                return Err(QQMusicError::QQMusicLoginError);
+            }
+        };
+
+        let mut headers = HeaderMap::new();
+        headers.insert(REFERER, self.config.site_url.parse().map_err(QQMusicError::QQMusicCookieError)?);
+
+        let mut batch = MusicuBatch::new(json!({"g_tk": g_tk, "platform": "yqq", "ct": 24, "cv": 0}));
+        let key = batch.push(&QQConnectLogin { code })?;
+
+        let credential = batch.send_with(&self.client, &self.config, None, headers).await?.take(key)?;
+
+        if credential.musickey.is_empty() {
+
+            error!("QRLogin: Login server returned no musickey");
+            return Err(QQMusicError::QQMusicLoginError);
+        }
+
+        info!("QRLogin: Success to login as {}", credential.musicid);
+
+        Ok(credential)
+    }
+}
+
+
+// Hash used by ptlogin for ptqrtoken (seed 0) and g_tk (seed 5381)
+
+fn hash33(s: &str, seed: i64) -> i64 {
+
+    let mut hash = seed;
+
+    for c in s.chars() {
+        hash = (hash + (hash << 5) + c as i64) & 0x7fffffff;
+    }
+
+    hash
+}
+
+
+// Parse `ptuiCB('66','0','','0','...', '')` into the login status
+
+fn parse_ptuicb(text: &str) -> Option<QRLoginStatus> {
+
+    let args: Vec<&str> = text.split('\'').skip(1).step_by(2).collect();
+
+    let status = match *args.first()? {
+        "0" => QRLoginStatus::Success(args.get(2)?.to_string()),
+        "66" => QRLoginStatus::Waiting,
+        "67" => QRLoginStatus::Scanned,
+        "65" => QRLoginStatus::Expired,
+        "68" => QRLoginStatus::Refused,
+        code => {
+            warn!("QRLogin: Unknown login status {}", code);
+            return None;
+        }
+    };
+
+    Some(status)
+}
+
+
+// Collect `name=value` pairs from the Set-Cookie headers
+
+fn get_cookies(headers: &HeaderMap) -> Vec<(String, String)> {
+
+    headers.get_all(SET_COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .filter_map(|value| value.split(';').next())
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .collect()
+}
+
+fn query_value(url: &str, key: &str) -> Option<String> {
+
+    let (_, query) = url.split_once('?')?;
+
+    query.split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(name, _)| *name == key)
+        .map(|(_, value)| value.to_string())
+}
+
+fn timestamp_millis() -> u128 {
+
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis()).unwrap_or(0)
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_hash33() {
+
+        assert_eq!(hash33("", 0), 0);
+        assert_eq!(hash33("a", 0), 97);
+        assert_eq!(hash33("ab", 0), 97 * 33 + 98);
+        assert_eq!(hash33("a", 5381), 5381 * 33 + 97);
+    }
+
+
+    #[test]
+    fn test_parse_ptuicb() {
+
+        let waiting = "ptuiCB('66','0','','0','二维码未失效。', '')";
+        assert!(matches!(parse_ptuicb(waiting), Some(QRLoginStatus::Waiting)));
+
+        let expired = "ptuiCB('65','0','','0','二维码已失效。', '')";
+        assert!(matches!(parse_ptuicb(expired), Some(QRLoginStatus::Expired)));
+
+        let success = "ptuiCB('0','0','https://ssl.ptlogin2.graph.qq.com/check_sig?uin=123&ptsigx=abc','0','登录成功！', 'nick')";
+
+        match parse_ptuicb(success) {
+            Some(QRLoginStatus::Success(url)) => assert!(url.starts_with("https://ssl.ptlogin2.graph.qq.com/check_sig")),
+            other => panic!("unexpected status {:?}", other),
+        }
+
+        assert!(parse_ptuicb("garbage").is_none());
+    }
+
+
+    #[test]
+    fn test_query_value() {
+
+        let location = "https://y.qq.com/portal/wx_redirect.html?login_type=1&code=ABCDEF&state=state";
+
+        assert_eq!(query_value(location, "code").as_deref(), Some("ABCDEF"));
+        assert_eq!(query_value(location, "missing"), None);
+    }
+}
@@ -1,98 +1,859 @@
 use discord_qqmusic_bot::bot::*;
 use discord_qqmusic_bot::qqmusic::*;
-use discord_qqmusic_bot::error::*;
+use discord_qqmusic_bot::login::*;
 use discord_qqmusic_bot::structs::*;
+use discord_qqmusic_bot::error::*;
+use discord_qqmusic_bot::config::*;
+use discord_qqmusic_bot::audio_cache::*;
+use discord_qqmusic_bot::player::*;
+use discord_qqmusic_bot::filter::*;
+use discord_qqmusic_bot::saved_queue::*;
+use discord_qqmusic_bot::storage::*;
+use discord_qqmusic_bot::settings::*;
+use discord_qqmusic_bot::permission::*;
 
 use dotenvy::dotenv;
-use tokio::sync::mpsc::{self, Sender, Receiver};
+use serenity::all::{ChannelId, Context, GuildId, Message};
+use tokio::sync::mpsc;
 use log::{info, error,debug,warn};
+use std::collections::{BTreeMap, HashMap};
+use std::io::Cursor;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+
+// How often the queues are checked for changes worth saving
+const QUEUE_SAVE_INTERVAL: Duration = Duration::from_secs(5);
+
+// How often the calls are checked for an idle_timeout that ran out
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+
+// What the commands run against, shared by every command task
+
+#[derive(Clone)]
+struct Services {
+
+    qqmusic: Arc<QQMusic>,
+    players: Arc<Players>,
+    storage: Arc<Storage>,
+    settings: Arc<GuildSettingsCache>,
+}
+
 
 #[tokio::main]
 async fn main () {
 
-    
+
     dotenv().ok();
     env_logger::init();
 
     let (tx, mut rx) = mpsc::channel(100);
+    let (notice_tx, mut notice_rx) = mpsc::channel::<String>(10);
+    let (ready_tx, mut ready_rx) = mpsc::channel::<Context>(1);
+
+    let language = Language::from_env();
+    let config = QQMusicConfig::from_env();
+
+    let storage = match Storage::open_default() {
+
+        Ok(storage) => Arc::new(storage),
+
+        Err(e) => {
+
+            error!("Failed to open the storage: {}", error_chain(&e));
+            return;
+        }
+    };
+
+    let qqmusic_instance = match QQMusic::new(config, Arc::clone(&storage)).await {
+
+        Ok(qqmusic) => Arc::new(qqmusic.with_notifier(notice_tx)),
+
+        Err(e) => {
 
-    let qqmusic_instance = Arc::new(QQMusic::new().await.unwrap());
+            error!("Failed to start QQ Music: {:?}", e);
+            return;
+        }
+    };
+
+    let guild_settings = Arc::new(GuildSettingsCache::new(Arc::clone(&storage)));
+
+    let mut app = match Bot::new(tx, ready_tx, Arc::clone(&guild_settings)).await {
+
+        Ok(app) => app,
+
+        Err(e) => {
+
+            error!("Failed to start the bot: {}", e);
+            return;
+        }
+    };
+
+    let services = Services {
+        qqmusic: qqmusic_instance,
+        // Per-guild playback settings such as the crossfade
+        players: Arc::new(Players::default()),
+        storage,
+        settings: guild_settings,
+    };
+
+    let http = app.client.http.clone();
+    let owner_id = app.owner_id;
 
     tokio::spawn(async move {
 
-        let mut app = Bot::new(tx).await.unwrap();
-        app.client.start().await.unwrap();
+        if let Err(e) = app.client.start().await {
+            error!("Discord client stopped: {:?}", e);
+        }
     });
 
 
-    loop {
+    // Pick up the queues saved before the restart, then keep them saved
+    let services_clone = services.clone();
+
+    tokio::spawn(async move {
+
+        let Some(ctx) = ready_rx.recv().await else {
+            return;
+        };
+
+        restore_queues(&services_clone, &ctx, language).await;
+
+        tokio::spawn(leave_idle_channels(ctx.clone(), Arc::clone(&services_clone.settings)));
+
+        save_queues(&ctx, &services_clone.players, &services_clone.storage).await;
+    });
+
+
+    // Forward QQ Music notices (e.g. expired credential) to the bot owner
+    tokio::spawn(async move {
 
-        let command = rx.recv().await.unwrap();
+        while let Some(notice) = notice_rx.recv().await {
+
+            match owner_id {
+
+                Some(owner) => {
+                    let _ = Bot::send_direct_message(&http, owner, &notice).await;
+                }
+
+                None => warn!("No DISCORD_OWNER_ID to notify: {}", notice),
+            }
+        }
+    });
+
+
+    while let Some(command) = rx.recv().await {
 
         debug!("Result = {:?}",command);
 
-        let qqmusic_clone = Arc::clone(&qqmusic_instance);
+        let services_clone = services.clone();
 
         tokio::spawn(async move {
 
-            let (ctx, msg, response_content) : (_,_,String) = match command {
-                
-                // Command Cancel match
-                BotCommand::Cancel { ctx, msg } => {
+            let response_content = match dispatch(&services_clone, &command, language).await {
 
-                    let result = match Bot::stop_music(&ctx,&msg).await {
+                Ok(content) => content,
 
-                        Ok(_) => {
+                Err(e) => {
 
-                            info!("Success to cancel shit music");
+                    let (_, msg) = command.context();
 
-                            "Sir, I suceess to cancle this shit music".to_string()
-                        }
+                    // The guild may have picked its own language
+                    let language = match msg.guild_id {
+                        Some(guild_id) => services_clone.settings.get(guild_id).await.language.unwrap_or(language),
+                        None => language,
+                    };
 
-                        Err(_) => {
+                    error!("Command {} from {} ({}) failed [{}]: {}", command.name(), msg.author.name, msg.author.id, e.code(), error_chain(&e));
+                    e.reply(language)
+                }
+            };
 
-                            warn!("Failed to cancel music");
-                            "Sir, I failed to cancel this shit music".to_string()
-                        }
-                    };
+            let (ctx, msg) = command.context();
+
+            if let Err(e) = msg.reply(ctx, response_content).await {
+                error!("Failed to reply to command {}: {:?}", command.name(), e);
+            }
+        });
+    }
+
+    error!("Command channel closed, shutting down");
+}
+
+
+// Run one command and build the reply, errors are turned into a friendly message by the caller
+
+async fn dispatch(services: &Services, command: &BotCommand, language: Language) -> Result<String, CommandError> {
+
+    let Services { qqmusic, players, storage, .. } = services;
+
+    let (_, msg) = command.context();
+
+    // Direct messages have no guild and get the defaults
+    let guild = match msg.guild_id {
+        Some(guild_id) => services.settings.get(guild_id).await,
+        None => GuildSettings::default(),
+    };
+
+    let language = guild.language.unwrap_or(language);
+    let quality = guild.quality.unwrap_or(qqmusic.config().quality);
+
+    let settings = msg.guild_id
+        .map(|guild_id| players.get_or_insert_with(guild_id, || PlayerSettings { volume: guild.volume, ..PlayerSettings::default() }))
+        .unwrap_or_default();
+
+    // Direct messages can't be checked and are turned down by the commands that need a guild
+    if msg.guild_id.is_some() {
+
+        let (ctx, _) = command.context();
+
+        let requirement = Requirement::of(command);
+
+        if !requirement.allows(&Bot::author(ctx, msg, guild.dj_role).await) {
+            return Err(requirement.denial().into());
+        }
+    }
+
+    match command {
+
+        // Command Cancel match
+        BotCommand::Cancel { ctx, msg } => {
+
+            Bot::stop_music(ctx, msg).await?;
+
+            info!("Success to cancel shit music");
+
+            Ok("Sir, I suceess to cancle this shit music".to_string())
+        }
+
+        // Command Skip match
+        BotCommand::Skip { ctx, msg } => {
+
+            Bot::skip_music(ctx, msg).await?;
+
+            info!("Success to skip the music");
+
+            Ok("Sir, on to the next song".to_string())
+        }
 
+        // Command VoteSkip match
+        BotCommand::VoteSkip { ctx, msg } => {
 
-                    (ctx, msg, result)
+            let (votes, needed) = Bot::vote_skip(ctx, msg, guild.vote_skip).await?;
+
+            info!("Vote to skip from {} ({}/{})", msg.author.name, votes, needed);
+
+            if votes >= needed {
+                Ok(format!("Sir, {}/{} votes, on to the next song", votes, needed))
+            } else {
+                Ok(format!("Sir, {}/{} votes to skip this song", votes, needed))
+            }
+        }
+
+        // Command Crossfade match
+        BotCommand::Crossfade { msg, seconds, .. } => {
+
+            let guild_id = msg.guild_id.ok_or(BotError::BotAudioChannelError)?;
+
+            let crossfade = players.set_crossfade(guild_id, Duration::from_secs(*seconds));
+
+            info!("Crossfade of guild {} set to {:?}", guild_id, crossfade);
+
+            // Songs already in the queue keep the crossfade they were queued with
+            if crossfade.is_zero() {
+                Ok("Sir, songs now follow each other without a crossfade".to_string())
+            } else {
+                Ok(format!("Sir, songs now crossfade over {} seconds", crossfade.as_secs()))
+            }
+        }
+
+        // Command Filter match
+        BotCommand::Filter { msg, filter, .. } => {
+
+            let guild_id = msg.guild_id.ok_or(BotError::BotAudioChannelError)?;
+
+            // Heard at once in the playing song too
+            players.set_filter(guild_id, *filter);
+
+            info!("Filter of guild {} set to {}", guild_id, filter);
+
+            match filter {
+                Filter::Off => Ok("Sir, the filter is off".to_string()),
+                filter => Ok(format!("Sir, the music now plays with {}", filter)),
+            }
+        }
+
+        // Command Loop match
+        BotCommand::Loop { ctx, msg, loop_mode } => {
+
+            let guild_id = msg.guild_id.ok_or(BotError::BotAudioChannelError)?;
+
+            players.set_loop(guild_id, *loop_mode);
+
+            Bot::update_player(ctx, msg, &players.get(guild_id)).await?;
+
+            info!("Loop of guild {} set to {:?}", guild_id, loop_mode);
+
+            match loop_mode {
+                LoopMode::Song => Ok("Sir, every song now repeats until it is skipped".to_string()),
+                LoopMode::Off => Ok("Sir, songs no longer repeat".to_string()),
+            }
+        }
+
+        // Command Volume match
+        BotCommand::Volume { ctx, msg, percent } => {
+
+            let guild_id = msg.guild_id.ok_or(BotError::BotAudioChannelError)?;
+
+            let volume = players.set_volume(guild_id, *percent as f32 / 100.0);
+
+            Bot::update_player(ctx, msg, &players.get(guild_id)).await?;
+
+            info!("Volume of guild {} set to {}", guild_id, volume);
+
+            Ok(format!("Sir, the volume is now {}%", (volume * 100.0).round()))
+        }
+
+        // Command Search match
+        BotCommand::Search { name, .. } => {
+
+            let playlist_table = qqmusic.get_search_list(name).await?;
+
+            info!("Success to get search result");
+
+            Ok(playlist_table)
+        }
+
+        // Command Play match
+        BotCommand::Play { ctx, msg, id } => {
+
+            if let Some((0, limit)) = queue_room(ctx, msg, &guild).await {
+                return Err(limit.into());
+            }
+
+            // Only looked up when the guild restricts what it plays. A song QQ Music doesn't know
+            // the length of is checked again once the file tells
+            let mut needs_length = false;
+
+            if guild.has_restrictions() {
+
+                let song = qqmusic.get_song(id).await?;
+
+                if let Some(rejection) = guild.restriction(&song) {
+
+                    info!("Song {} rejected in guild {:?}: {}", id, msg.guild_id, rejection);
+                    return Err(rejection.into());
                 }
 
-                // Command Search match
-                BotCommand::Search { ctx, msg, Name } => {
+                needs_length = guild.needs_length(&song);
+            }
+
+            // A cached song plays at once, even when the vkey server is down
+            let track = match qqmusic.audio_cache().get(id, quality).await {
+
+                Some(track) => track,
 
-                    // let result = "Got Command Search";
+                None => {
 
-                    let playlist_table = qqmusic_clone.get_search_list(&Name).await.unwrap();
+                    let url = qqmusic.get_qqmusic_play_urls_in(std::slice::from_ref(id), quality).await
+                        .remove(id)
+                        .unwrap_or(Err(QQMusicError::QQMusicPlayError))?;
 
-                    info!("Success to get search result");
+                    let bytes = Bot::download_music(qqmusic.downloader(), &url).await?;
 
-                    (ctx, msg, playlist_table)
+                    qqmusic.audio_cache().insert(id, quality, &bytes).await;
+
+                    AudioTrack::new(id, bytes.clone().into(), probe_duration(Cursor::new(bytes)))
                 }
+            };
+
+            if let Some(rejection) = guild.length_restriction(track.duration).filter(|_| needs_length) {
+
+                info!("Song {} rejected in guild {:?}: {}", id, msg.guild_id, rejection);
+                return Err(rejection.into());
+            }
+
+            Bot::play_music(ctx, msg, track, &settings).await?;
+
+            if let Some(guild_id) = msg.guild_id.filter(|_| guild.fair_queue) {
+                Bot::share_queue(ctx, guild_id).await;
+            }
+
+            record_history(storage, msg, &[MusicPlayList { id: id.clone(), ..MusicPlayList::default() }]).await;
+
+            info!("Success to add music into queue");
+
+            Ok("Got it! I'm playing this music".to_string())
+        }
+
+        // Command Login match
+        BotCommand::Login { ctx, msg } => {
+
+            let qrlogin = QRLogin::new(qqmusic.config()).await?;
+
+            Bot::send_login_qrcode(ctx, msg, &qrlogin.image).await?;
+
+            let ctx = ctx.clone();
+            let owner = msg.author.id;
+            let qqmusic = Arc::clone(qqmusic);
+
+            // Wait for the scan in the background so the reply is not held up
+            tokio::spawn(async move {
+
+                let result = match qrlogin.wait(Duration::from_secs(120)).await {
+
+                    Ok(credential) => {
+
+                        let musicid = credential.musicid;
+
+                        qqmusic.login(credential).await.map(|_| musicid)
+                    }
+
+                    Err(e) => Err(e),
+                };
+
+                let notice = match result {
+
+                    Ok(musicid) => {
+
+                        info!("Success to login QQ Music as {}", musicid);
+                        format!("Sir, I added QQ Music account {} to the pool", musicid)
+                    }
+
+                    Err(e) => {
+
+                        warn!("QR code login failed [{}]: {}", e.code(), error_chain(&e));
+                        CommandError::from(e).reply(language)
+                    }
+                };
+
+                let _ = Bot::send_direct_message(&ctx, owner, &notice).await;
+            });
+
+            Ok("Sir, I sent the login QR code to your DMs".to_string())
+        }
+
+        // Command Accounts match
+        BotCommand::Accounts { .. } => {
+
+            Ok(qqmusic.get_account_list())
+        }
+
+        // Command Playlist match
+        BotCommand::Playlist { ctx, msg, id } => {
+
+            let songs = qqmusic.get_playlist_songs(id).await?;
+
+            queue_songs(services, ctx, msg, songs, &settings, &guild, language).await
+        }
+
+        // Command Settings match
+        BotCommand::Settings { ctx, msg, change } => {
+
+            let guild_id = msg.guild_id.ok_or(BotError::BotAudioChannelError)?;
+
+            let Some(change) = change else {
+                return Ok(guild.describe());
+            };
+
+            let guild = services.settings.update(guild_id, change.clone()).await?;
 
-                // Command Play match
-                BotCommand::Play { ctx, msg, ID } => {
+            // The new volume is heard at once, other settings apply from the next command
+            if let SettingChange::Volume(volume) = change {
 
-                    let result = "Got it! I'm playing this music".to_string();
+                players.set_volume(guild_id, *volume);
 
-                    // let id = String::from("002GwAma2DGN2x");
+                Bot::update_player(ctx, msg, &players.get(guild_id)).await?;
+            }
 
-                    let url = qqmusic_clone.get_qqmusic_play_url(&ID).await.unwrap();
+            info!("Settings of guild {} changed: {:?}", guild_id, change);
 
-                    Bot::play_music(&ctx,&msg,&url).await.unwrap();
+            Ok(format!("Sir, the settings are saved\n{}", guild.describe()))
+        }
 
-                    info!("Success to add music into queue");
+        // Command Blocklist match
+        BotCommand::Blocklist { msg, change, .. } => {
 
-                    (ctx, msg, result)
+            let guild_id = msg.guild_id.ok_or(BotError::BotAudioChannelError)?;
+
+            let Some(change) = change else {
+                return Ok(guild.describe_blocklist());
+            };
+
+            // Songs already in the queue stay there
+            let guild = services.settings.update(guild_id, change.clone()).await?;
+
+            info!("Blocklist of guild {} changed: {:?}", guild_id, change);
+
+            Ok(format!("Sir, the blocklist is saved\n{}", guild.describe_blocklist()))
+        }
+
+        // Command Album match
+        BotCommand::Album { ctx, msg, id } => {
+
+            let songs = qqmusic.get_album_songs(id).await?;
+
+            queue_songs(services, ctx, msg, songs, &settings, &guild, language).await
+        }
+    }
+}
+
+
+// Queue the playable songs of a playlist or album
+
+async fn queue_songs(services: &Services, ctx: &Context, msg: &Message, songs: Vec<MusicPlayList>, settings: &PlayerSettings, guild: &GuildSettings, language: Language) -> Result<String, CommandError> {
+
+    let room = queue_room(ctx, msg, guild).await;
+
+    if let Some((0, limit)) = room {
+        return Err(limit.into());
+    }
+
+    let quality = guild.quality.unwrap_or(services.qqmusic.config().quality);
+
+    // Songs the guild doesn't take never reach the vkey server
+    let mut rejections: BTreeMap<&'static str, usize> = BTreeMap::new();
+
+    let songs: Vec<MusicPlayList> = songs.into_iter()
+        .filter(|song| match guild.restriction(song) {
+            Some(rejection) => { *rejections.entry(rejection.user_message(language)).or_default() += 1; false }
+            None => true,
+        })
+        .collect();
+
+    let (tracks, mut reasons) = resolve_tracks(&services.qqmusic, &songs, quality, language, |song| guild.needs_length(song)).await;
+
+    // Songs of unknown length were downloaded above, the same check /play does on their files
+    let mut tracks: Vec<AudioTrack> = tracks.into_iter()
+        .filter(|track| {
+
+            let song = songs.iter().find(|song| song.id == track.songmid);
+
+            match guild.length_restriction(track.duration).filter(|_| song.is_some_and(|song| guild.needs_length(song))) {
+                Some(rejection) => { *rejections.entry(rejection.user_message(language)).or_default() += 1; false }
+                None => true,
+            }
+        })
+        .collect();
+
+    for (reason, count) in rejections {
+        *reasons.entry(reason).or_default() += count;
+    }
+
+    // Only the songs that fit, in the order of the list
+    let left_out = room.as_ref().map(|(room, _)| tracks.len().saturating_sub(*room)).unwrap_or(0);
+
+    tracks.truncate(tracks.len() - left_out);
+
+    let queued: Vec<MusicPlayList> = songs.into_iter().filter(|song| tracks.iter().any(|track| track.songmid == song.id)).collect();
+
+    let count = Bot::play_music_list(ctx, msg, tracks, settings).await?;
+
+    if let Some(guild_id) = msg.guild_id.filter(|_| guild.fair_queue) {
+        Bot::share_queue(ctx, guild_id).await;
+    }
+
+    record_history(&services.storage, msg, &queued).await;
+
+    info!("Success to add {} musics into queue", count);
+
+    let mut result = format!("Got it! I queued {} songs", count);
+
+    if let Some((_, limit)) = room.filter(|_| left_out > 0) {
+        result.push_str(&format!("\n{} × {}", left_out, limit.user_message(language)));
+    }
+
+    // e.g. "2 × Sir, this song is only for QQ Music VIP members."
+    for (reason, count) in reasons {
+        result.push_str(&format!("\n{} × {}", count, reason));
+    }
+
+    Ok(result)
+}
+
+
+// Songs the author may still queue under the guild's max_queue_length and max_user_tracks,
+// with the error of the tighter limit. None when there is no limit
+
+async fn queue_room(ctx: &Context, msg: &Message, guild: &GuildSettings) -> Option<(usize, BotError)> {
+
+    let guild_id = msg.guild_id?;
+
+    let mut room = None;
+
+    if let Some(max_queue_length) = guild.max_queue_length {
+        room = Some((max_queue_length.saturating_sub(Bot::queue_length(ctx, guild_id).await), BotError::BotQueueFullError));
+    }
+
+    if let Some(max_user_tracks) = guild.max_user_tracks {
+
+        let user_room = max_user_tracks.saturating_sub(Bot::queued_by(ctx, guild_id, msg.author.id).await);
+
+        if room.as_ref().is_none_or(|(room, _)| user_room < *room) {
+            room = Some((user_room, BotError::BotUserQueueFullError));
+        }
+    }
+
+    room
+}
+
+
+// Remember who asked for what in the guild, a failure doesn't stop the music
+
+async fn record_history(storage: &Storage, msg: &Message, songs: &[MusicPlayList]) {
+
+    let Some(guild_id) = msg.guild_id else {
+        return;
+    };
+
+    if let Err(e) = storage.history().record(guild_id, songs, Some(msg.author.id)).await {
+        warn!("Failed to record the history of guild {}: {}", guild_id, error_chain(&e));
+    }
+}
+
+
+// Tracks for the songs that can be played, in order, resolved in batches. The others are
+// counted by the reason they can't
+
+async fn resolve_tracks(qqmusic: &QQMusic, songs: &[MusicPlayList], quality: Quality, language: Language, download: impl Fn(&MusicPlayList) -> bool) -> (Vec<AudioTrack>, BTreeMap<&'static str, usize>) {
+
+    let mut cached: HashMap<String, AudioTrack> = HashMap::new();
+    let mut songmids: Vec<String> = vec![];
+
+    for song in songs {
+
+        match qqmusic.audio_cache().get(&song.id, quality).await {
+            Some(track) => { cached.insert(song.id.clone(), track); }
+            None => songmids.push(song.id.clone()),
+        }
+    }
+
+    // One vkey request per VKEY_BATCH_SIZE songs instead of one per song
+    let mut play_urls = qqmusic.get_qqmusic_play_urls_in(&songmids, quality).await;
+
+    let mut tracks: Vec<AudioTrack> = vec![];
+    let mut reasons: BTreeMap<&'static str, usize> = BTreeMap::new();
+
+    for song in songs {
+
+        if let Some(track) = cached.remove(&song.id) {
+            tracks.push(track);
+            continue;
+        }
+
+        match play_urls.remove(&song.id) {
+
+            // Downloaded rather than streamed when its length has to be read before it is queued
+            Some(Ok(url)) if download(song) => match Bot::download_music(qqmusic.downloader(), &url).await {
+
+                Ok(bytes) => {
+
+                    qqmusic.audio_cache().insert(&song.id, quality, &bytes).await;
+                    tracks.push(AudioTrack::new(&song.id, bytes.clone().into(), probe_duration(Cursor::new(bytes))));
+                }
+
+                Err(e) => *reasons.entry(e.user_message(language)).or_default() += 1,
+            },
+
+            Some(Ok(url)) => tracks.push(AudioTrack::new(
+                &song.id,
+                AudioCache::stream(qqmusic.audio_cache(), qqmusic.downloader(), &url, &song.id, quality),
+                Some(Duration::from_secs(song.interval)).filter(|duration| !duration.is_zero()),
+            )),
+
+            Some(Err(e)) => *reasons.entry(e.user_message(language)).or_default() += 1,
+            None => *reasons.entry(QQMusicError::QQMusicPlayError.user_message(language)).or_default() += 1,
+        }
+    }
+
+    (tracks, reasons)
+}
+
+
+// Queue the saved songs again in the guilds where someone is still listening
+
+async fn restore_queues(services: &Services, ctx: &Context, language: Language) {
+
+    let Services { qqmusic, players, storage, .. } = services;
+
+    let queues = storage.saved_queues().list().await.unwrap_or_else(|e| {
+        error!("Failed to load the saved queues: {}", error_chain(&e));
+        vec![]
+    });
+
+    info!("Loaded {} saved queues", queues.len());
+
+    for saved in queues {
+
+        let guild_id = saved.guild_id;
+        let guild = services.settings.get(guild_id).await;
+
+        if Bot::listeners(ctx, guild_id, saved.channel_id).is_empty() {
+
+            info!("Queue of guild {} not restored, nobody is left in its channel", guild_id);
+            continue;
+        }
+
+        players.set_loop(guild_id, saved.loop_mode);
+        players.set_volume(guild_id, saved.volume);
+
+        let songs: Vec<MusicPlayList> = saved.songs.iter()
+            .map(|song| MusicPlayList { id: song.songmid.clone(), interval: song.duration.unwrap_or_default(), ..MusicPlayList::default() })
+            .collect();
+
+        let quality = guild.quality.unwrap_or(qqmusic.config().quality);
+
+        let (mut tracks, _) = resolve_tracks(qqmusic, &songs, quality, guild.language.unwrap_or(language), |_| false).await;
+
+        // Songs that can't be played anymore are missing from the tracks, the rest keep their order
+        let mut saved_songs = saved.songs.iter().enumerate();
+
+        for track in tracks.iter_mut() {
+
+            if let Some((index, song)) = saved_songs.find(|(_, song)| song.songmid == track.songmid) {
+
+                track.requester = song.requester;
+
+                if index == 0 {
+                    track.start = saved.position();
+                }
+            }
+        }
+
+        match Bot::restore_queue(ctx, guild_id, saved.channel_id, tracks, &players.get(guild_id)).await {
+
+            Ok(count) => {
+
+                info!("Restored {} songs in guild {}", count, guild_id);
+
+                if let Some(channel_id) = guild.announce_channel {
+                    Bot::announce(ctx, channel_id, &format!("Sir, I picked the music up where it stopped, {} songs are queued", count)).await;
                 }
+            }
+
+            Err(e) => error!("Failed to restore the queue of guild {}: {}", guild_id, error_chain(&e)),
+        }
+    }
+}
+
+
+// Save the queue of every guild whenever it changed since the last check, one row per guild
+
+async fn save_queues(ctx: &Context, players: &Players, storage: &Storage) {
+
+    let Some(manager) = songbird::get(ctx).await else {
+        return;
+    };
+
+    // What the storage holds, a failed write is tried again on the next check
+    let mut saved: HashMap<GuildId, SavedQueue> = storage.saved_queues().list().await
+        .map(|queues| queues.into_iter().map(|queue| (queue.guild_id, queue)).collect())
+        .unwrap_or_default();
+
+    loop {
+
+        tokio::time::sleep(QUEUE_SAVE_INTERVAL).await;
+
+        let calls: Vec<_> = manager.iter().collect();
+        let mut queues: HashMap<GuildId, SavedQueue> = HashMap::new();
+
+        for (guild_id, call) in calls {
+
+            let (channel_id, queue) = {
+                let call = call.lock().await;
+                (call.current_channel(), call.queue().clone())
             };
 
-            msg.reply(&ctx, response_content).await.unwrap();
-        });
+            let Some(channel_id) = channel_id else {
+                continue;
+            };
+
+            let guild_id = GuildId::new(guild_id.0.get());
+            let channel_id = ChannelId::new(channel_id.0.get());
+
+            if let Some(queue) = SavedQueue::capture(guild_id, channel_id, &queue, &players.get(guild_id)).await {
+                queues.insert(guild_id, queue);
+            }
+        }
+
+        for (guild_id, queue) in &queues {
+
+            if saved.get(guild_id) == Some(queue) {
+                continue;
+            }
+
+            match storage.saved_queues().save(queue).await {
+                Ok(_) => { saved.insert(*guild_id, queue.clone()); }
+                Err(e) => error!("Failed to save the queue of guild {}: {}", guild_id, error_chain(&e)),
+            }
+        }
+
+        let stopped: Vec<GuildId> = saved.keys().filter(|guild_id| !queues.contains_key(guild_id)).copied().collect();
+
+        for guild_id in stopped {
+
+            match storage.saved_queues().remove(guild_id).await {
+                Ok(_) => { saved.remove(&guild_id); }
+                Err(e) => error!("Failed to remove the saved queue of guild {}: {}", guild_id, error_chain(&e)),
+            }
+        }
     }
+}
+
+
+// Leave the voice channel of a guild whose queue stayed empty for longer than its idle_timeout
+
+async fn leave_idle_channels(ctx: Context, settings: Arc<GuildSettingsCache>) {
 
+    let Some(manager) = songbird::get(&ctx).await else {
+        return;
+    };
 
-}
\ No newline at end of file
+    // When the queue of each guild was first seen empty
+    let mut idle_since: HashMap<GuildId, Instant> = HashMap::new();
+
+    loop {
+
+        tokio::time::sleep(IDLE_CHECK_INTERVAL).await;
+
+        let calls: Vec<_> = manager.iter().collect();
+
+        idle_since.retain(|guild_id, _| calls.iter().any(|(id, _)| id.0.get() == guild_id.get()));
+
+        for (id, call) in calls {
+
+            let guild_id = GuildId::new(id.0.get());
+
+            if !call.lock().await.queue().is_empty() {
+                idle_since.remove(&guild_id);
+                continue;
+            }
+
+            let since = *idle_since.entry(guild_id).or_insert_with(Instant::now);
+
+            let guild = settings.get(guild_id).await;
+
+            let Some(timeout) = guild.idle_timeout.map(Duration::from_secs) else {
+                continue;
+            };
+
+            if since.elapsed() < timeout {
+                continue;
+            }
+
+            idle_since.remove(&guild_id);
+
+            if let Err(e) = manager.remove(guild_id).await {
+                error!("Failed to leave the idle channel of guild {}: {:?}", guild_id, e);
+                continue;
+            }
+
+            info!("Left the voice channel of guild {} after {:?} without music", guild_id, timeout);
+
+            if let Some(channel_id) = guild.announce_channel {
+                Bot::announce(&ctx, channel_id, &format!("Sir, I left the voice channel after {} seconds without music", timeout.as_secs())).await;
+            }
+        }
+    }
+}
@@ -0,0 +1,453 @@
+use crate::config::QQMusicConfig;
+use crate::credential::Credential;
+use crate::error::*;
+use crate::ratelimit::RateLimiter;
+use crate::sign::sign;
+use crate::structs::*;
+
+use reqwest::header::{HeaderMap, HeaderValue, CONTENT_TYPE};
+use reqwest::{Client, StatusCode};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+
+use std::marker::PhantomData;
+use std::time::{SystemTime, UNIX_EPOCH};
+use log::{warn, error, debug};
+
+
+pub const QQMUSIC_API_URL: &str = "https://u.y.qq.com";
+
+pub const MUSICU_PATH: &str = "/cgi-bin/musicu.fcg";
+
+// Same API, but the body must come with a sign, see sign.rs
+pub const MUSICS_PATH: &str = "/cgi-bin/musics.fcg";
+
+
+// One module/method of musicu.fcg, the struct itself is the `param` object
+
+pub trait MusicuRequest: Serialize {
+
+    const MODULE: &'static str;
+    const METHOD: &'static str;
+
+    // Modules which refuse unsigned calls
+    const SIGNED: bool = false;
+
+    type Response: DeserializeOwned;
+}
+
+
+// Handle to take the typed answer of one request out of a batch
+
+pub struct MusicuKey<R> {
+
+    name: String,
+    _request: PhantomData<R>,
+}
+
+
+// Several requests sent in a single musicu.fcg call as req_1, req_2, ...
+
+pub struct MusicuBatch {
+
+    comm: Value,
+    requests: Map<String, Value>,
+    signed: bool,
+}
+
+impl MusicuBatch {
+
+    pub fn new(comm: Value) -> Self {
+
+        MusicuBatch { comm, requests: Map::new(), signed: false }
+    }
+
+    pub fn push<R: MusicuRequest>(&mut self, request: &R) -> Result<MusicuKey<R>, QQMusicError> {
+
+        let param = serde_json::to_value(request).map_err(QQMusicError::QQMusicParseError)?;
+
+        let name = format!("req_{}", self.requests.len() + 1);
+
+        self.requests.insert(name.clone(), json!({
+            "module": R::MODULE,
+            "method": R::METHOD,
+            "param": param,
+        }));
+
+        // One signed module is enough to send the whole batch to musics.fcg
+        self.signed |= R::SIGNED;
+
+        Ok(MusicuKey { name, _request: PhantomData })
+    }
+
+    pub fn len(&self) -> usize {
+
+        self.requests.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+
+        self.requests.is_empty()
+    }
+
+    pub fn is_signed(&self) -> bool {
+
+        self.signed
+    }
+
+    pub fn payload(&self) -> Value {
+
+        let mut payload = self.requests.clone();
+        payload.insert("comm".to_string(), self.comm.clone());
+
+        Value::Object(payload)
+    }
+
+
+    // Sent to `config.api_url`, transient failures are retried with backoff. Every attempt
+    // waits for a token of `limiter` when there is one
+
+    pub async fn send(self, client: &Client, config: &QQMusicConfig, limiter: Option<&RateLimiter>) -> Result<MusicuResponse, QQMusicError> {
+
+        self.send_with(client, config, limiter, HeaderMap::new()).await
+    }
+
+    pub async fn send_with(self, client: &Client, config: &QQMusicConfig, limiter: Option<&RateLimiter>, headers: HeaderMap) -> Result<MusicuResponse, QQMusicError> {
+
+        // The sign covers the exact bytes sent, so the body is serialized once here
+        let body = serde_json::to_string(&self.payload()).map_err(QQMusicError::QQMusicParseError)?;
+
+        let mut attempt = 0;
+
+        loop {
+
+            if let Some(limiter) = limiter {
+                limiter.acquire().await;
+            }
+
+            match self.send_once(client, &config.api_url, &headers, &body).await {
+
+                Err(e) if e.is_transient() && attempt < config.retry.max_retries => {
+
+                    let delay = config.retry.delay(attempt);
+                    attempt += 1;
+
+                    warn!("Musicu: {}, retrying in {:?} ({}/{})", error_chain(&e), delay, attempt, config.retry.max_retries);
+
+                    tokio::time::sleep(delay).await;
+                }
+
+                result => return result,
+            }
+        }
+    }
+
+    async fn send_once(&self, client: &Client, api_url: &str, headers: &HeaderMap, body: &str) -> Result<MusicuResponse, QQMusicError> {
+
+        let request = if self.signed {
+
+            let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis()).unwrap_or(0);
+
+            client.post(format!("{}{}", api_url, MUSICS_PATH)).query(&[("_", timestamp.to_string()), ("sign", sign(body))])
+        } else {
+
+            client.post(format!("{}{}", api_url, MUSICU_PATH))
+        };
+
+        let res = request
+            .headers(headers.clone())
+            .header(CONTENT_TYPE, HeaderValue::from_static("application/json"))
+            .body(body.to_string())
+            .send()
+            .await
+            .map_err(|e| {
+                error!("Musicu: Request to musicu.fcg failed: {:?}", e);
+                QQMusicError::QQMusicRequestError(e)
+            })?;
+
+        if res.status() == StatusCode::TOO_MANY_REQUESTS {
+
+            error!("Musicu: Rate limited by musicu.fcg");
+            return Err(QQMusicError::QQMusicRateLimitedError);
+        }
+
+        if !res.status().is_success() {
+
+            error!("Musicu: musicu.fcg answered with HTTP {}", res.status());
+            return Err(QQMusicError::QQMusicHttpStatusError { status: res.status() });
+        }
+
+        let body: Value = res.json().await.map_err(|e| {
+            error!("Musicu: Failed to read the musicu.fcg response: {:?}", e);
+            QQMusicError::QQMusicRequestError(e)
+        })?;
+
+        MusicuResponse::parse(body)
+    }
+}
+
+
+// Answer of a batch, each request is taken out with its key
+
+#[derive(Debug)]
+pub struct MusicuResponse {
+
+    body: Value,
+}
+
+impl MusicuResponse {
+
+    // A non-zero code on the envelope fails the whole batch
+
+    pub fn parse(body: Value) -> Result<Self, QQMusicError> {
+
+        match body["code"].as_i64().unwrap_or(0) {
+
+            0 => Ok(MusicuResponse { body }),
+
+            code => {
+
+                error!("Musicu: musicu.fcg answered with code {}", code);
+                Err(QQMusicError::QQMusicApiError { code })
+            }
+        }
+    }
+
+    pub fn take<R: MusicuRequest>(&mut self, key: MusicuKey<R>) -> Result<R::Response, QQMusicError> {
+
+        let mut answer = self.body[&key.name].take();
+
+        let code = answer["code"].as_i64().unwrap_or(0);
+
+        if code != 0 {
+
+            error!("Musicu: {}.{} answered with code {}", R::MODULE, R::METHOD, code);
+            return Err(QQMusicError::QQMusicApiError { code });
+        }
+
+        debug!("Musicu: {}.{} answered", R::MODULE, R::METHOD);
+
+        serde_json::from_value(answer["data"].take()).map_err(|e| {
+            error!("Musicu: Failed to parse the answer of {}.{}: {:?}", R::MODULE, R::METHOD, e);
+            QQMusicError::QQMusicParseError(e)
+        })
+    }
+}
+
+
+// Send one request on its own
+
+pub async fn musicu_call<R: MusicuRequest>(client: &Client, config: &QQMusicConfig, limiter: Option<&RateLimiter>, comm: Value, request: &R) -> Result<R::Response, QQMusicError> {
+
+    let mut batch = MusicuBatch::new(comm);
+    let key = batch.push(request)?;
+
+    batch.send(client, config, limiter).await?.take(key)
+}
+
+
+// Play urls of songs, `sip` + `purl` is the url
+
+#[derive(Debug, Serialize)]
+pub struct GetVkey {
+
+    pub guid: String,
+    pub songmid: Vec<String>,
+    pub songtype: Vec<u8>,
+    pub uin: String,
+    pub loginflag: u8,
+    pub platform: String,
+    // One per songmid to ask for a quality, left out for the default one
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub filename: Vec<String>,
+}
+
+impl MusicuRequest for GetVkey {
+
+    const MODULE: &'static str = "vkey.GetVkeyServer";
+    const METHOD: &'static str = "CgiGetVkey";
+
+    type Response = VkeyData;
+}
+
+
+#[derive(Debug, Serialize)]
+pub struct SearchSong {
+
+    pub query: String,
+    pub num_per_page: u32,
+    pub page_num: u32,
+    pub search_type: i32,
+    pub remoteplace: String,
+}
+
+impl MusicuRequest for SearchSong {
+
+    const MODULE: &'static str = "music.search.SearchCgiService";
+    const METHOD: &'static str = "DoSearchForQQMusicDesktop";
+    const SIGNED: bool = true;
+
+    type Response = SearchData;
+}
+
+
+#[derive(Debug, Serialize)]
+pub struct GetDissInfo {
+
+    pub disstid: u64,
+    pub userinfo: u8,
+    pub tag: u8,
+    pub orderlist: u8,
+    pub song_begin: usize,
+    pub song_num: usize,
+    pub onlysonglist: u8,
+    pub enc_host_uin: String,
+}
+
+impl MusicuRequest for GetDissInfo {
+
+    const MODULE: &'static str = "music.srfDissInfo.aiDissInfo";
+    const METHOD: &'static str = "uniform_GetDissinfo";
+
+    type Response = DissInfoData;
+}
+
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GetAlbumSongList {
+
+    pub album_mid: String,
+
+    #[serde(rename = "albumID")]
+    pub album_id: u64,
+
+    pub begin: usize,
+    pub num: usize,
+    pub order: u8,
+}
+
+impl MusicuRequest for GetAlbumSongList {
+
+    const MODULE: &'static str = "music.musichallAlbum.AlbumSongList";
+    const METHOD: &'static str = "GetAlbumSongList";
+
+    type Response = AlbumSongListData;
+}
+
+
+// Name, singers and length of one song
+
+#[derive(Debug, Serialize)]
+pub struct GetSongDetail {
+
+    pub song_mid: String,
+    pub song_type: u8,
+}
+
+impl MusicuRequest for GetSongDetail {
+
+    const MODULE: &'static str = "music.pf_song_detail_svr";
+    const METHOD: &'static str = "get_song_detail_yqq";
+
+    type Response = SongDetailData;
+}
+
+
+// Exchange the refresh key of a credential for a new musickey
+
+#[derive(Debug, Serialize)]
+pub struct RefreshLogin {
+
+    pub openid: String,
+    pub access_token: String,
+    pub refresh_token: String,
+    pub expired_in: u64,
+    pub musicid: u64,
+    pub musickey: String,
+    pub refresh_key: String,
+
+    #[serde(rename = "loginMode")]
+    pub login_mode: u8,
+}
+
+impl MusicuRequest for RefreshLogin {
+
+    const MODULE: &'static str = "music.login.LoginServer";
+    const METHOD: &'static str = "Login";
+
+    type Response = Credential;
+}
+
+
+// Exchange a QQ Connect authorization code for a credential
+
+#[derive(Debug, Serialize)]
+pub struct QQConnectLogin {
+
+    pub code: String,
+}
+
+impl MusicuRequest for QQConnectLogin {
+
+    const MODULE: &'static str = "QQConnectLogin.LoginServer";
+    const METHOD: &'static str = "QQLogin";
+
+    type Response = Credential;
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_batch() {
+
+        let mut batch = MusicuBatch::new(json!({"ct": 24, "cv": 0}));
+
+        let first = batch.push(&QQConnectLogin { code: "abc".to_string() }).unwrap();
+        let second = batch.push(&GetAlbumSongList { album_mid: "002fRO0N4FftzY".to_string(), album_id: 0, begin: 0, num: 10, order: 2 }).unwrap();
+
+        let payload = batch.payload();
+
+        assert_eq!(batch.len(), 2);
+        assert!(!batch.is_signed());
+        assert_eq!(payload["comm"]["ct"], 24);
+        assert_eq!(payload["req_1"]["module"], "QQConnectLogin.LoginServer");
+        assert_eq!(payload["req_1"]["param"]["code"], "abc");
+        assert_eq!(payload["req_2"]["method"], "GetAlbumSongList");
+        assert_eq!(payload["req_2"]["param"]["albumMid"], "002fRO0N4FftzY");
+        assert_eq!(payload["req_2"]["param"]["albumID"], 0);
+
+        let body = json!({
+            "code": 0,
+            "req_1": {"code": 1000, "data": {}},
+            "req_2": {"code": 0, "data": {"songList": [{"songInfo": {"mid": "001"}}]}},
+        });
+
+        let mut response = MusicuResponse::parse(body).unwrap();
+
+        assert!(matches!(response.take(first), Err(QQMusicError::QQMusicApiError { code: 1000 })));
+        assert_eq!(response.take(second).unwrap().song_list.len(), 1);
+
+        assert!(matches!(MusicuResponse::parse(json!({"code": 500001})), Err(QQMusicError::QQMusicApiError { code: 500001 })));
+    }
+
+
+    #[test]
+    fn test_signed_batch() {
+
+        let mut batch = MusicuBatch::new(json!({"ct": 24, "cv": 0}));
+
+        batch.push(&GetDissInfo { disstid: 1, userinfo: 1, tag: 1, orderlist: 1, song_begin: 0, song_num: 10, onlysonglist: 1, enc_host_uin: String::new() }).unwrap();
+
+        assert!(!batch.is_signed());
+
+        batch.push(&SearchSong { query: "晴天".to_string(), num_per_page: 10, page_num: 1, search_type: 0, remoteplace: "txt.yqq.top".to_string() }).unwrap();
+
+        assert!(batch.is_signed());
+    }
+}
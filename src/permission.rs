@@ -0,0 +1,116 @@
+use crate::error::BotError;
+use crate::structs::BotCommand;
+
+
+// What the author of a command needs before it runs. Manage Server passes every one of them
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Requirement {
+    Everyone,
+    // The guild's dj_role, or anyone when the guild has none
+    Dj,
+    // A DJ, or whoever asked for the song that is playing
+    DjOrRequester,
+    ManageGuild,
+}
+
+// Who the author of a command is in the guild
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Author {
+
+    // Manage Server, or Administrator which includes it
+    pub admin: bool,
+    pub dj: bool,
+    // Asked for the song that is playing
+    pub requester: bool,
+}
+
+impl Requirement {
+
+    // /login and /accounts are for the bot owner, checked before they are sent
+
+    pub fn of(command: &BotCommand) -> Requirement {
+
+        match command {
+            BotCommand::Skip { .. } => Requirement::DjOrRequester,
+
+            BotCommand::Cancel { .. }
+            | BotCommand::Crossfade { .. }
+            | BotCommand::Filter { .. }
+            | BotCommand::Loop { .. }
+            | BotCommand::Volume { .. } => Requirement::Dj,
+
+            BotCommand::Settings { .. }
+            | BotCommand::Blocklist { .. } => Requirement::ManageGuild,
+
+            BotCommand::VoteSkip { .. }
+            | BotCommand::Search { .. }
+            | BotCommand::Play { .. }
+            | BotCommand::Login { .. }
+            | BotCommand::Accounts { .. }
+            | BotCommand::Playlist { .. }
+            | BotCommand::Album { .. } => Requirement::Everyone,
+        }
+    }
+
+
+    pub fn allows(self, author: &Author) -> bool {
+
+        author.admin || match self {
+            Requirement::Everyone => true,
+            Requirement::Dj => author.dj,
+            Requirement::DjOrRequester => author.dj || author.requester,
+            Requirement::ManageGuild => false,
+        }
+    }
+
+
+    // The reply to an author who doesn't meet it, naming what is missing
+
+    pub fn denial(self) -> BotError {
+
+        match self {
+            Requirement::DjOrRequester => BotError::BotDjOrRequesterRequiredError,
+            Requirement::ManageGuild => BotError::BotManageGuildRequiredError,
+            Requirement::Everyone | Requirement::Dj => BotError::BotDjRequiredError,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_requirement() {
+
+        let nobody = Author::default();
+        let dj = Author { dj: true, ..Author::default() };
+        let requester = Author { requester: true, ..Author::default() };
+        let admin = Author { admin: true, ..Author::default() };
+
+        assert!(Requirement::Everyone.allows(&nobody));
+
+        assert!(!Requirement::Dj.allows(&nobody));
+        assert!(!Requirement::Dj.allows(&requester));
+        assert!(Requirement::Dj.allows(&dj));
+
+        assert!(!Requirement::DjOrRequester.allows(&nobody));
+        assert!(Requirement::DjOrRequester.allows(&requester));
+        assert!(Requirement::DjOrRequester.allows(&dj));
+
+        assert!(!Requirement::ManageGuild.allows(&dj));
+
+        // An admin overrides every requirement
+        for requirement in [Requirement::Dj, Requirement::DjOrRequester, Requirement::ManageGuild] {
+            assert!(requirement.allows(&admin));
+        }
+
+        assert!(matches!(Requirement::Dj.denial(), BotError::BotDjRequiredError));
+        assert!(matches!(Requirement::DjOrRequester.denial(), BotError::BotDjOrRequesterRequiredError));
+        assert!(matches!(Requirement::ManageGuild.denial(), BotError::BotManageGuildRequiredError));
+    }
+}
@@ -0,0 +1,674 @@
+use crate::filter::{filtered, Filter, SharedFilter};
+use crate::structs::AudioTrack;
+
+use serde::{Deserialize, Serialize};
+use serenity::async_trait;
+use serenity::model::id::{GuildId, UserId};
+use songbird::events::{Event, EventContext, EventData, EventHandler};
+use songbird::tracks::{LoopState, Queued, Track, TrackHandle, TrackQueue};
+
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use log::debug;
+
+
+// Longest crossfade a guild may ask for
+pub const MAX_CROSSFADE: Duration = Duration::from_secs(12);
+
+// A skipped song fades out this fast, or faster when the crossfade is shorter
+pub const SKIP_FADE: Duration = Duration::from_secs(1);
+
+// How often the volume of a fading song is updated
+const FADE_STEP: Duration = Duration::from_millis(50);
+
+// How often a song checks whether the next one should be opened
+const PRELOAD_STEP: Duration = Duration::from_millis(500);
+
+// Loudest a guild may turn the music, twice the original
+pub const MAX_VOLUME: f32 = 2.0;
+
+
+// What happens when a song ends
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum LoopMode {
+    #[default]
+    Off,
+    // Every song repeats until it is skipped
+    Song,
+}
+
+impl LoopMode {
+
+    // "song" or "off"
+
+    pub fn parse(args: &str) -> Option<LoopMode> {
+
+        match args.trim().to_lowercase().as_str() {
+            "song" | "on" => Some(LoopMode::Song),
+            "off" => Some(LoopMode::Off),
+            _ => None,
+        }
+    }
+}
+
+
+// How a guild wants its music played
+
+#[derive(Debug, Clone)]
+pub struct PlayerSettings {
+
+    pub crossfade: Duration,
+    pub filter: SharedFilter,
+    pub loop_mode: LoopMode,
+    pub volume: f32,
+}
+
+impl Default for PlayerSettings {
+
+    fn default() -> Self {
+
+        PlayerSettings {
+            crossfade: Duration::ZERO,
+            filter: SharedFilter::default(),
+            loop_mode: LoopMode::Off,
+            volume: 1.0,
+        }
+    }
+}
+
+
+#[derive(Default)]
+pub struct Players {
+
+    guilds: RwLock<HashMap<GuildId, PlayerSettings>>,
+}
+
+impl Players {
+
+    // Kept even when untouched, the queued songs have to share the filter changed later
+
+    pub fn get(&self, guild_id: GuildId) -> PlayerSettings {
+
+        self.get_or_insert_with(guild_id, PlayerSettings::default)
+    }
+
+    // The first use of a guild starts from `init`, e.g. its default volume
+
+    pub fn get_or_insert_with(&self, guild_id: GuildId, init: impl FnOnce() -> PlayerSettings) -> PlayerSettings {
+
+        if let Some(settings) = self.guilds.read().unwrap().get(&guild_id) {
+            return settings.clone();
+        }
+
+        self.guilds.write().unwrap().entry(guild_id).or_insert_with(init).clone()
+    }
+
+    pub fn set_crossfade(&self, guild_id: GuildId, crossfade: Duration) -> Duration {
+
+        let crossfade = crossfade.min(MAX_CROSSFADE);
+
+        self.guilds.write().unwrap().entry(guild_id).or_default().crossfade = crossfade;
+
+        crossfade
+    }
+
+    pub fn set_filter(&self, guild_id: GuildId, filter: Filter) {
+
+        *self.get(guild_id).filter.write().unwrap() = filter;
+    }
+
+    pub fn set_loop(&self, guild_id: GuildId, loop_mode: LoopMode) {
+
+        self.guilds.write().unwrap().entry(guild_id).or_default().loop_mode = loop_mode;
+    }
+
+    pub fn set_volume(&self, guild_id: GuildId, volume: f32) -> f32 {
+
+        let volume = volume.clamp(0.0, MAX_VOLUME);
+
+        self.guilds.write().unwrap().entry(guild_id).or_default().volume = volume;
+
+        volume
+    }
+}
+
+
+// Kept with every queued song, read back through `TrackHandle::data`
+
+#[derive(Debug)]
+pub struct TrackData {
+
+    pub songmid: String,
+    pub requester: Option<UserId>,
+    pub duration: Option<Duration>,
+
+    // Where in the song playback started, songbird counts the position from there
+    pub start: Duration,
+
+    // Crossfade in effect when the song was queued, zero if it has no fader
+    pub crossfade: Duration,
+
+    // Guild volume, the fader scales its fades by it
+    volume: Mutex<f32>,
+
+    // Position where a skip started fading the song out, and how long that takes
+    skip_fade: Mutex<Option<(Duration, Duration)>>,
+
+    // Who voted to skip the song, a new song starts with none
+    skip_votes: Mutex<HashSet<UserId>>,
+
+    // Set once the fader has started the next song, which then plays alongside this one
+    crossfading: AtomicBool,
+
+    // Where the song is in its own time, shared by the fader, the preloader and the queue saver
+    pub clock: SongClock,
+}
+
+impl TrackData {
+
+    pub fn volume(&self) -> f32 {
+
+        *self.volume.lock().unwrap()
+    }
+
+    pub fn start_skip_fade(&self, position: Duration, length: Duration) {
+
+        *self.skip_fade.lock().unwrap() = Some((position, length));
+    }
+
+    fn skip_fade(&self) -> Option<(Duration, Duration)> {
+
+        *self.skip_fade.lock().unwrap()
+    }
+
+    pub fn is_crossfading(&self) -> bool {
+
+        self.crossfading.load(Ordering::Relaxed)
+    }
+
+    // Counts the vote of `user_id` and returns the votes of those still listening, the
+    // votes of anyone who left the channel are dropped
+
+    pub fn vote_skip(&self, user_id: UserId, listeners: &[UserId]) -> usize {
+
+        let mut votes = self.skip_votes.lock().unwrap();
+
+        votes.insert(user_id);
+        votes.retain(|voter| listeners.contains(voter));
+
+        votes.len()
+    }
+}
+
+
+// Positions of the waiting songs taken in turns by requester, each requester's songs in the
+// order they were queued. The requester of the playing song, `previous`, has the last turn
+
+pub fn fair_order(requesters: &[Option<UserId>], previous: Option<UserId>) -> Vec<usize> {
+
+    // Requesters by their first waiting song
+    let mut turns: Vec<(Option<UserId>, Vec<usize>)> = vec![];
+
+    for (index, requester) in requesters.iter().enumerate() {
+
+        match turns.iter_mut().find(|(turn, _)| turn == requester) {
+            Some((_, indexes)) => indexes.push(index),
+            None => turns.push((*requester, vec![index])),
+        }
+    }
+
+    if let Some(position) = turns.iter().position(|(turn, _)| *turn == previous) {
+        let turn = turns.remove(position);
+        turns.push(turn);
+    }
+
+    let rounds = turns.iter().map(|(_, indexes)| indexes.len()).max().unwrap_or(0);
+
+    (0..rounds)
+        .flat_map(|round| turns.iter().filter_map(move |(_, indexes)| indexes.get(round).copied()))
+        .collect()
+}
+
+
+// Put the waiting songs of the queue in fair_order. The playing one stays where it is, and so
+// does the next one once a crossfade has started it
+
+pub fn share_fairly(queue: &TrackQueue) {
+
+    queue.modify_queue(|queued| {
+
+        let Some(head) = queued.front().map(|first| first.data::<TrackData>()) else {
+            return;
+        };
+
+        let pinned = if head.is_crossfading() { 2 } else { 1 };
+
+        if queued.len() < pinned + 2 {
+            return;
+        }
+
+        let previous = queued[pinned - 1].data::<TrackData>().requester;
+
+        let mut waiting: Vec<Option<Queued>> = queued.drain(pinned..).map(Some).collect();
+
+        let requesters: Vec<Option<UserId>> = waiting.iter()
+            .map(|track| track.as_ref().and_then(|track| track.data::<TrackData>().requester))
+            .collect();
+
+        for index in fair_order(&requesters, previous) {
+            queued.extend(waiting[index].take());
+        }
+    });
+}
+
+
+// A songbird track for the song, played through the guild's filter. With a crossfade it starts
+// silent, fades in, fades out over its last seconds and starts the next song in the queue while
+// it does. With a `preload` the next song is opened that long before this one ends
+
+pub fn queue_track(audio: AudioTrack, settings: &PlayerSettings, queue: &TrackQueue, preload: Duration) -> Track {
+
+    let crossfade = settings.crossfade;
+
+    // What is left to play, songbird's positions don't include the start
+    let duration = audio.duration.map(|duration| duration.saturating_sub(audio.start));
+
+    let data = TrackData {
+        songmid: audio.songmid,
+        requester: audio.requester,
+        duration: audio.duration,
+        start: audio.start,
+        crossfade,
+        volume: Mutex::new(settings.volume),
+        skip_fade: Mutex::new(None),
+        skip_votes: Mutex::new(HashSet::new()),
+        crossfading: AtomicBool::new(false),
+        clock: SongClock::new(&settings.filter),
+    };
+
+    let mut track = Track::new_with_data(filtered(audio.source, &settings.filter, audio.start), Arc::new(data));
+
+    if settings.loop_mode == LoopMode::Song {
+        track = track.loops(LoopState::Infinite);
+    }
+
+    // Checked on every step rather than set once, a speed filter changes when the song ends
+    if let Some(duration) = duration.filter(|_| !preload.is_zero()) {
+
+        let preloader = Preloader { duration, preload, queue: queue.clone() };
+        track.events.add_event(EventData::new(Event::Periodic(PRELOAD_STEP, None), preloader), Duration::ZERO);
+    }
+
+    if crossfade.is_zero() {
+        return track.volume(settings.volume);
+    }
+
+    track = track.volume(0.0);
+
+    // The fader also starts the next song, on the step where this one enters its last
+    // crossfade. A Delayed event would only fire on the first play of a looping song
+    let fader = Fader { duration, crossfade, queue: queue.clone() };
+    track.events.add_event(EventData::new(Event::Periodic(FADE_STEP, None), fader), Duration::ZERO);
+
+    track
+}
+
+
+// Bring the songs already queued in line with changed loop or volume settings
+
+pub fn apply_settings(queue: &TrackQueue, settings: &PlayerSettings) {
+
+    for handle in queue.current_queue() {
+
+        let data = handle.data::<TrackData>();
+
+        *data.volume.lock().unwrap() = settings.volume;
+
+        // A fading song picks the volume up on its next step
+        if data.crossfade.is_zero() {
+            let _ = handle.set_volume(settings.volume);
+        }
+
+        let _ = match settings.loop_mode {
+            LoopMode::Song => handle.enable_loop(),
+            LoopMode::Off => handle.disable_loop(),
+        };
+    }
+}
+
+
+// Volume of a fading song. It fades in over its first play, out over the end of its last
+// loop, and out after a skip
+
+pub fn fade_volume(
+    play_time: Duration,
+    position: Duration,
+    duration: Option<Duration>,
+    last_loop: bool,
+    crossfade: Duration,
+    skip_fade: Option<(Duration, Duration)>,
+) -> f32 {
+
+    let ratio = |elapsed: Duration, length: Duration| {
+        if length.is_zero() { 1.0 } else { (elapsed.as_secs_f32() / length.as_secs_f32()).clamp(0.0, 1.0) }
+    };
+
+    let mut volume = ratio(play_time, crossfade);
+
+    if let Some(duration) = duration.filter(|_| last_loop) {
+        volume = volume.min(ratio(duration.saturating_sub(position), crossfade));
+    }
+
+    if let Some((from, length)) = skip_fade {
+        volume = volume.min(1.0 - ratio(position.saturating_sub(from), length));
+    }
+
+    volume
+}
+
+
+// Whether a song at `position` has reached the crossfade into the next one, never for a song
+// no longer than the crossfade
+
+pub fn in_last_crossfade(position: Duration, duration: Option<Duration>, crossfade: Duration) -> bool {
+
+    duration.is_some_and(|duration| duration > crossfade && duration.saturating_sub(position) <= crossfade)
+}
+
+
+// Where a song is in its own time. songbird counts the time it has played, which a speed
+// filter stretches or squeezes, so the clock adds up what each step played at the speed of then
+
+#[derive(Debug)]
+pub struct SongClock {
+
+    filter: SharedFilter,
+    // songbird's last position and the song's
+    positions: Mutex<(Duration, Duration)>,
+}
+
+impl SongClock {
+
+    pub fn new(filter: &SharedFilter) -> Self {
+
+        SongClock { filter: Arc::clone(filter), positions: Mutex::new((Duration::ZERO, Duration::ZERO)) }
+    }
+
+    // The song's own position at songbird's `position`. A position going back is a loop or a seek
+
+    pub fn position(&self, position: Duration) -> Duration {
+
+        let speed = f64::from(self.filter.read().unwrap().speed());
+        let mut positions = self.positions.lock().unwrap();
+
+        let (played, song) = *positions;
+
+        let song = match position.checked_sub(played) {
+            Some(step) => song + step.mul_f64(speed),
+            None => position.mul_f64(speed),
+        };
+
+        *positions = (position, song);
+
+        song
+    }
+
+    // How long until a song of `duration` ends, as played at the speed it has now
+
+    pub fn remaining(&self, position: Duration, duration: Duration) -> Duration {
+
+        let song = self.position(position);
+
+        duration.saturating_sub(song).div_f64(f64::from(self.filter.read().unwrap().speed()))
+    }
+}
+
+
+struct Preloader {
+
+    duration: Duration,
+    preload: Duration,
+    queue: TrackQueue,
+}
+
+#[async_trait]
+impl EventHandler for Preloader {
+
+    async fn act(&self, ctx: &EventContext<'_>) -> Option<Event> {
+
+        let EventContext::Track(tracks) = ctx else {
+            return None;
+        };
+
+        let (state, handle) = tracks.first()?;
+
+        if handle.data::<TrackData>().clock.remaining(state.position, self.duration) > self.preload {
+            return None;
+        }
+
+        let queue = self.queue.current_queue();
+
+        // Opening a song that is already open does nothing, the next steps may ask again
+        if queue.first()?.uuid() == handle.uuid() {
+            let _ = queue.get(1)?.make_playable();
+        }
+
+        None
+    }
+}
+
+
+struct Fader {
+
+    duration: Option<Duration>,
+    crossfade: Duration,
+    queue: TrackQueue,
+}
+
+impl Fader {
+
+    // Only the song at the head of the queue hands over, a reordered queue decides again.
+    // Done under the queue's lock so share_fairly can't move the next song in between
+
+    fn start_next(&self, handle: &TrackHandle) {
+
+        self.queue.modify_queue(|queued| {
+
+            if queued.front().map(|first| first.uuid()) != Some(handle.uuid()) {
+                return;
+            }
+
+            handle.data::<TrackData>().crossfading.store(true, Ordering::Relaxed);
+
+            if let Some(next) = queued.get(1) {
+
+                debug!("Player: Crossfading into track {}", next.uuid());
+                let _ = next.play();
+            }
+        });
+    }
+}
+
+#[async_trait]
+impl EventHandler for Fader {
+
+    async fn act(&self, ctx: &EventContext<'_>) -> Option<Event> {
+
+        let EventContext::Track(tracks) = ctx else {
+            return None;
+        };
+
+        for (state, handle) in tracks.iter() {
+
+            let data = handle.data::<TrackData>();
+
+            let skip_fade = data.skip_fade();
+            let last_loop = matches!(state.loops, LoopState::Finite(0));
+
+            // When the song will end in songbird's time, at the speed it plays now
+            let duration = self.duration.map(|duration| state.position + data.clock.remaining(state.position, duration));
+
+            let volume = fade_volume(state.play_time, state.position, duration, last_loop, self.crossfade, skip_fade);
+
+            let _ = handle.set_volume(volume * data.volume());
+
+            // Without a length the song can't start the next one early, it still fades in.
+            // A looping song plays again instead of handing over
+            if in_last_crossfade(state.position, duration, self.crossfade) && last_loop && skip_fade.is_none() && !data.is_crossfading() {
+                self.start_next(handle);
+            }
+
+            // Faded out by a skip, ending it moves the queue on
+            if skip_fade.is_some() && volume <= 0.0 {
+
+                debug!("Player: Skipped track {} faded out", handle.uuid());
+                let _ = handle.stop();
+            }
+        }
+
+        None
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_fade_volume() {
+
+        let secs = Duration::from_secs;
+        let song = Some(secs(200));
+
+        // Fades in over the first 4 seconds, full in the middle, out over the last 4
+        assert_eq!(fade_volume(secs(0), secs(0), song, true, secs(4), None), 0.0);
+        assert_eq!(fade_volume(secs(2), secs(2), song, true, secs(4), None), 0.5);
+        assert_eq!(fade_volume(secs(100), secs(100), song, true, secs(4), None), 1.0);
+        assert_eq!(fade_volume(secs(199), secs(199), song, true, secs(4), None), 0.25);
+
+        // A looping song doesn't fade out, and only fades in the first time
+        assert_eq!(fade_volume(secs(397), secs(197), song, false, secs(4), None), 1.0);
+        assert_eq!(fade_volume(secs(201), secs(1), song, true, secs(4), None), 1.0);
+
+        // A skip fades out from where it happened
+        assert_eq!(fade_volume(secs(100), secs(100), song, true, secs(4), Some((secs(100), secs(1)))), 1.0);
+        assert_eq!(fade_volume(secs(101), secs(101), song, true, secs(4), Some((secs(100), secs(1)))), 0.0);
+    }
+
+
+    #[test]
+    fn test_in_last_crossfade() {
+
+        let secs = Duration::from_secs;
+
+        assert!(!in_last_crossfade(secs(195), Some(secs(200)), secs(4)));
+        assert!(in_last_crossfade(secs(196), Some(secs(200)), secs(4)));
+
+        // Back at the start after a loop, it hands over again at the end of the next play
+        assert!(!in_last_crossfade(secs(0), Some(secs(200)), secs(4)));
+
+        assert!(!in_last_crossfade(secs(3), Some(secs(3)), secs(4)));
+        assert!(!in_last_crossfade(secs(196), None, secs(4)));
+    }
+
+
+    #[test]
+    fn test_song_clock() {
+
+        let secs = Duration::from_secs;
+        let filter: SharedFilter = Arc::default();
+        let clock = SongClock::new(&filter);
+
+        assert_eq!(clock.remaining(secs(100), secs(200)), secs(100));
+
+        // Twice as fast from here, the second half takes half the time
+        *filter.write().unwrap() = Filter::Speed(2.0);
+        assert_eq!(clock.remaining(secs(100), secs(200)), secs(50));
+        assert_eq!(clock.remaining(secs(120), secs(200)), secs(30));
+        assert_eq!(clock.position(secs(120)), secs(140));
+
+        // Looped back to the start
+        assert_eq!(clock.remaining(secs(0), secs(200)), secs(100));
+    }
+
+
+    #[test]
+    fn test_vote_skip() {
+
+        let data = TrackData {
+            songmid: "002GwAma2DGN2x".to_string(),
+            requester: None,
+            duration: None,
+            start: Duration::ZERO,
+            crossfade: Duration::ZERO,
+            volume: Mutex::new(1.0),
+            skip_fade: Mutex::new(None),
+            skip_votes: Mutex::new(HashSet::new()),
+            crossfading: AtomicBool::new(false),
+            clock: SongClock::new(&Arc::default()),
+        };
+
+        let listeners = [UserId::new(1), UserId::new(2), UserId::new(3)];
+
+        assert_eq!(data.vote_skip(UserId::new(1), &listeners), 1);
+
+        // Voting twice counts once
+        assert_eq!(data.vote_skip(UserId::new(1), &listeners), 1);
+        assert_eq!(data.vote_skip(UserId::new(2), &listeners), 2);
+
+        // The second voter left the channel
+        assert_eq!(data.vote_skip(UserId::new(3), &[UserId::new(1), UserId::new(3)]), 2);
+    }
+
+
+    #[test]
+    fn test_fair_order() {
+
+        let (a, b, c) = (Some(UserId::new(1)), Some(UserId::new(2)), Some(UserId::new(3)));
+
+        // A is playing and queued two more, B and C come first
+        assert_eq!(fair_order(&[a, a, b, b, c], a), vec![2, 4, 0, 3, 1]);
+
+        // Already fair, nothing moves
+        assert_eq!(fair_order(&[b, c, a, b, a], a), vec![0, 1, 2, 3, 4]);
+
+        // A newcomer has a turn before the requester of the playing song
+        assert_eq!(fair_order(&[b, c, a, b, a, None, None], a), vec![0, 1, 5, 2, 3, 6, 4]);
+
+        assert_eq!(fair_order(&[b, b], a), vec![0, 1]);
+        assert!(fair_order(&[], a).is_empty());
+    }
+
+
+    #[test]
+    fn test_players() {
+
+        let players = Players::default();
+        let guild_id = GuildId::new(1);
+
+        assert!(players.get(guild_id).crossfade.is_zero());
+        assert_eq!(players.set_crossfade(guild_id, Duration::from_secs(60)), MAX_CROSSFADE);
+        assert_eq!(players.get(guild_id).crossfade, MAX_CROSSFADE);
+        assert!(players.get(GuildId::new(2)).crossfade.is_zero());
+
+        // Songs queued earlier hear the new filter
+        let queued = players.get(guild_id).filter;
+        players.set_filter(guild_id, Filter::Nightcore);
+
+        assert_eq!(*queued.read().unwrap(), Filter::Nightcore);
+
+        assert_eq!(players.get(guild_id).volume, 1.0);
+        assert_eq!(players.set_volume(guild_id, 5.0), MAX_VOLUME);
+        assert_eq!(players.set_volume(guild_id, 0.5), 0.5);
+
+        players.set_loop(guild_id, LoopMode::Song);
+        assert_eq!(players.get(guild_id).loop_mode, LoopMode::Song);
+        assert_eq!(LoopMode::parse("off"), Some(LoopMode::Off));
+        assert_eq!(LoopMode::parse("queue"), None);
+    }
+}
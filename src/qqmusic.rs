@@ -1,165 +1,890 @@
+use crate::account::*;
+use crate::audio_cache::AudioCache;
+use crate::cache::{CacheStats, ResponseCache};
+use crate::config::QQMusicConfig;
+use crate::credential::{data_dir, Credential};
 use crate::error::*;
+use crate::musicu::*;
+use crate::ratelimit::RateLimiter;
+use crate::storage::Storage;
 use crate::structs::*;
 
-use reqwest::header::{HeaderMap, COOKIE, REFERER};
+use reqwest::header::HeaderMap;
 use reqwest::Client;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
 use serde_json::json;
 use serenity::json::Value;
 
 use prettytable::{Table, Row, Cell, row, format};
 
+use std::collections::HashMap;
 use std::env;
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime};
+use tokio::sync::Mutex;
+use tokio::sync::mpsc::Sender;
+use uuid::Uuid;
 use log::{info, warn, error, debug};
 
+
+// musicu.fcg answers with this code when the musickey is no longer accepted
+const LOGIN_EXPIRED_CODE: i64 = 1000;
+
+// A few VIP songs come back without a purl, many in a row means the login is gone
+const EMPTY_PURL_LIMIT: usize = 5;
+
+// Songmids resolved by one CgiGetVkey request, several requests share one musicu.fcg call
+pub const VKEY_BATCH_SIZE: usize = 50;
+
+// Songs taken from one playlist or album
+pub const SONG_LIST_LIMIT: usize = 100;
+
+// Play urls leave the cache this long before their vkey expires
+const VKEY_EXPIRY_MARGIN: u64 = 60;
+
+
 pub struct QQMusic {
 
-    pub client: Client,
+    accounts: RwLock<Vec<Account>>,
+    next_account: AtomicUsize,
+    guid: String,
+    storage: Arc<Storage>,
+    refresh_lock: Mutex<()>,
+    notifier: Option<Sender<String>>,
+    config: QQMusicConfig,
+    downloader: Client,
+    limiter: RateLimiter,
+    cache: Arc<ResponseCache>,
+    audio_cache: Arc<AudioCache>,
 }
 
 impl QQMusic {
 
-    // Intialize the client
+    // Intialize the client, with the accounts and guid kept in `storage`
+
+    pub async fn new(config: QQMusicConfig, storage: Arc<Storage>) -> Result<Self,QQMusicError> {
 
-    pub async fn new() -> Result<Self,QQMusicError> {
+        // Accounts saved by /login, then cookies pasted into COOKIE (several separated by `|`)
 
-        let user_cookie = env::var("COOKIE").unwrap();
-        let base_url = "https://y.qq.com/";
+        let mut accounts = vec![];
 
-        let mut headers = HeaderMap::new();
-        headers.insert(COOKIE, user_cookie.parse().unwrap());
-        headers.insert(REFERER, base_url.parse().unwrap());
+        let credentials = storage.credentials().list().await.unwrap_or_else(|e| {
+            error!("QQmusic: Failed to load the saved credentials: {}", error_chain(&e));
+            vec![]
+        });
 
-        match Client::builder().default_headers(headers).build() {
+        for credential in credentials {
+            accounts.push(Account::new(&config, Some(credential), None)?);
+        }
 
-            Ok(client) => {
+        if let Ok(cookies) = env::var("COOKIE") {
 
-                info!("QQmusic: Success to initialize the client");
-                Ok(   QQMusic {client }  )
-            },
+            // Entries are numbered from 1 as they are written in COOKIE
+            for (entry, cookie) in (1..).zip(cookies.split('|').map(str::trim).filter(|cookie| !cookie.is_empty())) {
 
-            Err(_) => {
+                let credential = Credential::from_cookie(cookie).ok_or_else(|| {
+                    error!("QQmusic: COOKIE entry {} has no uin or p_uin", entry);
+                    QQMusicError::QQMusicCookieUinError { entry }
+                })?;
 
-                error!("QQmusic: Failed to initialize the client");
-                Err(QQMusicError::QQMusicClientError)
+                if accounts.iter().any(|account: &Account| account.musicid() == credential.musicid) {
+                    info!("QQmusic: COOKIE entry {} is account {}, which is already in the pool", entry, credential.musicid);
+                    continue;
+                }
+
+                accounts.push(Account::new(&config, Some(credential), Some(cookie))?);
             }
         }
+
+        if accounts.is_empty() {
+
+            warn!("QQmusic: No credential found, use /login to sign in");
+            accounts.push(Account::new(&config, None, None)?);
+        }
+
+        // Audio is downloaded without the account cookies
+        let downloader = config.client(HeaderMap::new())?;
+
+        let cache_path = config.cache.persist.then(|| data_dir().join("cache.json"));
+
+        let cache = Arc::new(ResponseCache::new(config.cache.max_entries, cache_path));
+
+        ResponseCache::flush_in_background(&cache);
+
+        // The play urls of a new guid work too, the old ones just aren't reused
+        let guid = storage.installation().guid().await.unwrap_or_else(|e| {
+            warn!("QQmusic: Failed to load the guid, using a new one: {}", error_chain(&e));
+            Uuid::new_v4().simple().to_string()
+        });
+
+        info!("QQmusic: Success to initialize the client with {} accounts", accounts.len());
+
+        Ok(QQMusic {
+            accounts: RwLock::new(accounts),
+            next_account: AtomicUsize::new(0),
+            guid,
+            storage,
+            refresh_lock: Mutex::new(()),
+            notifier: None,
+            limiter: RateLimiter::new(config.requests_per_second, config.burst),
+            cache,
+            audio_cache: Arc::new(AudioCache::new(data_dir().join("audio"), config.audio_cache_bytes, config.audio_memory_tracks).await),
+            config,
+            downloader,
+        })
     }
 
 
-    // Get the music record url
+    // Messages for the bot owner, e.g. when the credential can't be refreshed
 
-    pub async fn get_qqmusic_play_url(&self, songmid: &str) -> Result<String,QQMusicError> {
+    pub fn with_notifier(mut self, notifier: Sender<String>) -> Self {
 
-        let url = "https://u.y.qq.com/cgi-bin/musicu.fcg";
-
-        let payload = json!({
-            "req_1": {
-                "module": "vkey.GetVkeyServer",
-                "method": "CgiGetVkey",
-                "param": {
-                    "guid": "10000",
-                    "songmid": [songmid],
-                    "uin": "0",
-                    "platform": "20"
-                }
+        self.notifier = Some(notifier);
+        self
+    }
+
+
+    // Client for the audio files, with the same proxy and timeouts as the API
+
+    pub fn downloader(&self) -> &Client {
+
+        &self.downloader
+    }
+
+    pub fn audio_cache(&self) -> &Arc<AudioCache> {
+
+        &self.audio_cache
+    }
+
+    pub fn config(&self) -> &QQMusicConfig {
+
+        &self.config
+    }
+
+
+    pub fn cache_stats(&self) -> CacheStats {
+
+        self.cache.stats()
+    }
+
+
+    fn cached<T: DeserializeOwned>(&self, key: &str) -> Option<T> {
+
+        self.cache.get(key).and_then(|value| serde_json::from_value(value).ok())
+    }
+
+
+    fn store<T: Serialize>(&self, key: &str, value: &T, ttl: Duration) {
+
+        match serde_json::to_value(value) {
+            Ok(value) => self.cache.insert(key, value, ttl),
+            Err(e) => warn!("QQmusic: Failed to cache {}: {:?}", key, e),
+        }
+    }
+
+
+    // Accounts in the order they should be tried: round-robin, healthy ones first
+
+    fn pick_accounts(&self) -> Vec<Account> {
+
+        let accounts = self.accounts.read().unwrap();
+
+        let start = self.next_account.fetch_add(1, Ordering::Relaxed) % accounts.len();
+
+        let mut picked: Vec<Account> = accounts.iter().cycle().skip(start).take(accounts.len()).cloned().collect();
+
+        picked.sort_by_key(|account| !account.health.is_healthy());
+
+        picked
+    }
+
+
+    fn account(&self, musicid: u64) -> Option<Account> {
+
+        self.accounts.read().unwrap().iter().find(|account| account.musicid() == musicid).cloned()
+    }
+
+
+    fn update_account(&self, musicid: u64, update: impl FnOnce(&mut Account)) {
+
+        if let Some(account) = self.accounts.write().unwrap().iter_mut().find(|account| account.musicid() == musicid) {
+            update(account);
+        }
+    }
+
+
+    // Add the account obtained from a QR code login, or replace it if it's already in the pool
+
+    pub async fn login(&self, credential: Credential) -> Result<(),QQMusicError> {
+
+        let musicid = credential.musicid;
+        let account = Account::new(&self.config, Some(credential.clone()), None)?;
+
+        {
+            let mut accounts = self.accounts.write().unwrap();
+
+            accounts.retain(|account| account.credential.is_some() && account.musicid() != musicid);
+            accounts.push(account);
+        }
+
+        // Only this account's row is written, even without a refresh key the musickey is good
+        // until it expires. Pasted cookies don't come through here, they stay in COOKIE
+        self.storage.credentials().save(&credential).await.map_err(|e| {
+            error!("QQmusic: Failed to save the credential of {}: {}", musicid, error_chain(&e));
+            QQMusicError::QQMusicCredentialError(e)
+        })?;
+
+        info!("QQmusic: Logged in as {}, {} accounts in the pool", musicid, self.accounts.read().unwrap().len());
+
+        Ok(())
+    }
+
+
+    // Exchange the refresh key for a new musickey. `stale_musickey` is the key that was
+    // rejected, if another task already replaced it there is nothing left to do
+
+    pub async fn refresh(&self, musicid: u64, stale_musickey: &str) -> Result<(),QQMusicError> {
+
+        let _guard = self.refresh_lock.lock().await;
+
+        let account = match self.account(musicid) {
+
+            Some(account) => account,
+            None => return Err(QQMusicError::QQMusicRefreshError),
+        };
+
+        let credential = match &account.credential {
+
+            Some(credential) if credential.musickey != stale_musickey => {
+
+                debug!("QQmusic: The credential of {} was already refreshed", musicid);
+                return Ok(());
+            }
+
+            Some(credential) if !credential.refresh_key.is_empty() => credential.clone(),
+
+            _ => {
+
+                warn!("QQmusic: No refresh key, the credential of {} can't be refreshed", musicid);
+                self.refresh_failed(musicid).await;
+                return Err(QQMusicError::QQMusicRefreshError);
             }
+        };
+
+        // Same comm as every other request, the login server also wants the account as "qq"
+        let mut comm = account.comm();
+        comm["qq"] = json!(credential.musicid.to_string());
+        comm["authst"] = json!(credential.musickey);
+        comm["tmeLoginType"] = json!(credential.tme_login_type().to_string());
+
+        let request = RefreshLogin {
+            openid: String::new(),
+            access_token: String::new(),
+            refresh_token: credential.refresh_token.clone(),
+            expired_in: 0,
+            musicid: credential.musicid,
+            musickey: credential.musickey.clone(),
+            refresh_key: credential.refresh_key.clone(),
+            login_mode: 2,
+        };
+
+        let refreshed = match musicu_call(&account.client, &self.config, Some(&self.limiter), comm, &request).await {
+
+            Ok(refreshed) if !refreshed.musickey.is_empty() => Some(refreshed),
+
+            Ok(_) => None,
+
+            Err(e) => {
+
+                error!("QQmusic: Failed to refresh the credential of {}: {}", musicid, error_chain(&e));
+                None
+            }
+        };
+
+        let mut refreshed = match refreshed {
+
+            Some(refreshed) => refreshed,
+
+            None => {
+
+                error!("QQmusic: The login server refused to refresh the credential of {}", musicid);
+                self.refresh_failed(musicid).await;
+                return Err(QQMusicError::QQMusicRefreshError);
+            }
+        };
+
+        // The refresh answer does not always repeat the refresh pair
+
+        if refreshed.refresh_key.is_empty() {
+            refreshed.refresh_key = credential.refresh_key;
+        }
+
+        if refreshed.refresh_token.is_empty() {
+            refreshed.refresh_token = credential.refresh_token;
+        }
+
+        if refreshed.musicid == 0 {
+            refreshed.musicid = credential.musicid;
+        }
+
+        self.login(refreshed).await?;
+
+        info!("QQmusic: Success to refresh the credential of {}", musicid);
+
+        Ok(())
+    }
+
+
+    async fn refresh_failed(&self, musicid: u64) {
+
+        let mut notify = false;
+
+        self.update_account(musicid, |account| {
+            notify = !account.health.refresh_failed;
+            account.health.refresh_failed = true;
         });
 
-        let res = self.client.post(url).json(&payload).send().await.unwrap();
+        // Tell the owner once, not on every song that fails afterwards
 
-        let api_response: ApiResponse = res.json().await.unwrap();
+        if !notify {
+            return;
+        }
 
-        if let Some(sip) = api_response.req_1.data.sip.get(0) {
+        if let Some(notifier) = &self.notifier {
 
-            if let Some(midurlinfo) = api_response.req_1.data.midurlinfo.get(0) {
+            let notice = format!("Sir, the credential of QQ Music account {} has expired and I couldn't refresh it. Please /login again.", musicid);
 
-                if !midurlinfo.purl.is_empty() {
+            if let Err(e) = notifier.send(notice).await {
+                error!("QQmusic: Failed to notify the owner: {:?}", e);
+            }
+        }
+    }
 
-                    let play_url = format!("{}{}", sip, midurlinfo.purl);
 
-                    info!("QQmusic: Success to get music play url");
-                    debug!("{:?}",play_url);
-                    return Ok(play_url);
+    // Get the music record url, falling back to the next account when one can't play the song
+
+    pub async fn get_qqmusic_play_url(&self, songmid: &str) -> Result<String,QQMusicError> {
+
+        let mut play_urls = self.get_qqmusic_play_urls(&[songmid.to_string()]).await;
+
+        play_urls.remove(songmid).unwrap_or(Err(QQMusicError::QQMusicPlayError))
+    }
+
+
+    // Resolve many songs at once in the configured quality
+
+    pub async fn get_qqmusic_play_urls(&self, songmids: &[String]) -> HashMap<String, Result<String,QQMusicError>> {
+
+        self.get_qqmusic_play_urls_in(songmids, self.config.quality).await
+    }
+
+
+    // VKEY_BATCH_SIZE songmids per CgiGetVkey request and all of them in one musicu.fcg call.
+    // Songs an account can't play are handed to the next account
+
+    pub async fn get_qqmusic_play_urls_in(&self, songmids: &[String], quality: Quality) -> HashMap<String, Result<String,QQMusicError>> {
+
+        let mut play_urls = HashMap::new();
+
+        let mut pending: Vec<String> = vec![];
+
+        for songmid in songmids {
+
+            // A vkey stays valid for a while, no need to ask again for the song played a minute ago
+            match self.cached::<String>(&vkey_cache_key(songmid, quality)) {
+                Some(play_url) => { play_urls.insert(songmid.clone(), Ok(play_url)); }
+                None => pending.push(songmid.clone()),
+            }
+        }
+
+        for account in self.pick_accounts() {
+
+            if pending.is_empty() {
+                break;
+            }
+
+            match self.get_play_urls_with(account.musicid(), &pending, quality).await {
+
+                Ok(results) => {
+
+                    pending.clear();
+
+                    for (songmid, result) in results {
+
+                        // Another account may have the VIP or the purchase this one lacks
+                        if result.as_ref().is_err_and(QQMusicError::is_account_specific) {
+                            pending.push(songmid.clone());
+                        }
+
+                        play_urls.insert(songmid, result);
+                    }
                 }
+
+                Err(e) => {
+
+                    for songmid in &pending {
+
+                        let reason = match e {
+                            QQMusicError::QQMusicRateLimitedError => QQMusicError::QQMusicRateLimitedError,
+                            _ => QQMusicError::QQMusicPlayError,
+                        };
+
+                        play_urls.insert(songmid.clone(), Err(reason));
+                    }
+                }
+            }
+
+            if !pending.is_empty() {
+                warn!("QQmusic: Account {} can't play {} songs, trying the next one", account.musicid(), pending.len());
+            }
+        }
+
+        for (songmid, play_url) in &play_urls {
+
+            if let Err(e) = play_url {
+                error!("QQmusic: Failed to get music play url of {}: {}", songmid, e);
             }
         }
 
-        error!("QQmusic: Failed to get music play url");
+        info!("QQmusic: Resolved {}/{} music play urls", play_urls.values().filter(|url| url.is_ok()).count(), songmids.len());
 
-        Err(QQMusicError::QQMusicPlayError)
+        play_urls
     }
 
 
-    // Get search result of song's name
+    // Play url or the reason it can't be played, for every songmid
 
-    pub async fn get_search_list(&self, keyword: &str) -> Result<String,QQMusicError> {
+    async fn get_play_urls_with(&self, musicid: u64, songmids: &[String], quality: Quality) -> Result<HashMap<String, Result<String,QQMusicError>>,QQMusicError> {
 
-        let mut playlist: Vec<MusicPlayList> = vec![];
+        let account = self.account(musicid).ok_or(QQMusicError::QQMusicPlayError)?;
 
-        let url = "https://u.y.qq.com/cgi-bin/musicu.fcg";
-
-        let payload = json!({
-            "comm": {"ct": 24, "cv": 0},
-            "req_1": {
-                "module": "music.search.SearchCgiService",
-                "method": "DoSearchForQQMusicDesktop",
-                "param": {
-                    "query": keyword,
-                    "num_per_page": 10,
-                    "page_num": 1,
-                    "search_type": SearchType::Song as i32,
-                    "remoteplace": "txt.yqq.top",
-                },
-            },
+        let account = match account.musickey() {
+
+            Some(musickey) if account.credential.as_ref().is_some_and(Credential::is_expired) => {
+
+                info!("QQmusic: The credential of {} is about to expire, refreshing", musicid);
+                let _ = self.refresh(musicid, &musickey).await;
+                self.account(musicid).unwrap_or(account)
+            }
+
+            _ => account,
+        };
+
+        let (results, login_expired) = match self.request_play_urls(&account, songmids, quality).await {
+
+            Ok(results) => (results, false),
+
+            Err(QQMusicError::QQMusicApiError { code: LOGIN_EXPIRED_CODE }) => (HashMap::new(), true),
+
+            Err(e) => {
+
+                self.update_account(musicid, Account::record_failure);
+                return Err(e);
+            }
+        };
+
+        let found = results.values().filter(|result| result.is_ok()).count();
+
+        if found > 0 {
+            self.update_account(musicid, Account::record_success);
+        }
+
+        if found == songmids.len() {
+            return Ok(results);
+        }
+
+        let mut empty_purls = 0;
+
+        self.update_account(musicid, |account| {
+
+            if found == 0 {
+                account.record_failure();
+            }
+
+            account.health.empty_purls += songmids.len() - found;
+            empty_purls = account.health.empty_purls;
         });
 
-        let res = self.client.post(url).json(&payload).send().await.unwrap();
+        let expired = login_expired || (found == 0 && empty_purls >= EMPTY_PURL_LIMIT);
 
-        let json_response: Value = res.json().await.unwrap();
+        // Retry once with the refreshed credential
 
-        let base_data = &json_response["req_1"]["data"]["body"];
+        if let Some(musickey) = account.musickey().filter(|_| expired) {
 
-        match base_data["song"]["list"].as_array() {
+            warn!("QQmusic: The credential of {} looks expired, refreshing", musicid);
 
-            Some(songs_list) => {
+            if self.refresh(musicid, &musickey).await.is_ok() {
 
-                for item in songs_list {
+                if let Some(account) = self.account(musicid) {
 
-                    let singers: Vec<String> = item["singer"]
-                    .as_array()
-                    .unwrap_or(&vec![])
-                    .iter()
-                    .map(|s| s["name"].as_str().unwrap_or("").to_string())
-                    .collect();
+                    if let Ok(results) = self.request_play_urls(&account, songmids, quality).await {
 
-                    playlist.push(MusicPlayList {
-                        id: item["mid"].as_str().unwrap_or("").to_string(),
-                        name: item["name"].as_str().unwrap_or("").to_string(),
-                        player: singers.join(" / "),
-                    });
+                        if results.values().any(|result| result.is_ok()) {
+                            self.update_account(musicid, Account::record_success);
+                        }
+
+                        return Ok(results);
+                    }
                 }
+            }
+        }
+
+        if login_expired {
+            return Err(QQMusicError::QQMusicApiError { code: LOGIN_EXPIRED_CODE });
+        }
+
+        Ok(results)
+    }
+
+
+    // One CgiGetVkey request per VKEY_BATCH_SIZE songmids, packed in a single musicu.fcg call
+
+    async fn request_play_urls(&self, account: &Account, songmids: &[String], quality: Quality) -> Result<HashMap<String, Result<String,QQMusicError>>,QQMusicError> {
+
+        let mut batch = MusicuBatch::new(account.comm());
+        let mut keys = vec![];
+
+        for chunk in songmids.chunks(VKEY_BATCH_SIZE) {
+
+            let request = GetVkey {
+                guid: self.guid.clone(),
+                songmid: chunk.to_vec(),
+                songtype: vec![0; chunk.len()],
+                uin: account.musicid().to_string(),
+                loginflag: 1,
+                platform: "20".to_string(),
+                filename: chunk.iter().filter_map(|songmid| quality.file_name(songmid)).collect(),
+            };
+
+            keys.push((batch.push(&request)?, chunk));
+        }
+
+        let mut response = batch.send(&account.client, &self.config, Some(&self.limiter)).await?;
 
-                info!("Found Play list");
+        let mut play_urls = HashMap::new();
 
-                let mut count = 1;
+        for (key, chunk) in keys {
 
-                for song in &playlist {
+            match response.take(key) {
 
-                    debug!("{} {:?} {:?} {:?}",count,&song.name,&song.id,&song.player);
-                    count = count + 1;
+                Ok(data) => {
+
+                    let parsed = Self::parse_play_urls(&data, chunk);
+                    let ttl = Duration::from_secs(data.expiration.saturating_sub(VKEY_EXPIRY_MARGIN));
+
+                    for (songmid, play_url) in &parsed {
+
+                        if let Ok(play_url) = play_url {
+                            self.store(&vkey_cache_key(songmid, quality), play_url, ttl);
+                        }
+                    }
+
+                    play_urls.extend(parsed);
+                }
+
+                Err(QQMusicError::QQMusicApiError { code: LOGIN_EXPIRED_CODE }) => {
+                    return Err(QQMusicError::QQMusicApiError { code: LOGIN_EXPIRED_CODE });
                 }
 
-                let tabel_display = Self::_format_display(&playlist).await;
+                Err(_) => {
 
-                Ok(tabel_display)
+                    for songmid in chunk {
+                        play_urls.insert(songmid.clone(), Err(QQMusicError::QQMusicPlayError));
+                    }
+                }
             }
+        }
 
-            None => {
+        Ok(play_urls)
+    }
 
-                error!("QQmusic: Failed to search list");
-                return Err(QQMusicError::QQMusicPlaylistError);
-            }
+
+    // songmid -> play url, or why the vkey server gave no purl
+
+    fn parse_play_urls(data: &VkeyData, songmids: &[String]) -> HashMap<String, Result<String,QQMusicError>> {
+
+        let sip = data.sip.first();
+
+        songmids.iter().map(|songmid| {
+
+            let midurlinfo = data.midurlinfo.iter().find(|midurlinfo| &midurlinfo.songmid == songmid);
+
+            let result = match (sip, midurlinfo) {
+
+                (Some(sip), Some(midurlinfo)) if !midurlinfo.purl.is_empty() => {
+
+                    let play_url = format!("{}{}", sip, midurlinfo.purl);
+                    debug!("{:?}",play_url);
+
+                    Ok(play_url)
+                }
+
+                (_, Some(midurlinfo)) => Err(Self::unplayable_reason(midurlinfo)),
+
+                (_, None) => Err(QQMusicError::QQMusicPlayError),
+            };
+
+            (songmid.clone(), result)
+
+        }).collect()
+    }
+
+
+    // Result codes seen in midurlinfo when the purl is empty
+
+    fn unplayable_reason(midurlinfo: &MidUrlInfo) -> QQMusicError {
+
+        debug!("QQmusic: {} has no purl, result {} errtype {} tips {:?}", midurlinfo.songmid, midurlinfo.result, midurlinfo.errtype, midurlinfo.tips);
+
+        match midurlinfo.result {
+
+            _ if midurlinfo.pneedbuy == 1 => QQMusicError::QQMusicPaidAlbumError,
+
+            104003 => QQMusicError::QQMusicVipRequiredError,
+            104004 => QQMusicError::QQMusicPaidAlbumError,
+            104001 | 104002 => QQMusicError::QQMusicSongRemovedError,
+            104013 | 104019 => QQMusicError::QQMusicRegionBlockedError,
+            104400 => QQMusicError::QQMusicRateLimitedError,
+
+            _ => QQMusicError::QQMusicPlayError,
+        }
+    }
+
+
+    // Songs of a playlist (disstid is the number in the playlist link)
+
+    pub async fn get_playlist_songs(&self, disstid: &str) -> Result<Vec<MusicPlayList>,QQMusicError> {
+
+        let disstid = disstid.parse::<u64>().map_err(|_| QQMusicError::QQMusicPlaylistError)?;
+
+        let key = format!("playlist:{}", disstid);
+
+        if let Some(songs) = self.cached(&key) {
+            return Ok(songs);
+        }
+
+        let account = &self.pick_accounts()[0];
+
+        let request = GetDissInfo {
+            disstid,
+            userinfo: 1,
+            tag: 1,
+            orderlist: 1,
+            song_begin: 0,
+            song_num: SONG_LIST_LIMIT,
+            onlysonglist: 1,
+            enc_host_uin: String::new(),
+        };
+
+        let data = musicu_call(&account.client, &self.config, Some(&self.limiter), account.comm(), &request).await?;
+
+        if data.songlist.is_empty() {
+
+            error!("QQmusic: Failed to get playlist {}", disstid);
+            return Err(QQMusicError::QQMusicPlaylistError);
+        }
+
+        info!("QQmusic: Found {} songs in playlist {}", data.songlist.len(), disstid);
+
+        let songs: Vec<MusicPlayList> = data.songlist.iter().map(Self::parse_song).collect();
+
+        self.store(&key, &songs, self.config.cache.song_list_ttl);
+
+        Ok(songs)
+    }
+
+
+    // Songs of an album
+
+    pub async fn get_album_songs(&self, album_mid: &str) -> Result<Vec<MusicPlayList>,QQMusicError> {
+
+        let key = format!("album:{}", album_mid);
+
+        if let Some(songs) = self.cached(&key) {
+            return Ok(songs);
+        }
+
+        let account = &self.pick_accounts()[0];
+
+        let request = GetAlbumSongList {
+            album_mid: album_mid.to_string(),
+            album_id: 0,
+            begin: 0,
+            num: SONG_LIST_LIMIT,
+            order: 2,
+        };
+
+        let data = musicu_call(&account.client, &self.config, Some(&self.limiter), account.comm(), &request).await?;
+
+        if data.song_list.is_empty() {
+
+            error!("QQmusic: Failed to get album {}", album_mid);
+            return Err(QQMusicError::QQMusicPlaylistError);
+        }
+
+        info!("QQmusic: Found {} songs in album {}", data.song_list.len(), album_mid);
+
+        let songs: Vec<MusicPlayList> = data.song_list.iter().map(|item| Self::parse_song(&item["songInfo"])).collect();
+
+        self.store(&key, &songs, self.config.cache.song_list_ttl);
+
+        Ok(songs)
+    }
+
+
+    // Details of one song, for the checks a guild makes before queueing it
+
+    pub async fn get_song(&self, songmid: &str) -> Result<MusicPlayList,QQMusicError> {
+
+        let key = format!("song:{}", songmid);
+
+        if let Some(song) = self.cached(&key) {
+            return Ok(song);
+        }
+
+        let account = &self.pick_accounts()[0];
+
+        let request = GetSongDetail {
+            song_mid: songmid.to_string(),
+            song_type: 0,
+        };
+
+        let data = musicu_call(&account.client, &self.config, Some(&self.limiter), account.comm(), &request).await?;
+
+        let song = Self::parse_song(&data.track_info);
+
+        if song.id.is_empty() {
+
+            error!("QQmusic: Failed to get song {}", songmid);
+            return Err(QQMusicError::QQMusicSongError);
+        }
+
+        self.store(&key, &song, self.config.cache.song_list_ttl);
+
+        Ok(song)
+    }
+
+
+    fn parse_song(item: &Value) -> MusicPlayList {
+
+        let singers: Vec<String> = item["singer"]
+        .as_array()
+        .unwrap_or(&vec![])
+        .iter()
+        .map(|s| s["name"].as_str().unwrap_or("").to_string())
+        .collect();
+
+        // Flag songs the vkey server will refuse, so nobody tries to play them for nothing
+        let note = if item["action"]["alert"].as_i64() == Some(0) {
+            "N/A"
+        } else if item["pay"]["pay_play"].as_i64() == Some(1) && item["pay"]["price_album"].as_i64().unwrap_or(0) > 0 {
+            "Paid"
+        } else if item["pay"]["pay_play"].as_i64() == Some(1) {
+            "VIP"
+        } else {
+            ""
+        };
+
+        MusicPlayList {
+            id: item["mid"].as_str().unwrap_or("").to_string(),
+            name: item["name"].as_str().unwrap_or("").to_string(),
+            player: singers.join(" / "),
+            note: note.to_string(),
+            interval: item["interval"].as_u64().unwrap_or(0),
+        }
+    }
+
+
+    // Health of every account in the pool, for the owner
+
+    pub fn get_account_list(&self) -> String {
+
+        let accounts = self.accounts.read().unwrap();
+
+        let mut table = Table::new();
+        table.set_format(*format::consts::FORMAT_CLEAN);
+
+        table.add_row(row![b->"Account", b->"Status", b->"Last success", b->"Failures"]);
+
+        for account in accounts.iter() {
+
+            let name = match account.musicid() {
+                0 => "anonymous".to_string(),
+                musicid => musicid.to_string(),
+            };
+
+            let status = if account.credential.is_none() {
+                "anonymous"
+            } else if account.health.refresh_failed {
+                "expired"
+            } else if !account.health.is_healthy() {
+                "failing"
+            } else {
+                "ok"
+            };
+
+            let last_success = match account.health.last_success {
+                Some(time) => format_ago(time),
+                None => "never".to_string(),
+            };
+
+            table.add_row(Row::new(vec![
+                Cell::new(&name),
+                Cell::new(status),
+                Cell::new(&last_success),
+                Cell::new(&account.health.failures.to_string()),
+            ]));
+        }
+
+        let stats = self.cache.stats();
+
+        format!("```\n{}\nCache: {} entries, {} hits, {} misses```", table, stats.entries, stats.hits, stats.misses)
+    }
+
+
+    // Get search result of song's name
+
+    pub async fn get_search_list(&self, keyword: &str) -> Result<String,QQMusicError> {
+
+        let key = format!("search:{}", keyword);
+
+        if let Some(playlist) = self.cached::<Vec<MusicPlayList>>(&key) {
+            return Ok(Self::_format_display(&playlist).await);
+        }
+
+        let mut playlist: Vec<MusicPlayList> = vec![];
+
+        let account = &self.pick_accounts()[0];
+
+        let request = SearchSong {
+            query: keyword.to_string(),
+            num_per_page: 10,
+            page_num: 1,
+            search_type: SearchType::Song as i32,
+            remoteplace: "txt.yqq.top".to_string(),
+        };
+
+        let data = musicu_call(&account.client, &self.config, Some(&self.limiter), account.comm(), &request).await?;
+
+        for item in &data.body.song.list {
+
+            playlist.push(Self::parse_song(item));
+        }
+
+        info!("Found Play list");
+
+        self.store(&key, &playlist, self.config.cache.search_ttl);
+
+        for (count, song) in (1..).zip(playlist.iter()) {
+
+            debug!("{} {:?} {:?} {:?}",count,&song.name,&song.id,&song.player);
         }
+
+        let tabel_display = Self::_format_display(&playlist).await;
+
+        Ok(tabel_display)
     }
 
     async fn _format_display(playlist: &Vec<MusicPlayList>) -> String {
@@ -172,6 +897,7 @@ impl QQMusic {
         const ID_WIDTH: usize = 25;
         const NAME_WIDTH: usize = 30;
         const PLAYER_WIDTH: usize = 30;
+        const NOTE_WIDTH: usize = 6;
     
         let mut table = Table::new();
         table.set_format(*format::consts::FORMAT_CLEAN);
@@ -179,8 +905,9 @@ impl QQMusic {
         let id_header = format!("{:<width$}", "ID", width = ID_WIDTH);
         let name_header = format!("{:<width$}", "Name", width = NAME_WIDTH);
         let player_header = format!("{:<width$}", "Player", width = PLAYER_WIDTH);
+        let note_header = format!("{:<width$}", "Note", width = NOTE_WIDTH);
         
-        table.add_row(row![b->id_header, b->name_header, b->player_header]);
+        table.add_row(row![b->id_header, b->name_header, b->player_header, b->note_header]);
     
         for item in playlist {
             // --- START OF FIX ---
@@ -211,29 +938,53 @@ impl QQMusic {
                 Cell::new(&item.id),
                 Cell::new(&name),
                 Cell::new(&player),
+                Cell::new(&item.note),
             ]));
         }
         
-        format!("```\n{}```", table.to_string())
+        format!("```\n{}```", table)
     }
 
     
 }
 
 
-#[cfg(test)]
+// Play urls of each quality are cached apart, "vkey:high:002GwAma2DGN2x"
+
+fn vkey_cache_key(songmid: &str, quality: Quality) -> String {
+
+    format!("vkey:{}:{}", quality, songmid)
+}
 
+
+// Rough "time ago" for the account table
+
+fn format_ago(time: SystemTime) -> String {
+
+    let secs = SystemTime::now().duration_since(time).map(|d| d.as_secs()).unwrap_or(0);
+
+    match secs {
+        0..=59 => format!("{}s ago", secs),
+        60..=3599 => format!("{}m ago", secs / 60),
+        3600..=86399 => format!("{}h ago", secs / 3600),
+        _ => format!("{}d ago", secs / 86400),
+    }
+}
+
+
+#[cfg(test)]
 mod tests {
 
     use super::*;
 
     #[tokio::test]
+    #[ignore = "requires a QQ Music account and network access"]
     pub async fn test_get_qqmusic_play_url() {
 
         dotenvy::dotenv().ok();
-        env_logger::init();
+        let _ = env_logger::try_init();
 
-        let app = QQMusic::new().await.unwrap();
+        let app = QQMusic::new(QQMusicConfig::default(), Arc::new(Storage::open_default().unwrap())).await.unwrap();
 
         let songmid = "002GwAma2DGN2x";
 
@@ -242,12 +993,13 @@ mod tests {
 
 
     #[tokio::test]
+    #[ignore = "requires a QQ Music account and network access"]
     pub async fn test_get_search_list() {
 
         dotenvy::dotenv().ok();
-        env_logger::init();
+        let _ = env_logger::try_init();
 
-        let app = QQMusic::new().await.unwrap();
+        let app = QQMusic::new(QQMusicConfig::default(), Arc::new(Storage::open_default().unwrap())).await.unwrap();
 
         let keyword = "永不失联的爱";
 
@@ -255,27 +1007,60 @@ mod tests {
     }
 
 
+    #[test]
+    pub fn test_parse_play_urls() {
+
+        let data = r#"{
+            "sip": ["https://ws.stream.qqmusic.qq.com/"],
+            "midurlinfo": [
+                {"songmid": "002GwAma2DGN2x", "purl": "C400002GwAma2DGN2x.m4a?vkey=abc"},
+                {"songmid": "0039MnYb0qxYhV", "purl": "", "result": 104003}
+            ]
+        }"#;
+
+        let data: VkeyData = serde_json::from_str(data).unwrap();
+
+        let songmids = vec!["002GwAma2DGN2x".to_string(), "0039MnYb0qxYhV".to_string(), "000000000000000".to_string()];
+
+        let play_urls = QQMusic::parse_play_urls(&data, &songmids);
+
+        assert_eq!(play_urls.len(), 3);
+        assert_eq!(
+            play_urls["002GwAma2DGN2x"].as_deref().unwrap(),
+            "https://ws.stream.qqmusic.qq.com/C400002GwAma2DGN2x.m4a?vkey=abc"
+        );
+        assert!(matches!(play_urls["0039MnYb0qxYhV"], Err(QQMusicError::QQMusicVipRequiredError)));
+        assert!(matches!(play_urls["000000000000000"], Err(QQMusicError::QQMusicPlayError)));
+    }
+
+
     #[tokio::test] 
     pub async fn test_format_display() {
 
         dotenvy::dotenv().ok();
-        env_logger::init();
+        let _ = env_logger::try_init();
 
         let playlist = vec![
             MusicPlayList {
                 id: "C400000HnvQU05eTgI".to_string(),
                 name: "晴天".to_string(),
                 player: "周杰伦".to_string(),
+                note: String::new(),
+                interval: 0,
             },
             MusicPlayList {
                 id: "C400003lghpv0iXmD6".to_string(),
                 name: "以父之名".to_string(),
                 player: "周杰伦".to_string(),
+                note: String::new(),
+                interval: 0,
             },
             MusicPlayList {
                 id: "C400001aBvJ41eRkL".to_string(),
                 name: "十年".to_string(),
                 player: "陈奕迅".to_string(),
+                note: String::new(),
+                interval: 0,
             },
         ];
 
@@ -0,0 +1,107 @@
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+
+use std::time::Duration;
+use log::debug;
+
+
+// Token bucket shared by every task talking to QQ Music: `burst` requests at once, then
+// `per_second` on average
+
+pub struct RateLimiter {
+
+    burst: f64,
+    per_second: f64,
+    bucket: Mutex<Bucket>,
+}
+
+struct Bucket {
+
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+
+    pub fn new(per_second: f64, burst: u32) -> Self {
+
+        let burst = f64::from(burst.max(1));
+
+        RateLimiter {
+            burst,
+            per_second: per_second.max(0.01),
+            bucket: Mutex::new(Bucket { tokens: burst, last_refill: Instant::now() }),
+        }
+    }
+
+
+    // Wait until a request may be sent
+
+    pub async fn acquire(&self) {
+
+        loop {
+
+            let wait = {
+
+                let mut bucket = self.bucket.lock().await;
+                self.refill(&mut bucket);
+
+                if bucket.tokens >= 1.0 {
+                    bucket.tokens -= 1.0;
+                    return;
+                }
+
+                Duration::from_secs_f64((1.0 - bucket.tokens) / self.per_second)
+            };
+
+            debug!("RateLimiter: Waiting {:?} for a token", wait);
+
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    pub async fn try_acquire(&self) -> bool {
+
+        let mut bucket = self.bucket.lock().await;
+        self.refill(&mut bucket);
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn refill(&self, bucket: &mut Bucket) {
+
+        let now = Instant::now();
+        let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+
+        bucket.tokens = (bucket.tokens + elapsed * self.per_second).min(self.burst);
+        bucket.last_refill = now;
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_rate_limiter() {
+
+        let limiter = RateLimiter::new(20.0, 2);
+
+        assert!(limiter.try_acquire().await);
+        assert!(limiter.try_acquire().await);
+        assert!(!limiter.try_acquire().await);
+
+        // One token comes back every 50ms
+        let start = Instant::now();
+        limiter.acquire().await;
+
+        assert!(start.elapsed() >= Duration::from_millis(40));
+    }
+}
@@ -0,0 +1,70 @@
+use crate::player::{LoopMode, PlayerSettings, TrackData};
+
+use serde::{Deserialize, Serialize};
+use serenity::model::id::{ChannelId, GuildId, UserId};
+use songbird::tracks::TrackQueue;
+
+use std::time::Duration;
+
+
+// The queue of a guild as it was last seen, so a restart picks the music up where it stopped.
+// Kept in the storage with `storage.saved_queues()`
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SavedQueue {
+
+    pub guild_id: GuildId,
+    pub channel_id: ChannelId,
+    pub songs: Vec<SavedSong>,
+    // Seconds into the first song
+    pub position: u64,
+    pub loop_mode: LoopMode,
+    pub volume: f32,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SavedSong {
+
+    pub songmid: String,
+    pub requester: Option<UserId>,
+    // Length in seconds when it was known
+    pub duration: Option<u64>,
+}
+
+impl SavedQueue {
+
+    // What a guild is playing right now, nothing when its queue is empty
+
+    pub async fn capture(guild_id: GuildId, channel_id: ChannelId, queue: &TrackQueue, settings: &PlayerSettings) -> Option<SavedQueue> {
+
+        let handles = queue.current_queue();
+        let current = handles.first()?;
+
+        let data = current.data::<TrackData>();
+
+        // songbird's position is time played, a speed filter makes it differ from the song's
+        let played = current.get_info().await.map(|state| data.clock.position(state.position)).unwrap_or_default();
+
+        let songs = handles.iter()
+            .map(|handle| {
+                let data = handle.data::<TrackData>();
+                SavedSong { songmid: data.songmid.clone(), requester: data.requester, duration: data.duration.map(|duration| duration.as_secs()) }
+            })
+            .collect();
+
+        Some(SavedQueue {
+            guild_id,
+            channel_id,
+            songs,
+            position: (data.start + played).as_secs(),
+            loop_mode: settings.loop_mode,
+            volume: settings.volume,
+        })
+    }
+
+
+    pub fn position(&self) -> Duration {
+
+        Duration::from_secs(self.position)
+    }
+}
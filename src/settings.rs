@@ -0,0 +1,563 @@
+use crate::error::{error_chain, BotError, StorageError};
+use crate::player::MAX_VOLUME;
+use crate::storage::Storage;
+use crate::structs::{Language, MusicPlayList, Quality};
+
+use serde::{Deserialize, Serialize};
+use serenity::model::id::{ChannelId, GuildId, RoleId};
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use log::{error, debug};
+
+
+// How a guild wants the bot to behave, changed with /settings. Unset fields fall back to
+// what the bot was started with
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GuildSettings {
+
+    // Commands also work after it instead of a mention, and without the slash, e.g. "!play 002GwAma2DGN2x"
+    pub prefix: Option<String>,
+
+    // Volume every song starts at, 1.0 is the original loudness
+    pub volume: f32,
+
+    // Where the bot tells what it did on its own, such as leaving an idle channel
+    pub announce_channel: Option<ChannelId>,
+
+    // Needed for /cancel, /skip and the player settings. Manage Server is always enough
+    pub dj_role: Option<RoleId>,
+
+    pub max_queue_length: Option<usize>,
+
+    // Songs one member may have in the queue, the playing one included
+    pub max_user_tracks: Option<usize>,
+
+    // Songs take turns by requester instead of first come, first served
+    pub fair_queue: bool,
+
+    // Share of the listeners whose /voteskip skips the song, 0.5 is half of them
+    pub vote_skip: f32,
+
+    // Seconds. Songs QQ Music doesn't know the length of are checked once their file is in,
+    // and turned away if the file can't tell either
+    pub max_track_duration: Option<u64>,
+
+    // Turns away the songs parse_song flags as N/A, VIP or Paid. QQ Music metadata carries no
+    // explicit-lyrics marker, so explicit songs can only be kept out with the blocklists
+    pub block_flagged: bool,
+
+    // Seconds without music before the bot leaves the voice channel
+    pub idle_timeout: Option<u64>,
+
+    pub language: Option<Language>,
+    pub quality: Option<Quality>,
+
+    // Managed with /block and /unblock. Singers are matched without regard to case
+    pub blocked_songs: Vec<String>,
+    pub blocked_singers: Vec<String>,
+}
+
+impl Default for GuildSettings {
+
+    fn default() -> Self {
+
+        GuildSettings {
+            prefix: None,
+            volume: 1.0,
+            announce_channel: None,
+            dj_role: None,
+            max_queue_length: None,
+            max_user_tracks: None,
+            fair_queue: false,
+            vote_skip: 0.5,
+            max_track_duration: None,
+            block_flagged: false,
+            idle_timeout: None,
+            language: None,
+            quality: None,
+            blocked_songs: vec![],
+            blocked_singers: vec![],
+        }
+    }
+}
+
+impl GuildSettings {
+
+    // One line per field, as /settings shows them
+
+    pub fn describe(&self) -> String {
+
+        let or = |value: Option<String>, unset: &str| value.unwrap_or_else(|| unset.to_string());
+
+        [
+            ("prefix", or(self.prefix.clone(), "mention only")),
+            ("volume", format!("{}%", (self.volume * 100.0).round())),
+            ("announce_channel", or(self.announce_channel.map(|channel| format!("<#{}>", channel)), "none")),
+            ("dj_role", or(self.dj_role.map(|role| format!("<@&{}>", role)), "none, everyone is a DJ")),
+            ("max_queue_length", or(self.max_queue_length.map(|length| length.to_string()), "unlimited")),
+            ("max_user_tracks", or(self.max_user_tracks.map(|length| length.to_string()), "unlimited")),
+            ("fair_queue", (if self.fair_queue { "on" } else { "off" }).to_string()),
+            ("vote_skip", format!("{}% of the listeners", (self.vote_skip * 100.0).round())),
+            ("max_track_duration", or(self.max_track_duration.map(|seconds| format!("{}s", seconds)), "unlimited")),
+            ("block_flagged", (if self.block_flagged { "on" } else { "off" }).to_string()),
+            ("idle_timeout", or(self.idle_timeout.map(|seconds| format!("{}s", seconds)), "never")),
+            ("language", or(self.language.map(|language| format!("{:?}", language)), "bot default")),
+            ("quality", or(self.quality.map(|quality| quality.to_string()), "bot default")),
+            ("blocked_songs", self.blocked_songs.len().to_string()),
+            ("blocked_singers", self.blocked_singers.len().to_string()),
+        ]
+        .iter()
+        .map(|(field, value)| format!("**{}**: {}", field, value))
+        .collect::<Vec<_>>()
+        .join("\n")
+    }
+
+
+    // What /blocklist shows
+
+    pub fn describe_blocklist(&self) -> String {
+
+        let list = |items: &[String]| if items.is_empty() { "none".to_string() } else { items.join(", ") };
+
+        format!("**Songs**: {}\n**Singers**: {}", list(&self.blocked_songs), list(&self.blocked_singers))
+    }
+
+
+    // Whether a song has to be looked up before it is queued
+
+    pub fn has_restrictions(&self) -> bool {
+
+        self.max_track_duration.is_some() || self.block_flagged || !self.blocked_songs.is_empty() || !self.blocked_singers.is_empty()
+    }
+
+
+    // Why the guild doesn't take a song, None when it does. A song of unknown length, an interval
+    // of 0, passes here and goes through length_restriction once its file is in
+
+    pub fn restriction(&self, song: &MusicPlayList) -> Option<BotError> {
+
+        if self.blocked_songs.contains(&song.id) {
+            return Some(BotError::BotSongBlockedError);
+        }
+
+        // parse_song joins the singers with " / "
+        let blocked_singer = song.player.split(" / ")
+            .any(|singer| self.blocked_singers.iter().any(|blocked| blocked.to_lowercase() == singer.trim().to_lowercase()));
+
+        if blocked_singer {
+            return Some(BotError::BotSingerBlockedError);
+        }
+
+        if self.block_flagged && !song.note.is_empty() {
+            return Some(BotError::BotSongFlaggedError);
+        }
+
+        match self.max_track_duration {
+            Some(max) if song.interval > max => Some(BotError::BotTrackTooLongError),
+            _ => None,
+        }
+    }
+
+
+    // Whether the length of a song has to come from its file before it is queued
+
+    pub fn needs_length(&self, song: &MusicPlayList) -> bool {
+
+        self.max_track_duration.is_some() && song.interval == 0
+    }
+
+
+    // The length check for a song whose file has been read, None when even that doesn't know
+
+    pub fn length_restriction(&self, duration: Option<Duration>) -> Option<BotError> {
+
+        let max = Duration::from_secs(self.max_track_duration?);
+
+        match duration {
+            None => Some(BotError::BotTrackLengthUnknownError),
+            Some(duration) if duration > max => Some(BotError::BotTrackTooLongError),
+            Some(_) => None,
+        }
+    }
+}
+
+
+// A song, by its songmid, or a singer, by name, that a guild doesn't want played
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Blocked {
+    Song(String),
+    Singer(String),
+}
+
+impl Blocked {
+
+    // "song 002GwAma2DGN2x" or "singer 周杰伦"
+
+    pub fn parse(args: &str) -> Option<Blocked> {
+
+        let (kind, value) = args.trim().split_once(char::is_whitespace)?;
+        let value = value.trim().to_string();
+
+        match kind.to_lowercase().as_str() {
+            "song" => Some(Blocked::Song(value)),
+            "singer" => Some(Blocked::Singer(value)),
+            _ => None,
+        }
+    }
+}
+
+
+// One field of the settings and its new value, None puts it back to the default
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SettingChange {
+    Prefix(Option<String>),
+    Volume(f32),
+    AnnounceChannel(Option<ChannelId>),
+    DjRole(Option<RoleId>),
+    MaxQueueLength(Option<usize>),
+    MaxUserTracks(Option<usize>),
+    FairQueue(bool),
+    VoteSkip(f32),
+    MaxTrackDuration(Option<u64>),
+    BlockFlagged(bool),
+    IdleTimeout(Option<u64>),
+    Language(Option<Language>),
+    Quality(Option<Quality>),
+    // From /block and /unblock rather than /settings
+    Block(Blocked),
+    Unblock(Blocked),
+}
+
+impl SettingChange {
+
+    // "volume 80", "dj_role @DJ", "announce_channel #music", "idle_timeout off"
+
+    pub fn parse(args: &str) -> Option<SettingChange> {
+
+        let (field, value) = args.trim().split_once(char::is_whitespace)?;
+        let value = value.trim();
+
+        let change = match field.to_lowercase().as_str() {
+            "prefix" => SettingChange::Prefix(optional(value, parse_prefix)?),
+            "volume" => SettingChange::Volume(parse_volume(value)?),
+            "announce_channel" => SettingChange::AnnounceChannel(optional(value, |value| parse_id(value, "<#").map(ChannelId::new))?),
+            "dj_role" => SettingChange::DjRole(optional(value, |value| parse_id(value, "<@&").map(RoleId::new))?),
+            "max_queue_length" => SettingChange::MaxQueueLength(optional(value, |value| parse_number(value).map(|length| length as usize))?),
+            "max_user_tracks" => SettingChange::MaxUserTracks(optional(value, |value| parse_number(value).map(|length| length as usize))?),
+            "fair_queue" => SettingChange::FairQueue(parse_switch(value)?),
+            "vote_skip" => SettingChange::VoteSkip(parse_fraction(value)?),
+            "max_track_duration" => SettingChange::MaxTrackDuration(optional(value, parse_number)?),
+            "block_flagged" => SettingChange::BlockFlagged(parse_switch(value)?),
+            "idle_timeout" => SettingChange::IdleTimeout(optional(value, parse_number)?),
+            "language" => SettingChange::Language(optional(value, Language::parse)?),
+            "quality" => SettingChange::Quality(optional(value, Quality::parse)?),
+            _ => return None,
+        };
+
+        Some(change)
+    }
+
+
+    pub fn apply(self, settings: &mut GuildSettings) {
+
+        match self {
+            SettingChange::Prefix(prefix) => settings.prefix = prefix,
+            SettingChange::Volume(volume) => settings.volume = volume,
+            SettingChange::AnnounceChannel(channel) => settings.announce_channel = channel,
+            SettingChange::DjRole(role) => settings.dj_role = role,
+            SettingChange::MaxQueueLength(length) => settings.max_queue_length = length,
+            SettingChange::MaxUserTracks(length) => settings.max_user_tracks = length,
+            SettingChange::FairQueue(fair) => settings.fair_queue = fair,
+            SettingChange::VoteSkip(fraction) => settings.vote_skip = fraction,
+            SettingChange::MaxTrackDuration(seconds) => settings.max_track_duration = seconds,
+            SettingChange::BlockFlagged(block) => settings.block_flagged = block,
+            SettingChange::IdleTimeout(seconds) => settings.idle_timeout = seconds,
+            SettingChange::Language(language) => settings.language = language,
+            SettingChange::Quality(quality) => settings.quality = quality,
+
+            SettingChange::Block(Blocked::Song(songmid)) => {
+                if !settings.blocked_songs.contains(&songmid) {
+                    settings.blocked_songs.push(songmid);
+                }
+            }
+
+            SettingChange::Block(Blocked::Singer(singer)) => {
+                if !settings.blocked_singers.iter().any(|blocked| blocked.to_lowercase() == singer.to_lowercase()) {
+                    settings.blocked_singers.push(singer);
+                }
+            }
+
+            SettingChange::Unblock(Blocked::Song(songmid)) => settings.blocked_songs.retain(|blocked| *blocked != songmid),
+            SettingChange::Unblock(Blocked::Singer(singer)) => settings.blocked_singers.retain(|blocked| blocked.to_lowercase() != singer.to_lowercase()),
+        }
+    }
+}
+
+
+// "off", "none" or "default" put a field back to its default, None when the value is not valid
+
+fn optional<T>(value: &str, parse: impl FnOnce(&str) -> Option<T>) -> Option<Option<T>> {
+
+    match value.to_lowercase().as_str() {
+        "off" | "none" | "default" => Some(None),
+        _ => parse(value).map(Some),
+    }
+}
+
+// Short and without spaces, so it can't be mistaken for the start of a chat message
+
+fn parse_prefix(value: &str) -> Option<String> {
+
+    (!value.contains(char::is_whitespace) && value.chars().count() <= 5).then(|| value.to_string())
+}
+
+// Percent, up to MAX_VOLUME
+
+fn parse_volume(value: &str) -> Option<f32> {
+
+    let percent = value.trim_end_matches('%').parse::<u32>().ok()?;
+
+    Some(percent as f32 / 100.0).filter(|volume| *volume <= MAX_VOLUME)
+}
+
+// "on" or "off"
+
+fn parse_switch(value: &str) -> Option<bool> {
+
+    match value.to_lowercase().as_str() {
+        "on" => Some(true),
+        "off" => Some(false),
+        _ => None,
+    }
+}
+
+// Percent from 1 to 100
+
+fn parse_fraction(value: &str) -> Option<f32> {
+
+    let percent = value.trim_end_matches('%').parse::<u32>().ok().filter(|percent| (1..=100).contains(percent))?;
+
+    Some(percent as f32 / 100.0)
+}
+
+// A mention such as "<#123>" or the bare id
+
+fn parse_id(value: &str, prefix: &str) -> Option<u64> {
+
+    let id = value.strip_prefix(prefix).and_then(|id| id.strip_suffix('>')).unwrap_or(value);
+
+    id.parse::<u64>().ok().filter(|id| *id != 0)
+}
+
+// At least 1, zero would make the field useless rather than off
+
+fn parse_number(value: &str) -> Option<u64> {
+
+    value.parse::<u64>().ok().filter(|number| *number > 0)
+}
+
+
+// Settings of every guild, read from the storage the first time a guild is seen
+
+pub struct GuildSettingsCache {
+
+    storage: Arc<Storage>,
+    guilds: RwLock<HashMap<GuildId, GuildSettings>>,
+    // Held across a whole update, two changes to a guild at once would lose one of them
+    updates: Mutex<HashMap<GuildId, Arc<tokio::sync::Mutex<()>>>>,
+}
+
+impl GuildSettingsCache {
+
+    pub fn new(storage: Arc<Storage>) -> Self {
+
+        GuildSettingsCache { storage, guilds: RwLock::new(HashMap::new()), updates: Mutex::new(HashMap::new()) }
+    }
+
+
+    // A guild whose settings can't be read plays with the defaults until they can
+
+    pub async fn get(&self, guild_id: GuildId) -> GuildSettings {
+
+        self.load(guild_id).await.unwrap_or_else(|e| {
+            error!("Settings: Failed to load the settings of guild {}: {}", guild_id, error_chain(&e));
+            GuildSettings::default()
+        })
+    }
+
+
+    async fn load(&self, guild_id: GuildId) -> Result<GuildSettings, StorageError> {
+
+        if let Some(settings) = self.guilds.read().unwrap().get(&guild_id) {
+            return Ok(settings.clone());
+        }
+
+        debug!("Settings: Loading the settings of guild {}", guild_id);
+
+        let settings = self.storage.guild_settings().get(guild_id).await?;
+
+        Ok(self.guilds.write().unwrap().entry(guild_id).or_insert(settings).clone())
+    }
+
+
+    // Saved before it is cached, so what the guild sees is what survives a restart. Settings
+    // that couldn't be read aren't overwritten with the defaults
+
+    pub async fn update(&self, guild_id: GuildId, change: SettingChange) -> Result<GuildSettings, StorageError> {
+
+        let lock = Arc::clone(self.updates.lock().unwrap().entry(guild_id).or_default());
+        let _update = lock.lock().await;
+
+        let mut settings = self.load(guild_id).await?;
+
+        change.apply(&mut settings);
+
+        self.storage.guild_settings().save(guild_id, &settings).await?;
+
+        self.guilds.write().unwrap().insert(guild_id, settings.clone());
+
+        Ok(settings)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_parse_setting_change() {
+
+        assert_eq!(SettingChange::parse("volume 80"), Some(SettingChange::Volume(0.8)));
+        assert_eq!(SettingChange::parse("volume 80%"), Some(SettingChange::Volume(0.8)));
+        assert_eq!(SettingChange::parse("prefix !"), Some(SettingChange::Prefix(Some("!".to_string()))));
+        assert_eq!(SettingChange::parse("prefix off"), Some(SettingChange::Prefix(None)));
+        assert_eq!(SettingChange::parse("dj_role <@&42>"), Some(SettingChange::DjRole(Some(RoleId::new(42)))));
+        assert_eq!(SettingChange::parse("announce_channel <#7>"), Some(SettingChange::AnnounceChannel(Some(ChannelId::new(7)))));
+        assert_eq!(SettingChange::parse("idle_timeout 300"), Some(SettingChange::IdleTimeout(Some(300))));
+        assert_eq!(SettingChange::parse("max_queue_length none"), Some(SettingChange::MaxQueueLength(None)));
+        assert_eq!(SettingChange::parse("max_user_tracks 10"), Some(SettingChange::MaxUserTracks(Some(10))));
+        assert_eq!(SettingChange::parse("fair_queue on"), Some(SettingChange::FairQueue(true)));
+        assert_eq!(SettingChange::parse("vote_skip 60%"), Some(SettingChange::VoteSkip(0.6)));
+        assert_eq!(SettingChange::parse("language zh"), Some(SettingChange::Language(Some(Language::Chinese))));
+        assert_eq!(SettingChange::parse("quality 320"), Some(SettingChange::Quality(Some(Quality::VeryHigh))));
+
+        assert_eq!(SettingChange::parse("volume 500"), None);
+        assert_eq!(SettingChange::parse("volume off"), None);
+        assert_eq!(SettingChange::parse("idle_timeout 0"), None);
+        assert_eq!(SettingChange::parse("vote_skip 0"), None);
+        assert_eq!(SettingChange::parse("fair_queue maybe"), None);
+        assert_eq!(SettingChange::parse("dj_role everyone"), None);
+        assert_eq!(SettingChange::parse("colour red"), None);
+        assert_eq!(SettingChange::parse("volume"), None);
+    }
+
+
+    #[test]
+    fn test_restriction() {
+
+        let song = MusicPlayList {
+            id: "002GwAma2DGN2x".to_string(),
+            player: "周杰伦 / Lara".to_string(),
+            interval: 269,
+            ..MusicPlayList::default()
+        };
+
+        let mut settings = GuildSettings::default();
+
+        assert!(!settings.has_restrictions());
+        assert!(settings.restriction(&song).is_none());
+
+        settings.max_track_duration = Some(300);
+        assert!(settings.restriction(&song).is_none());
+
+        settings.max_track_duration = Some(200);
+        assert!(matches!(settings.restriction(&song), Some(BotError::BotTrackTooLongError)));
+
+        // Let through until the file tells
+        let unknown = MusicPlayList { interval: 0, ..song.clone() };
+        assert!(settings.restriction(&unknown).is_none());
+        assert!(settings.needs_length(&unknown));
+        assert!(!settings.needs_length(&song));
+
+        assert!(matches!(settings.length_restriction(None), Some(BotError::BotTrackLengthUnknownError)));
+        assert!(matches!(settings.length_restriction(Some(Duration::from_secs(269))), Some(BotError::BotTrackTooLongError)));
+        assert!(settings.length_restriction(Some(Duration::from_secs(200))).is_none());
+        assert!(GuildSettings::default().length_restriction(None).is_none());
+
+        let vip = MusicPlayList { note: "VIP".to_string(), interval: 100, ..song.clone() };
+        assert!(settings.restriction(&vip).is_none());
+
+        SettingChange::parse("block_flagged on").unwrap().apply(&mut settings);
+        assert!(matches!(settings.restriction(&vip), Some(BotError::BotSongFlaggedError)));
+        SettingChange::parse("block_flagged off").unwrap().apply(&mut settings);
+
+        SettingChange::Block(Blocked::parse("singer lara").unwrap()).apply(&mut settings);
+        assert!(matches!(settings.restriction(&song), Some(BotError::BotSingerBlockedError)));
+
+        SettingChange::Block(Blocked::parse("song 002GwAma2DGN2x").unwrap()).apply(&mut settings);
+        SettingChange::Block(Blocked::parse("song 002GwAma2DGN2x").unwrap()).apply(&mut settings);
+        assert!(matches!(settings.restriction(&song), Some(BotError::BotSongBlockedError)));
+        assert_eq!(settings.blocked_songs.len(), 1);
+
+        SettingChange::Unblock(Blocked::Song("002GwAma2DGN2x".to_string())).apply(&mut settings);
+        SettingChange::Unblock(Blocked::Singer("LARA".to_string())).apply(&mut settings);
+        settings.max_track_duration = None;
+
+        assert!(!settings.has_restrictions());
+        assert_eq!(settings.describe_blocklist(), "**Songs**: none\n**Singers**: none");
+
+        assert_eq!(Blocked::parse("album 000MkMni19ClKG"), None);
+    }
+
+
+    #[tokio::test]
+    async fn test_guild_settings_cache() {
+
+        let dir = std::env::temp_dir().join(format!("qqmusic-settings-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let path = dir.join("storage.db");
+
+        let guild_id = GuildId::new(1);
+
+        let cache = GuildSettingsCache::new(Arc::new(Storage::open(path.clone()).unwrap()));
+
+        assert_eq!(cache.get(guild_id).await, GuildSettings::default());
+
+        let settings = cache.update(guild_id, SettingChange::MaxQueueLength(Some(50))).await.unwrap();
+
+        assert_eq!(settings.max_queue_length, Some(50));
+        assert_eq!(cache.get(guild_id).await.max_queue_length, Some(50));
+
+        // Read back from the storage by a new cache
+        let cache = GuildSettingsCache::new(Arc::new(Storage::open(path.clone()).unwrap()));
+
+        assert_eq!(cache.get(guild_id).await.max_queue_length, Some(50));
+        assert!(cache.get(guild_id).await.describe().contains("**max_queue_length**: 50"));
+
+        // Changes made at the same time all survive
+        let cache = Arc::new(cache);
+
+        let updates: Vec<_> = ["one", "two", "three", "four"].into_iter()
+            .map(|song| {
+                let cache = Arc::clone(&cache);
+                tokio::spawn(async move { cache.update(guild_id, SettingChange::Block(Blocked::Song(song.to_string()))).await.unwrap() })
+            })
+            .collect();
+
+        for update in updates {
+            update.await.unwrap();
+        }
+
+        assert_eq!(cache.get(guild_id).await.blocked_songs.len(), 4);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
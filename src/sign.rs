@@ -0,0 +1,77 @@
+// Sign of the musics.fcg endpoint, computed from the exact request body:
+// "zzb" + 8 chars of the md5 + the scrambled md5 in base64 + 8 more chars, lowercased
+
+
+const HEAD_INDEXES: [usize; 8] = [21, 4, 9, 26, 16, 20, 27, 30];
+const TAIL_INDEXES: [usize; 8] = [18, 11, 3, 2, 1, 7, 6, 25];
+const SCRAMBLE: [u8; 16] = [212, 45, 80, 68, 195, 163, 163, 203, 157, 220, 254, 91, 204, 79, 104, 6];
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+
+pub fn sign(body: &str) -> String {
+
+    let digest = md5::compute(body.as_bytes());
+    let hex: Vec<char> = format!("{:X}", digest).chars().collect();
+
+    let head: String = HEAD_INDEXES.iter().map(|&i| hex[i]).collect();
+    let tail: String = TAIL_INDEXES.iter().map(|&i| hex[i]).collect();
+
+    let scrambled: Vec<u8> = digest.0.iter().zip(SCRAMBLE.iter()).map(|(byte, key)| byte ^ key).collect();
+
+    // Base64 without padding, then '+' and '/' are dropped
+    let middle: String = base64(&scrambled).chars().filter(|c| *c != '+' && *c != '/').collect();
+
+    format!("zzb{}{}{}", head, middle, tail).to_lowercase()
+}
+
+
+fn base64(bytes: &[u8]) -> String {
+
+    let mut encoded = String::new();
+
+    for chunk in bytes.chunks(3) {
+
+        let b0 = chunk[0] as usize;
+        let b1 = chunk.get(1).copied().unwrap_or(0) as usize;
+        let b2 = chunk.get(2).copied().unwrap_or(0) as usize;
+
+        encoded.push(BASE64[b0 >> 2] as char);
+        encoded.push(BASE64[((b0 & 3) << 4) | (b1 >> 4)] as char);
+
+        if chunk.len() > 1 {
+            encoded.push(BASE64[((b1 & 15) << 2) | (b2 >> 6)] as char);
+        }
+
+        if chunk.len() > 2 {
+            encoded.push(BASE64[b2 & 63] as char);
+        }
+    }
+
+    encoded
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_sign() {
+
+        let body = r#"{"comm":{"ct":24,"cv":0},"req_1":{"module":"music.search.SearchCgiService","method":"DoSearchForQQMusicDesktop","param":{"query":"晴天","num_per_page":10,"page_num":1,"search_type":0}}}"#;
+
+        assert_eq!(sign(body), "zzb46e5deb4sv4b1q8mxlxbqkkbqhqg89375dbc");
+        assert_eq!(sign("hello world"), "zzb230592aciptryo9trsof9zgqxwlxqce6bebbf");
+        assert_eq!(sign(""), "zzb98ffe087addcnuyjec90xpfdilcqea80d149dc");
+    }
+
+
+    #[test]
+    fn test_base64() {
+
+        assert_eq!(base64(b"Man"), "TWFu");
+        assert_eq!(base64(b"Ma"), "TWE");
+        assert_eq!(base64(b"M"), "TQ");
+    }
+}
@@ -0,0 +1,770 @@
+use crate::credential::{data_dir, Credential};
+use crate::error::StorageError;
+use crate::saved_queue::SavedQueue;
+use crate::settings::GuildSettings;
+use crate::structs::MusicPlayList;
+
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serenity::model::id::{GuildId, UserId};
+
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+use log::{info, debug};
+
+
+// Version of the schema written by this build, kept in SQLite's user_version
+pub const SCHEMA_VERSION: u32 = 2;
+
+// Requested songs remembered per guild, the oldest are forgotten first
+pub const MAX_HISTORY: usize = 1000;
+
+// Each migration takes the database from the version at its index to the next one.
+// Append new ones, never change one that has shipped
+const MIGRATIONS: &[&str] = &[CREATE_TABLES, CLIENT_TABLES];
+
+// Version 0 to 1: the first tables. Songs and settings are JSON, ids are Discord snowflakes
+const CREATE_TABLES: &str = "
+    CREATE TABLE guild_settings (
+        guild_id INTEGER PRIMARY KEY,
+        settings TEXT NOT NULL
+    );
+
+    CREATE TABLE history (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        guild_id INTEGER NOT NULL,
+        song TEXT NOT NULL,
+        requester INTEGER,
+        requested_at INTEGER NOT NULL
+    );
+
+    CREATE INDEX history_guild ON history (guild_id, id);
+
+    CREATE TABLE playlists (
+        user_id INTEGER NOT NULL,
+        name TEXT NOT NULL,
+        songs TEXT NOT NULL,
+        PRIMARY KEY (user_id, name)
+    );
+
+    CREATE TABLE favorites (
+        user_id INTEGER NOT NULL,
+        songmid TEXT NOT NULL,
+        song TEXT NOT NULL,
+        PRIMARY KEY (user_id, songmid)
+    );
+";
+
+// Version 1 to 2: QQ Music credentials, the installation guid and saved queues
+const CLIENT_TABLES: &str = "
+    CREATE TABLE credentials (
+        musicid INTEGER PRIMARY KEY,
+        credential TEXT NOT NULL
+    );
+
+    CREATE TABLE installation (
+        key TEXT PRIMARY KEY,
+        value TEXT NOT NULL
+    );
+
+    CREATE TABLE saved_queues (
+        guild_id INTEGER PRIMARY KEY,
+        queue TEXT NOT NULL
+    );
+";
+
+
+// Everything the bot keeps about guilds and users, in a SQLite database where every change
+// writes only its own rows. Read through the typed repositories, e.g. `storage.favorites().add(..)`.
+// Queries block, they run on tokio's blocking threads
+
+pub struct Storage {
+
+    connection: Arc<Mutex<Connection>>,
+}
+
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HistoryEntry {
+
+    pub song: MusicPlayList,
+    pub requester: Option<UserId>,
+    // Unix seconds
+    pub requested_at: u64,
+}
+
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UserPlaylist {
+
+    pub name: String,
+    pub songs: Vec<MusicPlayList>,
+}
+
+
+impl Storage {
+
+    // DATA_DIR/storage.db
+
+    pub fn open_default() -> Result<Self, StorageError> {
+
+        Self::open(data_dir().join("storage.db"))
+    }
+
+
+    // Open the database, bringing it up to the current version. A missing file is a new database
+
+    pub fn open(path: PathBuf) -> Result<Self, StorageError> {
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(StorageError::StorageIoError)?;
+        }
+
+        let mut connection = Connection::open(&path)?;
+
+        // Readers don't wait for a write, and a crash mid-write keeps the last commit
+        connection.pragma_update(None, "journal_mode", "WAL")?;
+
+        let version: u32 = connection.pragma_query_value(None, "user_version", |row| row.get(0))?;
+
+        // Written by a newer build, migrating it again would break what that build added
+        if version > SCHEMA_VERSION {
+            return Err(StorageError::StorageVersionError { found: version, supported: SCHEMA_VERSION });
+        }
+
+        let transaction = connection.transaction()?;
+
+        for (from, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+
+            debug!("Storage: Migrating {:?} from version {}", path, from);
+            transaction.execute_batch(migration)?;
+        }
+
+        transaction.pragma_update(None, "user_version", SCHEMA_VERSION)?;
+        transaction.commit()?;
+
+        if version < SCHEMA_VERSION {
+            info!("Storage: Migrated {:?} from version {} to {}", path, version, SCHEMA_VERSION);
+        }
+
+        Ok(Storage { connection: Arc::new(Mutex::new(connection)) })
+    }
+
+
+    pub fn guild_settings(&self) -> GuildSettingsRepository<'_> {
+
+        GuildSettingsRepository { storage: self }
+    }
+
+    pub fn history(&self) -> HistoryRepository<'_> {
+
+        HistoryRepository { storage: self }
+    }
+
+    pub fn playlists(&self) -> PlaylistRepository<'_> {
+
+        PlaylistRepository { storage: self }
+    }
+
+    pub fn favorites(&self) -> FavoritesRepository<'_> {
+
+        FavoritesRepository { storage: self }
+    }
+
+    pub fn credentials(&self) -> CredentialRepository<'_> {
+
+        CredentialRepository { storage: self }
+    }
+
+    pub fn installation(&self) -> InstallationRepository<'_> {
+
+        InstallationRepository { storage: self }
+    }
+
+    pub fn saved_queues(&self) -> SavedQueueRepository<'_> {
+
+        SavedQueueRepository { storage: self }
+    }
+
+
+    // Run a query on a blocking thread
+
+    async fn call<T: Send + 'static>(&self, call: impl FnOnce(&mut Connection) -> Result<T, StorageError> + Send + 'static) -> Result<T, StorageError> {
+
+        let connection = Arc::clone(&self.connection);
+
+        tokio::task::spawn_blocking(move || call(&mut connection.lock().unwrap()))
+            .await
+            .map_err(|e| StorageError::StorageIoError(io::Error::other(e)))?
+    }
+}
+
+
+fn to_json<T: Serialize>(value: &T) -> Result<String, StorageError> {
+
+    serde_json::to_string(value).map_err(StorageError::StorageParseError)
+}
+
+fn from_json<T: DeserializeOwned>(json: &str) -> Result<T, StorageError> {
+
+    serde_json::from_str(json).map_err(StorageError::StorageParseError)
+}
+
+
+// Snowflakes are below 2^63, they fit SQLite's signed integers
+
+fn snowflake(id: u64) -> i64 {
+
+    id as i64
+}
+
+
+fn now() -> u64 {
+
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|elapsed| elapsed.as_secs()).unwrap_or_default()
+}
+
+
+fn save_settings(connection: &Connection, guild_id: GuildId, settings: &GuildSettings) -> Result<(), StorageError> {
+
+    connection.execute(
+        "INSERT INTO guild_settings (guild_id, settings) VALUES (?1, ?2)
+         ON CONFLICT (guild_id) DO UPDATE SET settings = excluded.settings",
+        params![snowflake(guild_id.get()), to_json(settings)?],
+    )?;
+
+    Ok(())
+}
+
+
+// Oldest first, and only the last MAX_HISTORY of the guild are kept
+
+fn insert_history(connection: &Connection, guild_id: GuildId, entries: &[HistoryEntry]) -> Result<(), StorageError> {
+
+    let guild_id = snowflake(guild_id.get());
+
+    let mut insert = connection.prepare_cached("INSERT INTO history (guild_id, song, requester, requested_at) VALUES (?1, ?2, ?3, ?4)")?;
+
+    for entry in entries {
+        insert.execute(params![guild_id, to_json(&entry.song)?, entry.requester.map(|user_id| snowflake(user_id.get())), entry.requested_at as i64])?;
+    }
+
+    connection.execute(
+        "DELETE FROM history WHERE guild_id = ?1 AND id NOT IN (SELECT id FROM history WHERE guild_id = ?1 ORDER BY id DESC LIMIT ?2)",
+        params![guild_id, MAX_HISTORY as i64],
+    )?;
+
+    Ok(())
+}
+
+
+// Replaces the playlist of the same name, which keeps its place in the list
+
+fn save_playlist(connection: &Connection, user_id: UserId, playlist: &UserPlaylist) -> Result<(), StorageError> {
+
+    connection.execute(
+        "INSERT INTO playlists (user_id, name, songs) VALUES (?1, ?2, ?3)
+         ON CONFLICT (user_id, name) DO UPDATE SET songs = excluded.songs",
+        params![snowflake(user_id.get()), playlist.name, to_json(&playlist.songs)?],
+    )?;
+
+    Ok(())
+}
+
+
+fn save_credential(connection: &Connection, credential: &Credential) -> Result<(), StorageError> {
+
+    connection.execute(
+        "INSERT INTO credentials (musicid, credential) VALUES (?1, ?2)
+         ON CONFLICT (musicid) DO UPDATE SET credential = excluded.credential",
+        params![credential.musicid as i64, to_json(credential)?],
+    )?;
+
+    Ok(())
+}
+
+
+fn save_queue(connection: &Connection, queue: &SavedQueue) -> Result<(), StorageError> {
+
+    connection.execute(
+        "INSERT INTO saved_queues (guild_id, queue) VALUES (?1, ?2)
+         ON CONFLICT (guild_id) DO UPDATE SET queue = excluded.queue",
+        params![snowflake(queue.guild_id.get()), to_json(queue)?],
+    )?;
+
+    Ok(())
+}
+
+
+fn add_favorite(connection: &Connection, user_id: UserId, song: &MusicPlayList) -> Result<bool, StorageError> {
+
+    let added = connection.execute(
+        "INSERT OR IGNORE INTO favorites (user_id, songmid, song) VALUES (?1, ?2, ?3)",
+        params![snowflake(user_id.get()), song.id, to_json(song)?],
+    )?;
+
+    Ok(added > 0)
+}
+
+
+pub struct GuildSettingsRepository<'a> {
+
+    storage: &'a Storage,
+}
+
+impl GuildSettingsRepository<'_> {
+
+    // The defaults for a guild that never changed anything
+
+    pub async fn get(&self, guild_id: GuildId) -> Result<GuildSettings, StorageError> {
+
+        self.storage.call(move |connection| {
+
+            let settings: Option<String> = connection
+                .query_row("SELECT settings FROM guild_settings WHERE guild_id = ?1", [snowflake(guild_id.get())], |row| row.get(0))
+                .optional()?;
+
+            settings.map(|settings| from_json(&settings)).transpose().map(Option::unwrap_or_default)
+        }).await
+    }
+
+    pub async fn save(&self, guild_id: GuildId, settings: &GuildSettings) -> Result<(), StorageError> {
+
+        let settings = settings.clone();
+
+        self.storage.call(move |connection| save_settings(connection, guild_id, &settings)).await
+    }
+}
+
+
+pub struct HistoryRepository<'a> {
+
+    storage: &'a Storage,
+}
+
+impl HistoryRepository<'_> {
+
+    // Songs queued together are written in one transaction
+
+    pub async fn record(&self, guild_id: GuildId, songs: &[MusicPlayList], requester: Option<UserId>) -> Result<(), StorageError> {
+
+        let requested_at = now();
+        let entries: Vec<HistoryEntry> = songs.iter().map(|song| HistoryEntry { song: song.clone(), requester, requested_at }).collect();
+
+        self.storage.call(move |connection| {
+
+            let transaction = connection.transaction()?;
+            insert_history(&transaction, guild_id, &entries)?;
+            transaction.commit()?;
+
+            Ok(())
+        }).await
+    }
+
+    // Newest first
+
+    pub async fn recent(&self, guild_id: GuildId, limit: usize) -> Result<Vec<HistoryEntry>, StorageError> {
+
+        self.storage.call(move |connection| {
+
+            let mut select = connection.prepare_cached("SELECT song, requester, requested_at FROM history WHERE guild_id = ?1 ORDER BY id DESC LIMIT ?2")?;
+
+            let rows = select.query_map(params![snowflake(guild_id.get()), i64::try_from(limit).unwrap_or(i64::MAX)], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, Option<i64>>(1)?, row.get::<_, i64>(2)?))
+            })?;
+
+            rows.map(|row| {
+
+                let (song, requester, requested_at) = row?;
+
+                Ok(HistoryEntry {
+                    song: from_json(&song)?,
+                    requester: requester.map(|user_id| UserId::new(user_id as u64)),
+                    requested_at: requested_at as u64,
+                })
+            }).collect()
+        }).await
+    }
+}
+
+
+pub struct PlaylistRepository<'a> {
+
+    storage: &'a Storage,
+}
+
+impl PlaylistRepository<'_> {
+
+    // In the order they were first saved
+
+    pub async fn list(&self, user_id: UserId) -> Result<Vec<UserPlaylist>, StorageError> {
+
+        self.storage.call(move |connection| {
+
+            let mut select = connection.prepare_cached("SELECT name, songs FROM playlists WHERE user_id = ?1 ORDER BY rowid")?;
+
+            let rows = select.query_map([snowflake(user_id.get())], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))?;
+
+            rows.map(|row| {
+                let (name, songs) = row?;
+                Ok(UserPlaylist { name, songs: from_json(&songs)? })
+            }).collect()
+        }).await
+    }
+
+    pub async fn get(&self, user_id: UserId, name: &str) -> Result<Option<UserPlaylist>, StorageError> {
+
+        let name = name.to_string();
+
+        self.storage.call(move |connection| {
+
+            let songs: Option<String> = connection
+                .query_row("SELECT songs FROM playlists WHERE user_id = ?1 AND name = ?2", params![snowflake(user_id.get()), name], |row| row.get(0))
+                .optional()?;
+
+            songs.map(|songs| Ok(UserPlaylist { name, songs: from_json(&songs)? })).transpose()
+        }).await
+    }
+
+    // Replaces the playlist of the same name
+
+    pub async fn save(&self, user_id: UserId, playlist: &UserPlaylist) -> Result<(), StorageError> {
+
+        let playlist = playlist.clone();
+
+        self.storage.call(move |connection| save_playlist(connection, user_id, &playlist)).await
+    }
+
+    // Whether there was one to delete
+
+    pub async fn delete(&self, user_id: UserId, name: &str) -> Result<bool, StorageError> {
+
+        let name = name.to_string();
+
+        self.storage.call(move |connection| {
+            Ok(connection.execute("DELETE FROM playlists WHERE user_id = ?1 AND name = ?2", params![snowflake(user_id.get()), name])? > 0)
+        }).await
+    }
+}
+
+
+pub struct FavoritesRepository<'a> {
+
+    storage: &'a Storage,
+}
+
+impl FavoritesRepository<'_> {
+
+    // In the order they were added
+
+    pub async fn list(&self, user_id: UserId) -> Result<Vec<MusicPlayList>, StorageError> {
+
+        self.storage.call(move |connection| {
+
+            let mut select = connection.prepare_cached("SELECT song FROM favorites WHERE user_id = ?1 ORDER BY rowid")?;
+
+            let rows = select.query_map([snowflake(user_id.get())], |row| row.get::<_, String>(0))?;
+
+            rows.map(|song| from_json(&song?)).collect()
+        }).await
+    }
+
+    // False when the song already was a favorite
+
+    pub async fn add(&self, user_id: UserId, song: &MusicPlayList) -> Result<bool, StorageError> {
+
+        let song = song.clone();
+
+        self.storage.call(move |connection| add_favorite(connection, user_id, &song)).await
+    }
+
+    pub async fn remove(&self, user_id: UserId, songmid: &str) -> Result<bool, StorageError> {
+
+        let songmid = songmid.to_string();
+
+        self.storage.call(move |connection| {
+            Ok(connection.execute("DELETE FROM favorites WHERE user_id = ?1 AND songmid = ?2", params![snowflake(user_id.get()), songmid])? > 0)
+        }).await
+    }
+}
+
+
+// Accounts signed in with /login, kept so they survive a restart
+
+pub struct CredentialRepository<'a> {
+
+    storage: &'a Storage,
+}
+
+impl CredentialRepository<'_> {
+
+    // In the order the accounts first signed in
+
+    pub async fn list(&self) -> Result<Vec<Credential>, StorageError> {
+
+        self.storage.call(|connection| {
+
+            let mut select = connection.prepare_cached("SELECT credential FROM credentials ORDER BY rowid")?;
+
+            let rows = select.query_map([], |row| row.get::<_, String>(0))?;
+
+            rows.map(|credential| from_json(&credential?)).collect()
+        }).await
+    }
+
+    // Replaces the credential of the same account
+
+    pub async fn save(&self, credential: &Credential) -> Result<(), StorageError> {
+
+        let credential = credential.clone();
+
+        self.storage.call(move |connection| save_credential(connection, &credential)).await
+    }
+
+    pub async fn remove(&self, musicid: u64) -> Result<bool, StorageError> {
+
+        self.storage.call(move |connection| {
+            Ok(connection.execute("DELETE FROM credentials WHERE musicid = ?1", [musicid as i64])? > 0)
+        }).await
+    }
+}
+
+
+// Values made once per installation
+
+pub struct InstallationRepository<'a> {
+
+    storage: &'a Storage,
+}
+
+impl InstallationRepository<'_> {
+
+    // A random guid, the vkey server ties play urls to it. Made the first time it is asked for
+
+    pub async fn guid(&self) -> Result<String, StorageError> {
+
+        self.storage.call(|connection| {
+
+            connection.execute("INSERT OR IGNORE INTO installation (key, value) VALUES ('guid', ?1)", [Uuid::new_v4().simple().to_string()])?;
+
+            Ok(connection.query_row("SELECT value FROM installation WHERE key = 'guid'", [], |row| row.get(0))?)
+        }).await
+    }
+}
+
+
+// The queue of each guild as it was last seen, one row per guild
+
+pub struct SavedQueueRepository<'a> {
+
+    storage: &'a Storage,
+}
+
+impl SavedQueueRepository<'_> {
+
+    pub async fn list(&self) -> Result<Vec<SavedQueue>, StorageError> {
+
+        self.storage.call(|connection| {
+
+            let mut select = connection.prepare_cached("SELECT queue FROM saved_queues ORDER BY rowid")?;
+
+            let rows = select.query_map([], |row| row.get::<_, String>(0))?;
+
+            rows.map(|queue| from_json(&queue?)).collect()
+        }).await
+    }
+
+    pub async fn save(&self, queue: &SavedQueue) -> Result<(), StorageError> {
+
+        let queue = queue.clone();
+
+        self.storage.call(move |connection| save_queue(connection, &queue)).await
+    }
+
+    pub async fn remove(&self, guild_id: GuildId) -> Result<bool, StorageError> {
+
+        self.storage.call(move |connection| {
+            Ok(connection.execute("DELETE FROM saved_queues WHERE guild_id = ?1", [snowflake(guild_id.get())])? > 0)
+        }).await
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::path::Path;
+
+    // A directory of its own, so tests running at once don't share a database
+
+    fn temp_path(name: &str) -> PathBuf {
+
+        let dir = std::env::temp_dir().join(format!("qqmusic-storage-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir.join("storage.db")
+    }
+
+    fn remove(path: &Path) {
+
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+    }
+
+    fn song(id: &str) -> MusicPlayList {
+
+        MusicPlayList { id: id.to_string(), name: format!("song {}", id), ..MusicPlayList::default() }
+    }
+
+
+    #[tokio::test]
+    async fn test_repositories() {
+
+        let path = temp_path("repositories");
+        let storage = Storage::open(path.clone()).unwrap();
+
+        let guild_id = GuildId::new(1);
+        let user_id = UserId::new(2);
+
+        // Guild settings
+        assert_eq!(storage.guild_settings().get(guild_id).await.unwrap(), GuildSettings::default());
+
+        let settings = GuildSettings { volume: 0.5, ..GuildSettings::default() };
+        storage.guild_settings().save(guild_id, &settings).await.unwrap();
+
+        // History
+        storage.history().record(guild_id, &[song("001")], Some(user_id)).await.unwrap();
+        storage.history().record(guild_id, &[song("002")], None).await.unwrap();
+
+        let recent = storage.history().recent(guild_id, 10).await.unwrap();
+        assert_eq!(recent.iter().map(|entry| entry.song.id.as_str()).collect::<Vec<_>>(), vec!["002", "001"]);
+        assert_eq!(recent[1].requester, Some(user_id));
+
+        // Playlists, saved again under the same name replaces it
+        let playlist = UserPlaylist { name: "road trip".to_string(), songs: vec![song("001")] };
+        storage.playlists().save(user_id, &playlist).await.unwrap();
+        storage.playlists().save(user_id, &UserPlaylist { songs: vec![song("001"), song("002")], ..playlist.clone() }).await.unwrap();
+
+        assert_eq!(storage.playlists().list(user_id).await.unwrap().len(), 1);
+        assert_eq!(storage.playlists().get(user_id, "road trip").await.unwrap().unwrap().songs.len(), 2);
+
+        // Favorites, no duplicates
+        assert!(storage.favorites().add(user_id, &song("001")).await.unwrap());
+        assert!(!storage.favorites().add(user_id, &song("001")).await.unwrap());
+        assert!(storage.favorites().add(user_id, &song("003")).await.unwrap());
+        assert!(storage.favorites().remove(user_id, "003").await.unwrap());
+        assert!(!storage.favorites().remove(user_id, "003").await.unwrap());
+
+        // Everything is still there after a restart
+        drop(storage);
+        let storage = Storage::open(path.clone()).unwrap();
+
+        assert_eq!(storage.guild_settings().get(guild_id).await.unwrap(), settings);
+        assert_eq!(storage.history().recent(guild_id, 1).await.unwrap()[0].song.id, "002");
+        assert!(storage.playlists().delete(user_id, "road trip").await.unwrap());
+        assert!(storage.playlists().get(user_id, "road trip").await.unwrap().is_none());
+        assert_eq!(storage.favorites().list(user_id).await.unwrap(), vec![song("001")]);
+
+        drop(storage);
+        remove(&path);
+    }
+
+
+    #[tokio::test]
+    async fn test_history_limit() {
+
+        let path = temp_path("history");
+        let storage = Storage::open(path.clone()).unwrap();
+        let guild_id = GuildId::new(1);
+
+        let songs: Vec<MusicPlayList> = (0..MAX_HISTORY + 5).map(|i| song(&i.to_string())).collect();
+        storage.history().record(guild_id, &songs, None).await.unwrap();
+
+        let history = storage.history().recent(guild_id, usize::MAX).await.unwrap();
+
+        assert_eq!(history.len(), MAX_HISTORY);
+        assert_eq!(history.last().unwrap().song.id, "5");
+
+        // Other guilds keep theirs
+        storage.history().record(GuildId::new(2), &songs[..1], None).await.unwrap();
+        assert_eq!(storage.history().recent(guild_id, usize::MAX).await.unwrap().len(), MAX_HISTORY);
+
+        drop(storage);
+        remove(&path);
+    }
+
+
+    #[tokio::test]
+    async fn test_migrations() {
+
+        let path = temp_path("migrations");
+
+        let storage = Storage::open(path.clone()).unwrap();
+
+        assert!(storage.history().recent(GuildId::new(1), 10).await.unwrap().is_empty());
+
+        drop(storage);
+
+        let connection = Connection::open(&path).unwrap();
+        let version: u32 = connection.pragma_query_value(None, "user_version", |row| row.get(0)).unwrap();
+        assert_eq!(version, SCHEMA_VERSION);
+
+        // Written by a newer build
+        connection.pragma_update(None, "user_version", 99).unwrap();
+        drop(connection);
+
+        assert!(matches!(Storage::open(path.clone()), Err(StorageError::StorageVersionError { found: 99, .. })));
+
+        remove(&path);
+    }
+
+
+    #[tokio::test]
+    async fn test_client_repositories() {
+
+        let path = temp_path("client");
+        let storage = Storage::open(path.clone()).unwrap();
+
+        // Credentials, one per account
+        let mut credential = Credential { musicid: 123456789, musickey: "Q_H_L_1".to_string(), refresh_key: "rk".to_string(), ..Credential::default() };
+        storage.credentials().save(&credential).await.unwrap();
+
+        credential.musickey = "Q_H_L_2".to_string();
+        storage.credentials().save(&credential).await.unwrap();
+        storage.credentials().save(&Credential { musicid: 987654321, ..Credential::default() }).await.unwrap();
+
+        let credentials = storage.credentials().list().await.unwrap();
+        assert_eq!(credentials.iter().map(|credential| credential.musickey.as_str()).collect::<Vec<_>>(), vec!["Q_H_L_2", ""]);
+
+        assert!(storage.credentials().remove(987654321).await.unwrap());
+        assert!(!storage.credentials().remove(987654321).await.unwrap());
+
+        // Saved queues, one per guild
+        let mut queue: SavedQueue = serde_json::from_str(r#"{"guild_id": "1", "channel_id": "2", "songs": [], "position": 42, "loop_mode": "Off", "volume": 1.0}"#).unwrap();
+        storage.saved_queues().save(&queue).await.unwrap();
+        assert_eq!(storage.saved_queues().list().await.unwrap(), vec![queue.clone()]);
+
+        queue.position = 7;
+        storage.saved_queues().save(&queue).await.unwrap();
+        storage.saved_queues().save(&SavedQueue { guild_id: GuildId::new(3), ..queue.clone() }).await.unwrap();
+        assert!(storage.saved_queues().remove(GuildId::new(3)).await.unwrap());
+
+        // The guid is made once and kept
+        let guid = storage.installation().guid().await.unwrap();
+        assert_eq!(guid.len(), 32);
+
+        drop(storage);
+        let storage = Storage::open(path.clone()).unwrap();
+
+        assert_eq!(storage.saved_queues().list().await.unwrap(), vec![queue]);
+        assert_eq!(storage.credentials().list().await.unwrap().len(), 1);
+        assert_eq!(storage.installation().guid().await.unwrap(), guid);
+
+        drop(storage);
+        remove(&path);
+    }
+}
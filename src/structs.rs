@@ -1,13 +1,49 @@
-use serde::Deserialize;
+use crate::filter::Filter;
+use crate::player::LoopMode;
+use crate::settings::SettingChange;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use serenity::model::channel::Message;
+use serenity::model::id::UserId;
 use serenity::all::Context;
+use songbird::input::Input;
+
+use std::fmt;
+use std::time::Duration;
 
-#[derive(Debug,Clone)]
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
 pub struct MusicPlayList {
 
     pub id: String,
     pub name: String,
     pub player: String,
+    pub note: String,
+    // Length in seconds, 0 when QQ Music didn't say
+    #[serde(default)]
+    pub interval: u64,
+}
+
+
+// A song ready for the queue. Knowing its length lets the next one be buffered before it ends
+
+pub struct AudioTrack {
+
+    pub songmid: String,
+    pub source: Input,
+    pub duration: Option<Duration>,
+    // Who asked for it, the author of the command when unset
+    pub requester: Option<UserId>,
+    // Where playback starts, past zero when a saved queue is restored
+    pub start: Duration,
+}
+
+impl AudioTrack {
+
+    pub fn new(songmid: &str, source: Input, duration: Option<Duration>) -> Self {
+
+        AudioTrack { songmid: songmid.to_string(), source, duration, requester: None, start: Duration::ZERO }
+    }
 }
 
 
@@ -22,30 +58,223 @@ pub enum SearchType {
     Mv = 8,
 }
 
-#[derive(Debug, Deserialize)]
-pub struct ApiResponse {
-    pub req_1: Req1,
-}
-
-#[derive(Debug, Deserialize)]
-pub struct Req1 {
-    pub data: Data,
-}
-
-#[derive(Debug, Deserialize)]
-pub struct Data {
+#[derive(Debug, Default, Deserialize)]
+pub struct VkeyData {
+    #[serde(default)]
     pub sip: Vec<String>,
+    #[serde(default)]
     pub midurlinfo: Vec<MidUrlInfo>,
+    // Seconds the vkeys in the purls stay valid
+    #[serde(default)]
+    pub expiration: u64,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct MidUrlInfo {
+    #[serde(default)]
+    pub songmid: String,
     pub purl: String,
+    #[serde(default)]
+    pub result: i64,
+    #[serde(default)]
+    pub errtype: Value,
+    #[serde(default)]
+    pub pneedbuy: i64,
+    #[serde(default)]
+    pub tips: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct SearchData {
+    #[serde(default)]
+    pub body: SearchBody,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct SearchBody {
+    #[serde(default)]
+    pub song: SongList,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct SongList {
+    #[serde(default)]
+    pub list: Vec<Value>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct DissInfoData {
+    #[serde(default)]
+    pub songlist: Vec<Value>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct AlbumSongListData {
+    #[serde(default, rename = "songList")]
+    pub song_list: Vec<Value>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct SongDetailData {
+    #[serde(default)]
+    pub track_info: Value,
+}
+
+#[derive(Debug)]
+pub enum QRLoginStatus {
+    Waiting,
+    Scanned,
+    Expired,
+    Refused,
+    Success(String),
+}
+
+// Language of the replies, from BOT_LANGUAGE (en / zh)
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Language {
+    #[default]
+    English,
+    Chinese,
+}
+
+impl Language {
+
+    pub fn from_env() -> Self {
+
+        Self::parse(&std::env::var("BOT_LANGUAGE").unwrap_or_default()).unwrap_or_default()
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+
+        match value.trim().to_lowercase().as_str() {
+            "zh" | "zh-cn" | "cn" | "chinese" => Some(Language::Chinese),
+            "en" | "english" => Some(Language::English),
+            _ => None,
+        }
+    }
+}
+
+
+// Audio file asked from the vkey server. Standard is what it hands out when not asked
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Quality {
+    // 96 kbps AAC
+    #[default]
+    Standard,
+    // 128 kbps MP3
+    High,
+    // 320 kbps MP3, usually VIP only
+    VeryHigh,
+    // FLAC, usually VIP only
+    Lossless,
+}
+
+impl Quality {
+
+    pub fn parse(value: &str) -> Option<Self> {
+
+        match value.trim().to_lowercase().as_str() {
+            "standard" | "m4a" | "96" => Some(Quality::Standard),
+            "high" | "128" => Some(Quality::High),
+            "veryhigh" | "hq" | "320" => Some(Quality::VeryHigh),
+            "lossless" | "flac" => Some(Quality::Lossless),
+            _ => None,
+        }
+    }
+
+    // File name to ask for, e.g. "M500{mid}{mid}.mp3". Standard asks for nothing in particular
+
+    pub fn file_name(&self, songmid: &str) -> Option<String> {
+
+        let (prefix, extension) = match self {
+            Quality::Standard => return None,
+            Quality::High => ("M500", "mp3"),
+            Quality::VeryHigh => ("M800", "mp3"),
+            Quality::Lossless => ("F000", "flac"),
+        };
+
+        Some(format!("{}{}{}.{}", prefix, songmid, songmid, extension))
+    }
+}
+
+impl fmt::Display for Quality {
+
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+
+        match self {
+            Quality::Standard => write!(f, "standard"),
+            Quality::High => write!(f, "high"),
+            Quality::VeryHigh => write!(f, "veryhigh"),
+            Quality::Lossless => write!(f, "lossless"),
+        }
+    }
 }
 
 #[derive(Debug)]
 pub enum BotCommand {
     Cancel { ctx: Context, msg: Message },
-    Search { ctx: Context, msg: Message, Name: String }, 
-    Play { ctx: Context, msg: Message, ID: String }, 
+    Skip { ctx: Context, msg: Message },
+    VoteSkip { ctx: Context, msg: Message },
+    Crossfade { ctx: Context, msg: Message, seconds: u64 },
+    Filter { ctx: Context, msg: Message, filter: Filter },
+    Loop { ctx: Context, msg: Message, loop_mode: LoopMode },
+    // Percent of the original loudness
+    Volume { ctx: Context, msg: Message, percent: u32 },
+    // Shows the settings when nothing changes
+    Settings { ctx: Context, msg: Message, change: Option<SettingChange> },
+    // /block, /unblock and /blocklist, which shows the blocklists when nothing changes
+    Blocklist { ctx: Context, msg: Message, change: Option<SettingChange> },
+    Search { ctx: Context, msg: Message, name: String },
+    Play { ctx: Context, msg: Message, id: String },
+    Login { ctx: Context, msg: Message },
+    Accounts { ctx: Context, msg: Message },
+    Playlist { ctx: Context, msg: Message, id: String },
+    Album { ctx: Context, msg: Message, id: String },
+}
+
+impl BotCommand {
+
+    pub fn context(&self) -> (&Context, &Message) {
+
+        match self {
+            BotCommand::Cancel { ctx, msg }
+            | BotCommand::Skip { ctx, msg }
+            | BotCommand::VoteSkip { ctx, msg }
+            | BotCommand::Crossfade { ctx, msg, .. }
+            | BotCommand::Filter { ctx, msg, .. }
+            | BotCommand::Loop { ctx, msg, .. }
+            | BotCommand::Volume { ctx, msg, .. }
+            | BotCommand::Settings { ctx, msg, .. }
+            | BotCommand::Blocklist { ctx, msg, .. }
+            | BotCommand::Search { ctx, msg, .. }
+            | BotCommand::Play { ctx, msg, .. }
+            | BotCommand::Login { ctx, msg }
+            | BotCommand::Accounts { ctx, msg }
+            | BotCommand::Playlist { ctx, msg, .. }
+            | BotCommand::Album { ctx, msg, .. } => (ctx, msg),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+
+        match self {
+            BotCommand::Cancel { .. } => "cancel",
+            BotCommand::Skip { .. } => "skip",
+            BotCommand::VoteSkip { .. } => "voteskip",
+            BotCommand::Crossfade { .. } => "crossfade",
+            BotCommand::Filter { .. } => "filter",
+            BotCommand::Loop { .. } => "loop",
+            BotCommand::Volume { .. } => "volume",
+            BotCommand::Settings { .. } => "settings",
+            BotCommand::Blocklist { .. } => "blocklist",
+            BotCommand::Search { .. } => "search",
+            BotCommand::Play { .. } => "play",
+            BotCommand::Login { .. } => "login",
+            BotCommand::Accounts { .. } => "accounts",
+            BotCommand::Playlist { .. } => "playlist",
+            BotCommand::Album { .. } => "album",
+        }
+    }
 }
@@ -0,0 +1,298 @@
+// A local stand-in for u.y.qq.com. Every req_N of a musicu.fcg call is answered with the
+// fixture of its module, unless the test asked for an error instead. It also plays
+// ssl.ptlogin2.qq.com and graph.qq.com for the QR code login
+
+use serde_json::{json, Map, Value};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{Mutex, MutexGuard};
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex as StdMutex};
+
+
+// QQMusic reads its data directory from the environment, so the tests take turns
+static SERIAL: Mutex<()> = Mutex::const_new(());
+
+
+#[derive(Debug, Clone)]
+pub enum MockReply {
+
+    // Answer the whole call with this HTTP status
+    Status(u16),
+
+    // Same, for the next call only
+    StatusOnce(u16),
+
+    // Answer the request with this API code and no data
+    Code(i64),
+
+    // Answer the whole call with this raw body
+    Body(&'static str),
+}
+
+
+#[derive(Debug, Clone)]
+pub struct MockRequest {
+
+    pub path: String,
+    pub raw_body: String,
+    pub body: Value,
+}
+
+impl MockRequest {
+
+    pub fn modules(&self) -> Vec<String> {
+
+        self.body.as_object()
+            .map(|requests| requests.iter()
+                .filter(|(name, _)| name.starts_with("req_"))
+                .filter_map(|(_, request)| request["module"].as_str().map(str::to_string))
+                .collect())
+            .unwrap_or_default()
+    }
+}
+
+
+#[derive(Default)]
+struct MockState {
+
+    url: String,
+    replies: HashMap<String, MockReply>,
+    requests: Vec<MockRequest>,
+
+    // ptuiCB codes answered to the next ptqrlogin polls, success ("0") once they run out
+    login_states: VecDeque<&'static str>,
+}
+
+
+pub struct MockServer {
+
+    pub url: String,
+    state: Arc<StdMutex<MockState>>,
+    _serial: MutexGuard<'static, ()>,
+}
+
+impl MockServer {
+
+    pub async fn start() -> Self {
+
+        let serial = SERIAL.lock().await;
+
+        // A fresh data directory, nothing saved by the previous test
+        let data_dir = std::env::temp_dir().join(format!("qqmusic-mock-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&data_dir);
+
+        std::env::set_var("DATA_DIR", &data_dir);
+        std::env::remove_var("COOKIE");
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+
+        let state = Arc::new(StdMutex::new(MockState { url: url.clone(), ..MockState::default() }));
+        let server_state = Arc::clone(&state);
+
+        tokio::spawn(async move {
+
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(handle(stream, Arc::clone(&server_state)));
+            }
+        });
+
+        MockServer { url, state, _serial: serial }
+    }
+
+
+    pub fn reply(&self, module: &str, reply: MockReply) {
+
+        self.state.lock().unwrap().replies.insert(module.to_string(), reply);
+    }
+
+    pub fn login_states(&self, states: &[&'static str]) {
+
+        self.state.lock().unwrap().login_states = states.iter().copied().collect();
+    }
+
+    pub fn requests(&self) -> Vec<MockRequest> {
+
+        self.state.lock().unwrap().requests.clone()
+    }
+}
+
+
+fn fixture(module: &str) -> Value {
+
+    let fixture = match module {
+        "vkey.GetVkeyServer" => include_str!("../fixtures/vkey.json"),
+        "music.search.SearchCgiService" => include_str!("../fixtures/search.json"),
+        "music.srfDissInfo.aiDissInfo" => include_str!("../fixtures/playlist.json"),
+        "music.musichallAlbum.AlbumSongList" => include_str!("../fixtures/album.json"),
+        "music.pf_song_detail_svr" => include_str!("../fixtures/song.json"),
+        "music.login.LoginServer" | "QQConnectLogin.LoginServer" => include_str!("../fixtures/login.json"),
+        _ => r#"{"code": 404}"#,
+    };
+
+    serde_json::from_str(fixture).unwrap()
+}
+
+
+async fn handle(mut stream: TcpStream, state: Arc<StdMutex<MockState>>) {
+
+    let Some((path, raw_body)) = read_request(&mut stream).await else {
+        return;
+    };
+
+    let body: Value = serde_json::from_str(&raw_body).unwrap_or_default();
+
+    let request = MockRequest { path, raw_body, body };
+
+    if let Some((status, headers, response)) = login_reply(&request, &state) {
+
+        state.lock().unwrap().requests.push(request);
+
+        return respond(&mut stream, status, &headers, &response).await;
+    }
+
+    let modules = request.modules();
+
+    let (status, response) = {
+
+        let mut state = state.lock().unwrap();
+        state.requests.push(request.clone());
+
+        let mut response = Map::new();
+        response.insert("code".to_string(), json!(0));
+
+        let mut whole = None;
+
+        for (name, value) in request.body.as_object().into_iter().flatten().filter(|(name, _)| name.starts_with("req_")) {
+
+            let module = value["module"].as_str().unwrap_or_default();
+
+            match state.replies.get(module).cloned() {
+
+                Some(MockReply::Status(status)) => whole = Some((status, String::new())),
+
+                Some(MockReply::StatusOnce(status)) => {
+
+                    whole = Some((status, String::new()));
+                    state.replies.remove(module);
+                }
+
+                Some(MockReply::Body(body)) => whole = Some((200, body.to_string())),
+
+                Some(MockReply::Code(code)) => {
+                    response.insert(name.clone(), json!({"code": code, "data": {}}));
+                }
+
+                None => {
+                    response.insert(name.clone(), fixture(module));
+                }
+            }
+        }
+
+        if modules.is_empty() {
+            whole = Some((400, String::new()));
+        }
+
+        whole.unwrap_or((200, Value::Object(response).to_string()))
+    };
+
+    respond(&mut stream, status, &["Content-Type: application/json".to_string()], &response).await;
+}
+
+
+// The QR code, its status, check_sig and the QQ Connect authorization, None for musicu.fcg
+
+fn login_reply(request: &MockRequest, state: &StdMutex<MockState>) -> Option<(u16, Vec<String>, String)> {
+
+    let path = request.path.split('?').next().unwrap_or_default();
+
+    let reply = match path {
+
+        "/ptqrshow" => (200, vec!["Set-Cookie: qrsig=QRSIG; Path=/".to_string()], "PNG".to_string()),
+
+        "/ptqrlogin" => {
+
+            let mut state = state.lock().unwrap();
+
+            let body = match state.login_states.pop_front().unwrap_or("0") {
+                "0" => format!("ptuiCB('0','0','{}/check_sig?uin=123456789&ptsigx=abc','0','登录成功！', 'nick')", state.url),
+                code => format!("ptuiCB('{}','0','','0','', '')", code),
+            };
+
+            (200, vec![], body)
+        }
+
+        "/check_sig" => (302, vec!["Set-Cookie: p_skey=PSKEY; Path=/".to_string(), "Set-Cookie: p_uin=o0123456789; Path=/".to_string()], String::new()),
+
+        "/oauth2.0/authorize" => (302, vec!["Location: https://y.qq.com/portal/wx_redirect.html?login_type=1&code=ABCDEF&state=state".to_string()], String::new()),
+
+        _ => return None,
+    };
+
+    Some(reply)
+}
+
+
+async fn respond(stream: &mut TcpStream, status: u16, headers: &[String], response: &str) {
+
+    let mut head = format!("HTTP/1.1 {} Mock\r\n", status);
+
+    for header in headers {
+        head.push_str(&format!("{}\r\n", header));
+    }
+
+    head.push_str(&format!("Content-Length: {}\r\nConnection: close\r\n\r\n", response.len()));
+
+    let _ = stream.write_all(head.as_bytes()).await;
+    let _ = stream.write_all(response.as_bytes()).await;
+    let _ = stream.shutdown().await;
+}
+
+
+// Path with query and body of one HTTP/1.1 request
+
+async fn read_request(stream: &mut TcpStream) -> Option<(String, String)> {
+
+    let mut buffer = vec![];
+    let mut chunk = [0u8; 4096];
+
+    let header_end = loop {
+
+        let read = stream.read(&mut chunk).await.ok()?;
+
+        if read == 0 {
+            return None;
+        }
+
+        buffer.extend_from_slice(&chunk[..read]);
+
+        if let Some(position) = buffer.windows(4).position(|window| window == b"\r\n\r\n") {
+            break position + 4;
+        }
+    };
+
+    let head = String::from_utf8_lossy(&buffer[..header_end]).to_string();
+
+    let path = head.lines().next()?.split_whitespace().nth(1)?.to_string();
+
+    let content_length = head.lines()
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, value)| value.trim().parse::<usize>().ok())
+        .unwrap_or(0);
+
+    while buffer.len() < header_end + content_length {
+
+        let read = stream.read(&mut chunk).await.ok()?;
+
+        if read == 0 {
+            break;
+        }
+
+        buffer.extend_from_slice(&chunk[..read]);
+    }
+
+    Some((path, String::from_utf8_lossy(&buffer[header_end..]).to_string()))
+}
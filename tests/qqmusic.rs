@@ -0,0 +1,505 @@
+mod mock;
+
+use discord_qqmusic_bot::config::{CachePolicy, QQMusicConfig, RetryPolicy};
+use discord_qqmusic_bot::credential::Credential;
+use discord_qqmusic_bot::error::QQMusicError;
+use discord_qqmusic_bot::login::QRLogin;
+use discord_qqmusic_bot::qqmusic::QQMusic;
+use discord_qqmusic_bot::sign::sign;
+use discord_qqmusic_bot::storage::Storage;
+use discord_qqmusic_bot::structs::{QRLoginStatus, Quality};
+
+use mock::{MockReply, MockServer};
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+
+// Every call reaches the mock server, the cache has its own tests
+async fn qqmusic(mock: &MockServer) -> QQMusic {
+
+    qqmusic_with_cache(mock, no_cache()).await
+}
+
+async fn qqmusic_with_cache(mock: &MockServer, cache: CachePolicy) -> QQMusic {
+
+    let config = QQMusicConfig::builder()
+        .api_url(&mock.url)
+        .retry(RetryPolicy { max_retries: 2, base_delay: Duration::from_millis(10), max_delay: Duration::from_millis(50) })
+        .cache(cache)
+        .build();
+
+    QQMusic::new(config, storage()).await.unwrap()
+}
+
+// In the data directory the mock server just emptied
+fn storage() -> Arc<Storage> {
+
+    Arc::new(Storage::open_default().unwrap())
+}
+
+fn login_config(mock: &MockServer) -> QQMusicConfig {
+
+    QQMusicConfig::builder().api_url(&mock.url).login_urls(&mock.url, &mock.url).build()
+}
+
+fn no_cache() -> CachePolicy {
+
+    CachePolicy { max_entries: 0, ..CachePolicy::default() }
+}
+
+
+#[tokio::test]
+async fn test_play_url() {
+
+    let mock = MockServer::start().await;
+    let qqmusic = qqmusic(&mock).await;
+
+    let url = qqmusic.get_qqmusic_play_url("002GwAma2DGN2x").await.unwrap();
+
+    assert_eq!(url, "http://ws.stream.qqmusic.qq.com/C400002GwAma2DGN2x.m4a?guid=1&vkey=ABCDEF&uin=0&fromtag=120032");
+
+    let requests = mock.requests();
+
+    assert_eq!(requests.len(), 1);
+    assert_eq!(requests[0].path, "/cgi-bin/musicu.fcg");
+    assert_eq!(requests[0].body["req_1"]["method"], "CgiGetVkey");
+    assert_eq!(requests[0].body["req_1"]["param"]["songmid"][0], "002GwAma2DGN2x");
+}
+
+
+#[tokio::test]
+async fn test_play_url_quality() {
+
+    let mock = MockServer::start().await;
+    let qqmusic = qqmusic_with_cache(&mock, CachePolicy::default()).await;
+
+    let songmids = vec!["002GwAma2DGN2x".to_string()];
+
+    qqmusic.get_qqmusic_play_urls(&songmids).await;
+    qqmusic.get_qqmusic_play_urls_in(&songmids, Quality::High).await;
+
+    let requests = mock.requests();
+
+    // The default quality asks for no file in particular, and is cached apart from the others
+    assert_eq!(requests.len(), 2);
+    assert!(requests[0].body["req_1"]["param"].get("filename").is_none());
+    assert_eq!(requests[1].body["req_1"]["param"]["filename"], serde_json::json!(["M500002GwAma2DGN2x002GwAma2DGN2x.mp3"]));
+}
+
+
+#[tokio::test]
+async fn test_unplayable_songs() {
+
+    let mock = MockServer::start().await;
+    let qqmusic = qqmusic(&mock).await;
+
+    let songmids: Vec<String> = ["0039MnYb0qxYhV", "001PaidAlbum00", "004RemovedSong", "000000000000000"]
+        .iter().map(|songmid| songmid.to_string()).collect();
+
+    let play_urls = qqmusic.get_qqmusic_play_urls(&songmids).await;
+
+    assert!(matches!(play_urls["0039MnYb0qxYhV"], Err(QQMusicError::QQMusicVipRequiredError)));
+    assert!(matches!(play_urls["001PaidAlbum00"], Err(QQMusicError::QQMusicPaidAlbumError)));
+    assert!(matches!(play_urls["004RemovedSong"], Err(QQMusicError::QQMusicSongRemovedError)));
+    assert!(matches!(play_urls["000000000000000"], Err(QQMusicError::QQMusicPlayError)));
+}
+
+
+#[tokio::test]
+async fn test_play_urls_batched() {
+
+    let mock = MockServer::start().await;
+    let qqmusic = qqmusic(&mock).await;
+
+    let mut songmids: Vec<String> = (0..120).map(|i| format!("{:014}", i)).collect();
+    songmids[0] = "002GwAma2DGN2x".to_string();
+
+    let play_urls = qqmusic.get_qqmusic_play_urls(&songmids).await;
+
+    assert_eq!(play_urls.len(), 120);
+    assert!(play_urls["002GwAma2DGN2x"].is_ok());
+
+    // 120 songs are three CgiGetVkey requests, all in one call
+    let requests = mock.requests();
+
+    assert_eq!(requests.len(), 1);
+    assert_eq!(requests[0].modules().len(), 3);
+}
+
+
+#[tokio::test]
+async fn test_play_url_rate_limited() {
+
+    let mock = MockServer::start().await;
+    let qqmusic = qqmusic(&mock).await;
+
+    mock.reply("vkey.GetVkeyServer", MockReply::Status(429));
+
+    let result = qqmusic.get_qqmusic_play_url("002GwAma2DGN2x").await;
+
+    assert!(matches!(result, Err(QQMusicError::QQMusicRateLimitedError)));
+}
+
+
+#[tokio::test]
+async fn test_play_url_login_expired() {
+
+    let mock = MockServer::start().await;
+    let qqmusic = qqmusic(&mock).await;
+
+    // Anonymous, so there is nothing to refresh
+    mock.reply("vkey.GetVkeyServer", MockReply::Code(1000));
+
+    let result = qqmusic.get_qqmusic_play_url("002GwAma2DGN2x").await;
+
+    assert!(matches!(result, Err(QQMusicError::QQMusicPlayError)));
+    assert!(qqmusic.get_account_list().contains("anonymous"));
+}
+
+
+#[tokio::test]
+async fn test_refresh_expired_credential() {
+
+    let mock = MockServer::start().await;
+    let qqmusic = qqmusic(&mock).await;
+
+    let credential = Credential {
+        musicid: 123456789,
+        musickey: "Q_H_L_old".to_string(),
+        refresh_key: "rk".to_string(),
+        refresh_token: "rt".to_string(),
+        key_expires_in: 259200,
+        musickey_create_time: 1,
+        ..Default::default()
+    };
+
+    qqmusic.login(credential).await.unwrap();
+
+    qqmusic.get_qqmusic_play_url("002GwAma2DGN2x").await.unwrap();
+
+    let requests = mock.requests();
+
+    assert_eq!(requests.len(), 2);
+    assert_eq!(requests[0].modules(), vec!["music.login.LoginServer"]);
+    assert_eq!(requests[0].body["req_1"]["param"]["refresh_key"], "rk");
+    assert_eq!(requests[0].body["comm"]["qq"], "123456789");
+    assert_eq!(requests[0].body["comm"]["ct"], requests[1].body["comm"]["ct"]);
+    assert_eq!(requests[0].body["comm"]["format"], "json");
+    assert_eq!(requests[1].modules(), vec!["vkey.GetVkeyServer"]);
+    assert_eq!(requests[1].body["comm"]["authst"], "Q_H_L_refreshed");
+
+    // The refreshed credential is kept for the next start
+    let saved = storage().credentials().list().await.unwrap();
+
+    assert_eq!(saved.len(), 1);
+    assert_eq!(saved[0].musickey, "Q_H_L_refreshed");
+}
+
+
+#[tokio::test]
+async fn test_login_without_refresh_key() {
+
+    let mock = MockServer::start().await;
+    let qqmusic = qqmusic(&mock).await;
+
+    // Some QR logins come back without a refresh key, the musickey still has to outlive a restart
+    let credential = Credential { musicid: 123456789, musickey: "Q_H_L_1".to_string(), ..Default::default() };
+
+    qqmusic.login(credential).await.unwrap();
+
+    let saved = storage().credentials().list().await.unwrap();
+
+    assert_eq!(saved.len(), 1);
+    assert_eq!(saved[0].musickey, "Q_H_L_1");
+}
+
+
+#[tokio::test]
+async fn test_search() {
+
+    let mock = MockServer::start().await;
+    let qqmusic = qqmusic(&mock).await;
+
+    let table = qqmusic.get_search_list("晴天").await.unwrap();
+
+    assert!(table.contains("002GwAma2DGN2x"));
+    assert!(table.contains("以父之名"));
+    assert!(table.contains("N/A"));
+
+    // Search goes to the signed endpoint, signed over the exact body
+    let requests = mock.requests();
+    let (path, query) = requests[0].path.split_once('?').unwrap();
+
+    assert_eq!(path, "/cgi-bin/musics.fcg");
+    assert!(query.contains(&format!("sign={}", sign(&requests[0].raw_body))));
+    assert_eq!(requests[0].body["req_1"]["param"]["query"], "晴天");
+}
+
+
+#[tokio::test]
+async fn test_search_server_error() {
+
+    let mock = MockServer::start().await;
+    let qqmusic = qqmusic(&mock).await;
+
+    mock.reply("music.search.SearchCgiService", MockReply::Status(500));
+
+    let result = qqmusic.get_search_list("晴天").await;
+
+    assert!(matches!(result, Err(QQMusicError::QQMusicHttpStatusError { status }) if status.as_u16() == 500));
+
+    // Tried once and retried twice
+    assert_eq!(mock.requests().len(), 3);
+}
+
+
+#[tokio::test]
+async fn test_retry_transient_error() {
+
+    let mock = MockServer::start().await;
+    let qqmusic = qqmusic(&mock).await;
+
+    mock.reply("music.srfDissInfo.aiDissInfo", MockReply::StatusOnce(503));
+
+    assert_eq!(qqmusic.get_playlist_songs("7256912512").await.unwrap().len(), 2);
+    assert_eq!(mock.requests().len(), 2);
+
+    // An API code is an answer, not a transient failure
+    mock.reply("music.srfDissInfo.aiDissInfo", MockReply::Code(4000));
+
+    assert!(qqmusic.get_playlist_songs("7256912512").await.is_err());
+    assert_eq!(mock.requests().len(), 3);
+}
+
+
+#[tokio::test]
+async fn test_song() {
+
+    let mock = MockServer::start().await;
+    let qqmusic = qqmusic(&mock).await;
+
+    let song = qqmusic.get_song("002GwAma2DGN2x").await.unwrap();
+
+    assert_eq!(song.name, "晴天");
+    assert_eq!(song.player, "周杰伦");
+    assert_eq!(song.interval, 269);
+    assert_eq!(mock.requests()[0].body["req_1"]["param"]["song_mid"], "002GwAma2DGN2x");
+
+    // QQ Music answers an unknown songmid with an empty track
+    mock.reply("music.pf_song_detail_svr", MockReply::Body(r#"{"code": 0, "req_1": {"code": 0, "data": {"track_info": {}}}}"#));
+
+    assert!(matches!(qqmusic.get_song("000000000000000").await, Err(QQMusicError::QQMusicSongError)));
+}
+
+
+#[tokio::test]
+async fn test_rate_limit() {
+
+    let mock = MockServer::start().await;
+
+    let config = QQMusicConfig::builder().api_url(&mock.url).rate_limit(20.0, 2).cache(no_cache()).build();
+    let qqmusic = QQMusic::new(config, storage()).await.unwrap();
+
+    let start = Instant::now();
+
+    for _ in 0..4 {
+        qqmusic.get_album_songs("000MkMni19ClKG").await.unwrap();
+    }
+
+    // Two calls from the burst, the other two wait 50ms each for a token
+    assert!(start.elapsed() >= Duration::from_millis(90));
+}
+
+
+#[tokio::test]
+async fn test_playlist() {
+
+    let mock = MockServer::start().await;
+    let qqmusic = qqmusic(&mock).await;
+
+    let songs = qqmusic.get_playlist_songs("7256912512").await.unwrap();
+
+    assert_eq!(songs.len(), 2);
+    assert_eq!(songs[0].name, "晴天");
+    assert_eq!(songs[0].interval, 269);
+    assert_eq!(songs[1].note, "VIP");
+    assert_eq!(mock.requests()[0].body["req_1"]["param"]["disstid"], 7256912512u64);
+
+    assert!(matches!(qqmusic.get_playlist_songs("not-a-number").await, Err(QQMusicError::QQMusicPlaylistError)));
+
+    mock.reply("music.srfDissInfo.aiDissInfo", MockReply::Code(4000));
+
+    assert!(matches!(qqmusic.get_playlist_songs("1").await, Err(QQMusicError::QQMusicApiError { code: 4000 })));
+}
+
+
+#[tokio::test]
+async fn test_album() {
+
+    let mock = MockServer::start().await;
+    let qqmusic = qqmusic(&mock).await;
+
+    let songs = qqmusic.get_album_songs("000MkMni19ClKG").await.unwrap();
+
+    assert_eq!(songs.len(), 2);
+    assert_eq!(songs[1].id, "001PaidAlbum00");
+    assert_eq!(songs[1].note, "Paid");
+    assert_eq!(mock.requests()[0].body["req_1"]["param"]["albumMid"], "000MkMni19ClKG");
+
+    mock.reply("music.musichallAlbum.AlbumSongList", MockReply::Body("<html>not json</html>"));
+
+    assert!(matches!(qqmusic.get_album_songs("000MkMni19ClKG").await, Err(QQMusicError::QQMusicRequestError(_))));
+}
+
+
+#[tokio::test]
+async fn test_account_list() {
+
+    let mock = MockServer::start().await;
+    let qqmusic = qqmusic(&mock).await;
+
+    qqmusic.login(Credential::from_cookie("uin=o0123456789; qqmusic_key=Q_H_L_5abc").unwrap()).await.unwrap();
+
+    let table = qqmusic.get_account_list();
+
+    assert!(table.contains("123456789"));
+    assert!(!table.contains("anonymous"));
+}
+
+
+#[tokio::test]
+async fn test_search_cached() {
+
+    let mock = MockServer::start().await;
+    let qqmusic = qqmusic_with_cache(&mock, CachePolicy::default()).await;
+
+    let first = qqmusic.get_search_list("晴天").await.unwrap();
+
+    // The second answer comes from the cache even though the server now fails
+    mock.reply("music.search.SearchCgiService", MockReply::Status(500));
+
+    assert_eq!(qqmusic.get_search_list("晴天").await.unwrap(), first);
+    assert_eq!(mock.requests().len(), 1);
+
+    // Another keyword is another entry
+    assert!(qqmusic.get_search_list("七里香").await.is_err());
+
+    let stats = qqmusic.cache_stats();
+
+    assert_eq!((stats.entries, stats.hits), (1, 1));
+    assert!(qqmusic.get_account_list().contains("Cache: 1 entries, 1 hits"));
+}
+
+
+#[tokio::test]
+async fn test_song_lists_cached() {
+
+    let mock = MockServer::start().await;
+    let qqmusic = qqmusic_with_cache(&mock, CachePolicy::default()).await;
+
+    for _ in 0..2 {
+        assert_eq!(qqmusic.get_playlist_songs("7256912512").await.unwrap().len(), 2);
+        assert_eq!(qqmusic.get_album_songs("000MkMni19ClKG").await.unwrap().len(), 2);
+    }
+
+    assert_eq!(mock.requests().len(), 2);
+}
+
+
+#[tokio::test]
+async fn test_play_url_cached() {
+
+    let mock = MockServer::start().await;
+    let qqmusic = qqmusic_with_cache(&mock, CachePolicy::default()).await;
+
+    let songmids = vec!["002GwAma2DGN2x".to_string(), "0039MnYb0qxYhV".to_string()];
+
+    qqmusic.get_qqmusic_play_urls(&songmids).await;
+    let play_urls = qqmusic.get_qqmusic_play_urls(&songmids).await;
+
+    assert!(play_urls["002GwAma2DGN2x"].is_ok());
+    assert!(play_urls["0039MnYb0qxYhV"].is_err());
+
+    // Only the song without a play url is asked for again
+    let requests = mock.requests();
+
+    assert_eq!(requests.len(), 2);
+    assert_eq!(requests[1].body["req_1"]["param"]["songmid"], serde_json::json!(["0039MnYb0qxYhV"]));
+}
+
+
+#[tokio::test]
+async fn test_qr_login_show() {
+
+    let mock = MockServer::start().await;
+
+    let login = QRLogin::new(&login_config(&mock)).await.unwrap();
+
+    assert_eq!(login.image, b"PNG");
+
+    let requests = mock.requests();
+
+    assert_eq!(requests.len(), 1);
+    assert!(requests[0].path.starts_with("/ptqrshow?appid=716027609"));
+}
+
+
+#[tokio::test]
+async fn test_qr_login_poll_states() {
+
+    let mock = MockServer::start().await;
+    let login = QRLogin::new(&login_config(&mock)).await.unwrap();
+
+    mock.login_states(&["66", "67", "68", "65", "99"]);
+
+    assert!(matches!(login.poll().await.unwrap(), QRLoginStatus::Waiting));
+    assert!(matches!(login.poll().await.unwrap(), QRLoginStatus::Scanned));
+    assert!(matches!(login.poll().await.unwrap(), QRLoginStatus::Refused));
+    assert!(matches!(login.poll().await.unwrap(), QRLoginStatus::Expired));
+    assert!(matches!(login.poll().await, Err(QQMusicError::QQMusicLoginError)));
+
+    match login.poll().await.unwrap() {
+        QRLoginStatus::Success(url) => assert_eq!(url, format!("{}/check_sig?uin=123456789&ptsigx=abc", mock.url)),
+        other => panic!("unexpected status {:?}", other),
+    }
+
+    // The qrsig cookie turned into ptqrtoken, and the jump back to graph.qq.com on the mock
+    let poll = &mock.requests()[1];
+
+    assert!(poll.path.starts_with("/ptqrlogin?u1="));
+    assert!(poll.path.contains("ptqrtoken="));
+
+    // A code that expired or was refused ends the wait at once
+    mock.login_states(&["65"]);
+    assert!(matches!(login.wait(Duration::from_secs(10)).await, Err(QQMusicError::QQMusicQRCodeExpiredError)));
+
+    mock.login_states(&["68"]);
+    assert!(matches!(login.wait(Duration::from_secs(10)).await, Err(QQMusicError::QQMusicLoginRefusedError)));
+}
+
+
+#[tokio::test]
+async fn test_qr_login_authorize() {
+
+    let mock = MockServer::start().await;
+    let login = QRLogin::new(&login_config(&mock)).await.unwrap();
+
+    mock.login_states(&["67"]);
+
+    let credential = login.wait(Duration::from_secs(10)).await.unwrap();
+
+    assert_eq!(credential.musicid, 123456789);
+    assert_eq!(credential.musickey, "Q_H_L_refreshed");
+
+    let requests = mock.requests();
+    let paths: Vec<&str> = requests.iter().map(|request| request.path.split('?').next().unwrap()).collect();
+
+    assert_eq!(paths, vec!["/ptqrshow", "/ptqrlogin", "/ptqrlogin", "/check_sig", "/oauth2.0/authorize", "/cgi-bin/musicu.fcg"]);
+
+    // check_sig's cookies, signed with g_tk, are exchanged for the code QQ Music logs in with
+    assert!(requests[4].raw_body.contains("client_id=100497308"));
+    assert!(requests[4].raw_body.contains("g_tk="));
+    assert_eq!(requests[5].modules(), vec!["QQConnectLogin.LoginServer"]);
+    assert_eq!(requests[5].body["req_1"]["param"]["code"], "ABCDEF");
+}